@@ -0,0 +1,439 @@
+//! Client address allow and deny lists of TCP listeners, and the relays trusted to tell the
+//! address of the clients behind them.
+
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use std::{fmt, io};
+
+use log::{info, warn};
+use serde::Deserialize;
+use tokio::net::TcpStream;
+use tokio::process::Command;
+use tokio::sync::{watch, Notify};
+
+use crate::config::BridgeConfig;
+use crate::listener::PeerInfo;
+use crate::proxy;
+use crate::util::other_error;
+
+/// Trusted relays that don't send a PROXY header in time are dropped.
+const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+/// Minimum interval between two lookups of the WSL subnet.
+const WSL_REFRESH_INTERVAL: Duration = Duration::from_secs(10);
+/// Interval of looking up the WSL subnet when nobody asks for it.
+const WSL_TTL: Duration = Duration::from_secs(60);
+/// Longest time a client waits for the first lookup of the WSL subnet.
+const WSL_FIRST_LOOKUP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Lists the IPv4 networks of the virtual switch used by WSL2, one `address/prefix` per line.
+const WSL_SUBNET_SCRIPT: &str = "Get-NetIPAddress -AddressFamily IPv4 \
+    | Where-Object InterfaceAlias -like 'vEthernet (WSL*' \
+    | ForEach-Object { '{0}/{1}' -f $_.IPAddress, $_.PrefixLength }";
+
+/// An IP network like `10.0.0.0/8`, a single address when the prefix is omitted.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let (net, ip, bits) = match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => (u32::from(net).into(), u32::from(ip).into(), 32),
+            (IpAddr::V6(net), IpAddr::V6(ip)) => (u128::from(net), u128::from(ip), 128),
+            _ => return false,
+        };
+        let mask = match self.prefix {
+            0 => 0,
+            prefix => !0u128 << (bits - prefix as u32),
+        };
+        net & mask == ip & mask
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| format!("invalid network {}", s))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse()
+                .ok()
+                .filter(|p| *p <= max)
+                .ok_or_else(|| format!("invalid prefix length in {}", s))?,
+            None => max,
+        };
+        Ok(Cidr { addr, prefix })
+    }
+}
+
+impl TryFrom<String> for Cidr {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+/// An entry of `allow` or `deny`.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub enum AddrRule {
+    Net(Cidr),
+    /// The subnet of the WSL2 virtual switch, which changes whenever WSL restarts.
+    Wsl,
+}
+
+impl TryFrom<String> for AddrRule {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        match s.as_str() {
+            "wsl" => Ok(AddrRule::Wsl),
+            s => s.parse().map(AddrRule::Net),
+        }
+    }
+}
+
+async fn wsl_subnets() -> io::Result<Vec<Cidr>> {
+    let output = Command::new("powershell")
+        .args([
+            "-NoProfile",
+            "-NonInteractive",
+            "-Command",
+            WSL_SUBNET_SCRIPT,
+        ])
+        .output()
+        .await?;
+    if !output.status.success() {
+        return Err(other_error(format!(
+            "failed to query network interfaces: {:?}",
+            String::from_utf8_lossy(&output.stderr)
+        )));
+    }
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter(|l| !l.trim().is_empty())
+        .map(|l| l.trim().parse().map_err(other_error))
+        .collect()
+}
+
+/// Outcome of the last lookup of the WSL subnets.
+enum WslLookup {
+    Pending,
+    Found(Vec<Cidr>),
+    Failed,
+}
+
+/// The WSL subnets, looked up by a background task so clients never wait for PowerShell.
+struct WslSubnets {
+    nets: watch::Receiver<WslLookup>,
+    /// Asks for another lookup, as the subnet changes whenever WSL restarts.
+    refresh: Arc<Notify>,
+}
+
+impl WslSubnets {
+    /// Starts looking up the subnets until the returned value is dropped.
+    fn spawn() -> WslSubnets {
+        let (tx, nets) = watch::channel(WslLookup::Pending);
+        let refresh = Arc::new(Notify::new());
+        tokio::spawn(refresh_wsl_subnets(tx, refresh.clone()));
+        WslSubnets { nets, refresh }
+    }
+}
+
+async fn refresh_wsl_subnets(tx: watch::Sender<WslLookup>, refresh: Arc<Notify>) {
+    loop {
+        let lookup = match wsl_subnets().await {
+            Ok(nets) if nets.is_empty() => {
+                warn!("no WSL virtual switch is found, is WSL running?");
+                WslLookup::Found(nets)
+            }
+            Ok(nets) => {
+                if !matches!(&*tx.borrow(), WslLookup::Found(old) if *old == nets) {
+                    let list: Vec<_> = nets.iter().map(|n| n.to_string()).collect();
+                    info!("WSL subnet is {}", list.join(", "));
+                }
+                WslLookup::Found(nets)
+            }
+            Err(e) => {
+                warn!("failed to find the WSL subnet: {}", e);
+                WslLookup::Failed
+            }
+        };
+        tx.send_replace(lookup);
+        let requested = async {
+            tokio::time::sleep(WSL_REFRESH_INTERVAL).await;
+            refresh.notified().await
+        };
+        tokio::select! {
+            _ = requested => {}
+            _ = tokio::time::sleep(WSL_TTL) => {}
+            _ = tx.closed() => return,
+        }
+    }
+}
+
+/// Checks peers of a listener against the `allow` and `deny` lists of its configuration.
+pub struct Acl {
+    config: watch::Receiver<Arc<BridgeConfig>>,
+    /// Started by the first peer checked against `wsl`.
+    wsl: OnceLock<WslSubnets>,
+}
+
+impl Acl {
+    pub fn new(config: watch::Receiver<Arc<BridgeConfig>>) -> Acl {
+        Acl {
+            config,
+            wsl: OnceLock::new(),
+        }
+    }
+
+    /// Returns who is behind a connection accepted from `addr`, or `None` if it has to be
+    /// dropped.
+    ///
+    /// A trusted relay must send a PROXY header first, then the client address it carries is
+    /// checked instead of the relay's.
+    pub async fn admit(&self, conn: &mut TcpStream, addr: SocketAddr) -> Option<PeerInfo> {
+        let (name, trusted) = {
+            let config = self.config.borrow();
+            let trusted = config.trusted_proxies.iter().any(|n| n.contains(addr.ip()));
+            (config.name().to_owned(), trusted)
+        };
+        let peer = if trusted {
+            match tokio::time::timeout(PROXY_HEADER_TIMEOUT, proxy::read_header(conn)).await {
+                Ok(Ok(Some(client))) => PeerInfo {
+                    addr: Some(client),
+                    proxy: Some(addr),
+                    ..Default::default()
+                },
+                Ok(Ok(None)) => PeerInfo::from(addr),
+                Ok(Err(e)) => {
+                    warn!("{} dropped connection from relay {}: {}", name, addr, e);
+                    return None;
+                }
+                Err(_) => {
+                    warn!("{} dropped relay {}, it sent no PROXY header", name, addr);
+                    return None;
+                }
+            }
+        } else {
+            PeerInfo::from(addr)
+        };
+        let client = peer.addr.map_or(addr.ip(), |a| a.ip());
+        self.permits(client).await.then_some(peer)
+    }
+
+    /// Returns whether `peer` may connect, rejected peers are logged.
+    async fn permits(&self, peer: IpAddr) -> bool {
+        let config = self.config.borrow().clone();
+        if config.allow.is_empty() && config.deny.is_empty() {
+            return true;
+        }
+        let peer = peer.to_canonical();
+        // A deny rule that can't be checked may be the one matching the peer.
+        match self.matches(&config.deny, peer).await {
+            Some(false) => {}
+            Some(true) => {
+                warn!("{} rejected {}, which is denied", config.name(), peer);
+                return false;
+            }
+            None => {
+                warn!(
+                    "{} rejected {}, the WSL subnet to deny is unknown",
+                    config.name(),
+                    peer
+                );
+                return false;
+            }
+        }
+        if !config.allow.is_empty() && self.matches(&config.allow, peer).await != Some(true) {
+            warn!("{} rejected {}, which is not allowed", config.name(), peer);
+            return false;
+        }
+        true
+    }
+
+    /// Returns whether `peer` matches any of `rules`, or `None` if it matches none of those
+    /// that can be checked and some can't.
+    async fn matches(&self, rules: &[AddrRule], peer: IpAddr) -> Option<bool> {
+        let mut known = true;
+        for rule in rules {
+            let matched = match rule {
+                AddrRule::Net(net) => Some(net.contains(peer)),
+                AddrRule::Wsl => self.in_wsl(peer).await,
+            };
+            match matched {
+                Some(true) => return Some(true),
+                Some(false) => {}
+                None => known = false,
+            }
+        }
+        known.then_some(false)
+    }
+
+    /// Checks `peer` against the WSL subnets found last, only the first lookup is waited for.
+    /// Returns `None` if the subnets are unknown, as looking them up failed or takes too long.
+    ///
+    /// A peer outside of them asks for another lookup, as the subnet may have changed.
+    async fn in_wsl(&self, peer: IpAddr) -> Option<bool> {
+        let wsl = self.wsl.get_or_init(WslSubnets::spawn);
+        let mut nets = wsl.nets.clone();
+        let first_lookup = nets.wait_for(|lookup| !matches!(lookup, WslLookup::Pending));
+        let matched = match tokio::time::timeout(WSL_FIRST_LOOKUP_TIMEOUT, first_lookup).await {
+            Ok(Ok(lookup)) => match &*lookup {
+                WslLookup::Found(nets) => Some(nets.iter().any(|n| n.contains(peer))),
+                _ => None,
+            },
+            _ => None,
+        };
+        if matched != Some(true) {
+            wsl.refresh.notify_one();
+        }
+        matched
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::SocketType;
+
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_parse_cidr() {
+        let cases = [
+            ("10.0.0.0/8", Ok("10.0.0.0/8")),
+            ("10.1.2.3", Ok("10.1.2.3/32")),
+            ("0.0.0.0/0", Ok("0.0.0.0/0")),
+            ("192.168.1.1/32", Ok("192.168.1.1/32")),
+            ("fd00::/8", Ok("fd00::/8")),
+            ("::1", Ok("::1/128")),
+            ("::/0", Ok("::/0")),
+            ("::ffff:10.0.0.0/104", Ok("::ffff:10.0.0.0/104")),
+            ("10.0.0.0/33", Err("invalid prefix length in 10.0.0.0/33")),
+            ("fd00::/129", Err("invalid prefix length in fd00::/129")),
+            ("10.0.0.0/-1", Err("invalid prefix length in 10.0.0.0/-1")),
+            ("10.0.0.0/", Err("invalid prefix length in 10.0.0.0/")),
+            ("10.0.0.0/8/8", Err("invalid prefix length in 10.0.0.0/8/8")),
+            ("10.0.0/8", Err("invalid network 10.0.0/8")),
+            ("localhost", Err("invalid network localhost")),
+            ("", Err("invalid network ")),
+        ];
+        for (s, expected) in cases {
+            let res = s.parse::<Cidr>().map(|c| c.to_string());
+            assert_eq!(
+                res,
+                expected.map(str::to_owned).map_err(str::to_owned),
+                "{}",
+                s
+            );
+        }
+    }
+
+    #[test]
+    fn test_contains() {
+        let cases = [
+            ("10.0.0.0/8", "10.255.1.2", true),
+            ("10.0.0.0/8", "11.0.0.1", false),
+            ("10.0.0.0/8", "::ffff:10.1.2.3", true),
+            ("10.0.0.0/8", "fd00::1", false),
+            ("172.16.0.0/12", "172.31.255.255", true),
+            ("172.16.0.0/12", "172.32.0.0", false),
+            ("0.0.0.0/0", "203.0.113.9", true),
+            ("0.0.0.0/0", "::1", false),
+            ("192.168.1.1/32", "192.168.1.1", true),
+            ("192.168.1.1/32", "192.168.1.2", false),
+            ("fd00::/8", "fd12::1", true),
+            ("fd00::/8", "fe80::1", false),
+            ("::/0", "2001:db8::1", true),
+            ("::/0", "10.0.0.1", false),
+            ("::1/128", "::1", true),
+            ("::1/128", "::2", false),
+            // Mapped addresses only match IPv4 networks.
+            ("::ffff:0.0.0.0/96", "::ffff:10.0.0.1", false),
+        ];
+        for (net, addr, expected) in cases {
+            let net: Cidr = net.parse().unwrap();
+            assert_eq!(net.contains(ip(addr)), expected, "{} {}", net, addr);
+        }
+    }
+
+    /// An ACL of space separated rules that sees `lookup` as the WSL subnets.
+    fn acl(allow: &str, deny: &str, lookup: WslLookup) -> (Acl, watch::Sender<WslLookup>) {
+        let rules = |rules: &str| {
+            rules
+                .split_whitespace()
+                .map(|r| AddrRule::try_from(r.to_owned()).unwrap())
+                .collect()
+        };
+        let mut config = BridgeConfig::new(SocketType::Extra, "127.0.0.1:0".to_owned());
+        config.allow = rules(allow);
+        config.deny = rules(deny);
+        let (_, config) = watch::channel(Arc::new(config));
+        let acl = Acl::new(config);
+        let (tx, nets) = watch::channel(lookup);
+        let wsl = WslSubnets {
+            nets,
+            refresh: Arc::new(Notify::new()),
+        };
+        assert!(acl.wsl.set(wsl).is_ok());
+        (acl, tx)
+    }
+
+    #[tokio::test]
+    async fn test_permits() {
+        // The WSL subnet is 172.20.0.0/16 unless looking it up failed.
+        let cases: &[(&str, &str, bool, &str, bool)] = &[
+            ("", "", false, "10.0.0.1", true),
+            ("10.0.0.0/8", "", true, "10.0.0.1", true),
+            ("10.0.0.0/8", "", true, "::ffff:10.0.0.1", true),
+            ("10.0.0.0/8", "", true, "192.168.0.1", false),
+            ("10.0.0.0/8", "10.0.0.1", true, "10.0.0.1", false),
+            ("wsl", "", true, "172.20.1.1", true),
+            ("wsl", "", true, "172.21.1.1", false),
+            ("", "wsl", true, "172.20.1.1", false),
+            ("", "wsl", true, "172.21.1.1", true),
+            // An unknown subnet neither allows nor lets through a peer it may deny.
+            ("wsl", "", false, "172.20.1.1", false),
+            ("wsl 10.0.0.0/8", "", false, "10.0.0.1", true),
+            ("", "wsl", false, "172.21.1.1", false),
+            ("", "10.0.0.0/8 wsl", false, "172.21.1.1", false),
+        ];
+        for (allow, deny, found, peer, expected) in cases {
+            let lookup = match found {
+                true => WslLookup::Found(vec!["172.20.0.0/16".parse().unwrap()]),
+                false => WslLookup::Failed,
+            };
+            let (acl, _tx) = acl(allow, deny, lookup);
+            assert_eq!(
+                acl.permits(ip(peer)).await,
+                *expected,
+                "{:?} {:?} {}",
+                allow,
+                deny,
+                peer
+            );
+        }
+    }
+}
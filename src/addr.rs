@@ -0,0 +1,418 @@
+//! Addresses bridges take connections from and forward them to, written as URIs like
+//! `tcp://127.0.0.1:4321`.
+//!
+//! Listening addresses without a scheme are still accepted: named pipes start with `\\.\pipe\`,
+//! Unix sockets with `/`, and anything else is a TCP address.
+
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use crate::SocketType;
+
+/// Prefix of named pipe paths.
+const PIPE_PREFIX: &str = r"\\.\pipe\";
+
+/// Where a bridge takes connections from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ListenAddr {
+    /// `tcp://HOST:PORT`, which uses TLS anyway if the bridge has a `tls` table.
+    Tcp(String),
+    /// `tls://HOST:PORT`, which requires the `tls` table.
+    Tls(String),
+    /// `unix:///PATH?mode=0660&owner=NAME`, the socket file is given `mode` and `owner` if set.
+    Unix {
+        path: PathBuf,
+        mode: Option<u32>,
+        owner: Option<u32>,
+    },
+    /// `pipe://NAME` for the named pipe `\\.\pipe\NAME`.
+    Pipe(String),
+    /// `stdio:`, a single connection over stdin and stdout.
+    Stdio,
+    /// `fd://NAME`, a socket passed by systemd under `NAME`.
+    Fd(String),
+    /// `dial://HOST:PORT`, connections dialed out to a rendezvous.
+    Dial(String),
+}
+
+impl ListenAddr {
+    /// Whether clients connect over TCP, with or without TLS.
+    pub fn is_tcp(&self) -> bool {
+        matches!(self, ListenAddr::Tcp(_) | ListenAddr::Tls(_))
+    }
+}
+
+fn check_host_port(s: &str, addr: &str) -> Result<String, String> {
+    match s.rsplit_once(':') {
+        Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => Ok(s.to_owned()),
+        _ => Err(format!("{} is not a HOST:PORT address", addr)),
+    }
+}
+
+#[cfg(unix)]
+fn lookup_user(name: &str) -> Option<u32> {
+    let name = std::ffi::CString::new(name).ok()?;
+    let mut pwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut buf = vec![0; 4096];
+    let mut result = std::ptr::null_mut();
+    let res = unsafe {
+        libc::getpwnam_r(
+            name.as_ptr(),
+            &mut pwd,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut result,
+        )
+    };
+    (res == 0 && !result.is_null()).then_some(pwd.pw_uid)
+}
+
+#[cfg(not(unix))]
+fn lookup_user(_name: &str) -> Option<u32> {
+    None
+}
+
+fn parse_unix(rest: &str, addr: &str) -> Result<ListenAddr, String> {
+    let (path, query) = match rest.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (rest, None),
+    };
+    if !path.starts_with('/') {
+        return Err(format!("{} is not an absolute path", addr));
+    }
+    let (mut mode, mut owner) = (None, None);
+    for param in query.into_iter().flat_map(|q| q.split('&')) {
+        match param.split_once('=') {
+            Some(("mode", m)) => {
+                mode = Some(
+                    u32::from_str_radix(m, 8)
+                        .ok()
+                        .filter(|m| *m <= 0o777)
+                        .ok_or_else(|| format!("invalid mode {} in {}", m, addr))?,
+                )
+            }
+            Some(("owner", o)) => {
+                owner = Some(
+                    o.parse()
+                        .ok()
+                        .or_else(|| lookup_user(o))
+                        .ok_or_else(|| format!("unknown owner {} in {}", o, addr))?,
+                )
+            }
+            _ => return Err(format!("unknown parameter {} in {}", param, addr)),
+        }
+    }
+    Ok(ListenAddr::Unix {
+        path: PathBuf::from(path),
+        mode,
+        owner,
+    })
+}
+
+impl FromStr for ListenAddr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(rest) = s.strip_prefix("tcp://") {
+            return check_host_port(rest, s).map(ListenAddr::Tcp);
+        }
+        if let Some(rest) = s.strip_prefix("tls://") {
+            return check_host_port(rest, s).map(ListenAddr::Tls);
+        }
+        if let Some(rest) = s.strip_prefix("unix://") {
+            return parse_unix(rest, s);
+        }
+        if let Some(name) = s.strip_prefix("pipe://") {
+            if name.is_empty() || name.contains(['/', '\\']) {
+                return Err(format!("{} doesn't name a pipe", s));
+            }
+            return Ok(ListenAddr::Pipe(format!("{}{}", PIPE_PREFIX, name)));
+        }
+        if s == "stdio:" {
+            return Ok(ListenAddr::Stdio);
+        }
+        if let Some(name) = s.strip_prefix("fd://") {
+            if name.is_empty() {
+                return Err(format!("{} doesn't name a socket", s));
+            }
+            return Ok(ListenAddr::Fd(name.to_owned()));
+        }
+        if let Some(rest) = s.strip_prefix("dial://") {
+            return check_host_port(rest, s).map(ListenAddr::Dial);
+        }
+        if let Some((scheme, _)) = s.split_once("://") {
+            return Err(format!("unknown scheme {} in {}", scheme, s));
+        }
+        // Addresses written before schemes existed.
+        if s.starts_with(PIPE_PREFIX) {
+            Ok(ListenAddr::Pipe(s.to_owned()))
+        } else if s.starts_with('/') {
+            // Plain paths have no query, `?` is part of the file name.
+            Ok(ListenAddr::Unix {
+                path: PathBuf::from(s),
+                mode: None,
+                owner: None,
+            })
+        } else if let Some(rest) = s.strip_prefix("dial:") {
+            check_host_port(rest, s).map(ListenAddr::Dial)
+        } else {
+            check_host_port(s, s).map(ListenAddr::Tcp)
+        }
+    }
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListenAddr::Tcp(addr) => write!(f, "tcp://{}", addr),
+            ListenAddr::Tls(addr) => write!(f, "tls://{}", addr),
+            ListenAddr::Unix { path, mode, owner } => {
+                write!(f, "unix://{}", path.display())?;
+                let mut sep = '?';
+                if let Some(mode) = mode {
+                    write!(f, "{}mode={:04o}", sep, mode)?;
+                    sep = '&';
+                }
+                if let Some(owner) = owner {
+                    write!(f, "{}owner={}", sep, owner)?;
+                }
+                Ok(())
+            }
+            ListenAddr::Pipe(path) => write!(f, "pipe://{}", &path[PIPE_PREFIX.len()..]),
+            ListenAddr::Stdio => f.write_str("stdio:"),
+            ListenAddr::Fd(name) => write!(f, "fd://{}", name),
+            ListenAddr::Dial(addr) => write!(f, "dial://{}", addr),
+        }
+    }
+}
+
+/// Where a bridge forwards connections to.
+///
+/// A path without a scheme is taken for an `assuan-file://` one.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BackendAddr {
+    /// `assuan-file:///PATH`, a gnupg socket file holding the port and nonce of the agent.
+    AssuanFile(String),
+    /// `unix:///PATH`, a Unix socket speaking the protocol of the bridge, like a native agent.
+    Unix(PathBuf),
+    /// `tcp://HOST:PORT`, e.g. another gpg-bridge.
+    Tcp(String),
+    /// `pageant:`, Pageant or gpg-agent with `enable-putty-support`.
+    Pageant,
+    /// `ssh-auth-sock:`, the ssh agent named by `SSH_AUTH_SOCK`.
+    SshAuthSock,
+}
+
+impl BackendAddr {
+    /// Whether connections are relayed to the backend as is.
+    pub fn is_relay(&self) -> bool {
+        matches!(
+            self,
+            BackendAddr::Unix(_) | BackendAddr::Tcp(_) | BackendAddr::SshAuthSock
+        )
+    }
+
+    /// Whether clients of a bridge of type `ty` can be forwarded to the backend.
+    pub fn serves(&self, ty: SocketType) -> bool {
+        match self {
+            BackendAddr::AssuanFile(_) => {
+                matches!(ty, SocketType::Extra | SocketType::Agent | SocketType::Auto)
+            }
+            BackendAddr::Unix(_) | BackendAddr::Tcp(_) => {
+                matches!(ty, SocketType::Ssh | SocketType::Extra | SocketType::Agent)
+            }
+            BackendAddr::Pageant | BackendAddr::SshAuthSock => ty == SocketType::Ssh,
+        }
+    }
+}
+
+impl FromStr for BackendAddr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix("assuan-file://") {
+            // `assuan-file:///C:/...` names a path with a drive letter on Windows.
+            let bytes = path.as_bytes();
+            let path = match bytes {
+                [b'/', drive, b':', ..] if drive.is_ascii_alphabetic() => &path[1..],
+                [b'/', ..] => path,
+                _ => return Err(format!("{} is not an absolute path", s)),
+            };
+            return Ok(BackendAddr::AssuanFile(path.to_owned()));
+        }
+        if let Some(path) = s.strip_prefix("unix://") {
+            if !path.starts_with('/') {
+                return Err(format!("{} is not an absolute path", s));
+            }
+            return Ok(BackendAddr::Unix(PathBuf::from(path)));
+        }
+        if let Some(rest) = s.strip_prefix("tcp://") {
+            return check_host_port(rest, s).map(BackendAddr::Tcp);
+        }
+        match s {
+            "pageant:" => return Ok(BackendAddr::Pageant),
+            "ssh-auth-sock:" => return Ok(BackendAddr::SshAuthSock),
+            _ => {}
+        }
+        if let Some((scheme, _)) = s.split_once("://") {
+            return Err(format!("unknown scheme {} in {}", scheme, s));
+        }
+        if s.is_empty() {
+            return Err("backend is empty".to_owned());
+        }
+        Ok(BackendAddr::AssuanFile(s.to_owned()))
+    }
+}
+
+impl fmt::Display for BackendAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BackendAddr::AssuanFile(path) if path.starts_with('/') => {
+                write!(f, "assuan-file://{}", path)
+            }
+            BackendAddr::AssuanFile(path) => write!(f, "assuan-file:///{}", path),
+            BackendAddr::Unix(path) => write!(f, "unix://{}", path.display()),
+            BackendAddr::Tcp(addr) => write!(f, "tcp://{}", addr),
+            BackendAddr::Pageant => f.write_str("pageant:"),
+            BackendAddr::SshAuthSock => f.write_str("ssh-auth-sock:"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unix(path: &str, mode: Option<u32>, owner: Option<u32>) -> ListenAddr {
+        ListenAddr::Unix {
+            path: PathBuf::from(path),
+            mode,
+            owner,
+        }
+    }
+
+    #[test]
+    fn test_listen() {
+        let cases = [
+            (
+                "tcp://127.0.0.1:4321",
+                ListenAddr::Tcp("127.0.0.1:4321".into()),
+            ),
+            ("tls://[::1]:4321", ListenAddr::Tls("[::1]:4321".into())),
+            ("127.0.0.1:4321", ListenAddr::Tcp("127.0.0.1:4321".into())),
+            ("unix:///run/S.agent", unix("/run/S.agent", None, None)),
+            (
+                "unix:///run/S.agent?mode=0660&owner=1000",
+                unix("/run/S.agent", Some(0o660), Some(1000)),
+            ),
+            ("/run/S.agent", unix("/run/S.agent", None, None)),
+            (
+                "/run/what?mode=0600",
+                unix("/run/what?mode=0600", None, None),
+            ),
+            (
+                "pipe://gpg-agent",
+                ListenAddr::Pipe(r"\\.\pipe\gpg-agent".into()),
+            ),
+            (
+                r"\\.\pipe\gpg-agent",
+                ListenAddr::Pipe(r"\\.\pipe\gpg-agent".into()),
+            ),
+            ("stdio:", ListenAddr::Stdio),
+            ("fd://ssh", ListenAddr::Fd("ssh".into())),
+            ("dial://vm:4330", ListenAddr::Dial("vm:4330".into())),
+            ("dial:vm:4330", ListenAddr::Dial("vm:4330".into())),
+        ];
+        for (s, addr) in cases {
+            assert_eq!(s.parse::<ListenAddr>(), Ok(addr), "{}", s);
+        }
+        #[cfg(unix)]
+        assert_eq!(
+            "unix:///run/S.agent?owner=root".parse::<ListenAddr>(),
+            Ok(unix("/run/S.agent", None, Some(0)))
+        );
+    }
+
+    #[test]
+    fn test_listen_invalid() {
+        for s in [
+            "tcp://127.0.0.1",
+            "tcp://:4321",
+            "tls://host:port",
+            "unix://run/S.agent",
+            "unix:///run/S.agent?mode=0800",
+            "unix:///run/S.agent?mode=rw",
+            "unix:///run/S.agent?owner=no-such-user-here",
+            "unix:///run/S.agent?group=wheel",
+            "pipe://",
+            "pipe://a/b",
+            "fd://",
+            "dial://vm",
+            "udp://127.0.0.1:4321",
+            "localhost",
+        ] {
+            assert!(s.parse::<ListenAddr>().is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn test_listen_display() {
+        for s in [
+            "tcp://127.0.0.1:4321",
+            "tls://127.0.0.1:4321",
+            "unix:///run/S.agent?mode=0660&owner=1000",
+            "unix:///run/S.agent?owner=1000",
+            "pipe://gpg-agent",
+            "stdio:",
+            "fd://ssh",
+            "dial://vm:4330",
+        ] {
+            assert_eq!(s.parse::<ListenAddr>().unwrap().to_string(), s);
+        }
+        let legacy: ListenAddr = r"\\.\pipe\gpg-agent".parse().unwrap();
+        assert_eq!(legacy.to_string(), "pipe://gpg-agent");
+    }
+
+    #[test]
+    fn test_backend() {
+        let cases = [
+            (
+                "assuan-file:///home/me/.gnupg/S.gpg-agent",
+                BackendAddr::AssuanFile("/home/me/.gnupg/S.gpg-agent".into()),
+            ),
+            (
+                "assuan-file:///C:/gnupg/S.gpg-agent",
+                BackendAddr::AssuanFile("C:/gnupg/S.gpg-agent".into()),
+            ),
+            (
+                "C:/gnupg/S.gpg-agent",
+                BackendAddr::AssuanFile("C:/gnupg/S.gpg-agent".into()),
+            ),
+            (
+                "unix:///run/S.agent",
+                BackendAddr::Unix("/run/S.agent".into()),
+            ),
+            (
+                "tcp://10.0.0.1:4321",
+                BackendAddr::Tcp("10.0.0.1:4321".into()),
+            ),
+            ("pageant:", BackendAddr::Pageant),
+            ("ssh-auth-sock:", BackendAddr::SshAuthSock),
+        ];
+        for (s, addr) in cases {
+            assert_eq!(s.parse::<BackendAddr>(), Ok(addr), "{}", s);
+        }
+        for s in [
+            "",
+            "assuan-file://relative",
+            "unix://run/S.agent",
+            "tcp://10.0.0.1",
+            "http://example.com:80",
+        ] {
+            assert!(s.parse::<BackendAddr>().is_err(), "{}", s);
+        }
+        let drive: BackendAddr = "C:/gnupg/S.gpg-agent".parse().unwrap();
+        assert_eq!(drive.to_string(), "assuan-file:///C:/gnupg/S.gpg-agent");
+    }
+}
@@ -0,0 +1,481 @@
+//! Token handshake that protects TCP listeners, similar to the nonce of gpg's emulated sockets.
+//!
+//! Before any protocol bytes, the client sends the token followed by `\n`. Connections that send
+//! anything else are dropped without a reply. The token is either a static one read from a file,
+//! or one issued by the running bridge for a single listener and a limited time.
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::debug;
+use parking_lot::Mutex;
+use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+
+use crate::config::BridgeConfig;
+use crate::secret::{self, SecretBuf};
+use crate::stream::SplitStream;
+use crate::util::report_data_err;
+
+/// Longest token accepted, including the trailing newline.
+const MAX_TOKEN_LEN: usize = 1024;
+/// Clients that don't send a token in time are dropped.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Loads a token from `path`, surrounding whitespace is ignored.
+pub async fn load_token(path: &Path) -> io::Result<SecretBuf> {
+    let mut content = tokio::fs::read(path)
+        .await
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+    let token = SecretBuf::from_slice(content.trim_ascii());
+    secret::wipe(&mut content);
+    if token.is_empty() {
+        return Err(report_data_err(format!(
+            "token file {} is empty",
+            path.display()
+        )));
+    }
+    Ok(token)
+}
+
+/// Compares in time that only depends on the lengths.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    let diff = a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y));
+    // Keeps the compiler from short-circuiting the fold.
+    std::hint::black_box(diff) == 0
+}
+
+/// Reads the token line sent by a client.
+pub async fn receive(conn: &mut impl SplitStream) -> io::Result<SecretBuf> {
+    let (mut read, _) = conn.split_rw();
+    let mut buf = SecretBuf::new(MAX_TOKEN_LEN);
+    let mut len = 0;
+    // Reads byte by byte, so nothing after the token is consumed.
+    let read_line = async {
+        loop {
+            let b = read.read_u8().await?;
+            if b == b'\n' {
+                return Ok(());
+            }
+            if len == buf.len() {
+                return Err(report_data_err("token is too long"));
+            }
+            buf[len] = b;
+            len += 1;
+        }
+    };
+    match tokio::time::timeout(HANDSHAKE_TIMEOUT, read_line).await {
+        Ok(res) => res?,
+        Err(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "client didn't send a token in time",
+            ))
+        }
+    }
+    let token = buf[..len].strip_suffix(b"\r").unwrap_or(&buf[..len]);
+    Ok(SecretBuf::from_slice(token))
+}
+
+fn denied(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::PermissionDenied, reason)
+}
+
+/// What a listener requires clients to send before serving them.
+#[derive(Clone)]
+pub struct Authenticator {
+    token_file: Option<PathBuf>,
+    /// Accepts tokens issued for the named listener.
+    issued: Option<(Arc<TokenStore>, String)>,
+}
+
+impl Authenticator {
+    pub fn new(config: &BridgeConfig, tokens: &Arc<TokenStore>) -> Authenticator {
+        Authenticator {
+            token_file: config.token_file.clone(),
+            issued: config
+                .issued_tokens
+                .then(|| (tokens.clone(), config.name().to_owned())),
+        }
+    }
+
+    /// Only accepts the static token in `token_file`, if any.
+    pub fn with_token_file(token_file: Option<PathBuf>) -> Authenticator {
+        Authenticator {
+            token_file,
+            issued: None,
+        }
+    }
+
+    /// Checks the token sent by a client, if the listener requires one.
+    pub async fn authenticate(&self, conn: &mut impl SplitStream) -> io::Result<()> {
+        if self.token_file.is_none() && self.issued.is_none() {
+            return Ok(());
+        }
+        let token = receive(conn).await?;
+        if let Some(path) = &self.token_file {
+            let expected = load_token(path).await?;
+            if constant_time_eq(&token, &expected) {
+                return Ok(());
+            }
+        }
+        match &self.issued {
+            Some((tokens, listener)) if token.starts_with(ISSUED_PREFIX.as_bytes()) => {
+                let id = tokens.verify(listener, &token).map_err(denied)?;
+                debug!("authenticated by issued token {}", id);
+                Ok(())
+            }
+            _ => Err(denied("invalid token")),
+        }
+    }
+}
+
+/// Sends the token in `token_file` to a listener that requires it.
+pub async fn send(conn: &mut impl SplitStream, token_file: &Path) -> io::Result<()> {
+    let token = load_token(token_file).await?;
+    let mut line = SecretBuf::new(token.len() + 1);
+    line[..token.len()].copy_from_slice(&token);
+    line[token.len()] = b'\n';
+    let (_, mut write) = conn.split_rw();
+    write.write_all(&line).await?;
+    write.flush().await
+}
+
+/// Prefix of issued tokens, which tells them apart from static ones.
+const ISSUED_PREFIX: &str = "gbt.";
+/// Issued tokens must expire before the year 10000, which is the last one logs can show.
+const MAX_EXPIRES: u64 = 253_402_300_800;
+
+/// A token issued by [`TokenStore::issue`], the token itself is not kept.
+#[derive(Clone, Debug)]
+pub struct IssuedToken {
+    pub id: u64,
+    /// Name of the only listener that accepts the token.
+    pub listener: String,
+    pub expires: SystemTime,
+    pub revoked: bool,
+}
+
+/// Issues tokens signed by a key that only lives in memory, so they are all invalidated when the
+/// process exits.
+pub struct TokenStore {
+    key: SecretBuf,
+    next_id: Mutex<u64>,
+    issued: Mutex<BTreeMap<u64, IssuedToken>>,
+}
+
+impl Default for TokenStore {
+    fn default() -> TokenStore {
+        let mut key = SecretBuf::new(32);
+        getrandom::getrandom(&mut key).expect("failed to generate the token key");
+        TokenStore {
+            key,
+            next_id: Mutex::new(0),
+            issued: Mutex::new(BTreeMap::new()),
+        }
+    }
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+impl TokenStore {
+    /// Signs `id`, `expires` and `listener`, so a token can't be altered or used elsewhere.
+    fn sign(&self, id: u64, expires: u64, listener: &str) -> String {
+        let mac = hmac_sha256::HMAC::mac(format!("{}.{}.{}", id, expires, listener), &*self.key);
+        mac.iter().fold(String::with_capacity(64), |mut hex, b| {
+            let _ = write!(hex, "{:02x}", b);
+            hex
+        })
+    }
+
+    /// Issues a token that `listener` accepts for `ttl`.
+    ///
+    /// Fails if the token would expire after the year 9999.
+    pub fn issue(&self, listener: &str, ttl: Duration) -> io::Result<(IssuedToken, SecretBuf)> {
+        let now = SystemTime::now();
+        let expires = now
+            .checked_add(ttl)
+            .map(unix_secs)
+            .filter(|expires| *expires < MAX_EXPIRES)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("ttl of {} seconds is too long", ttl.as_secs()),
+                )
+            })?;
+        let id = {
+            let mut next_id = self.next_id.lock();
+            *next_id += 1;
+            *next_id
+        };
+        let mac = self.sign(id, expires, listener);
+        let token = format!("{}{}.{}.{}", ISSUED_PREFIX, id, expires, mac);
+        let issued = IssuedToken {
+            id,
+            listener: listener.to_owned(),
+            expires: UNIX_EPOCH + Duration::from_secs(expires),
+            revoked: false,
+        };
+        let mut tokens = self.issued.lock();
+        tokens.retain(|_, t| t.expires > now);
+        tokens.insert(id, issued.clone());
+        Ok((issued, SecretBuf::from_slice(token.as_bytes())))
+    }
+
+    /// Tokens that haven't expired yet, including revoked ones.
+    pub fn list(&self) -> Vec<IssuedToken> {
+        let now = SystemTime::now();
+        let mut tokens = self.issued.lock();
+        tokens.retain(|_, t| t.expires > now);
+        tokens.values().cloned().collect()
+    }
+
+    /// Revokes the token with the given id, returns false if there is no such token.
+    pub fn revoke(&self, id: u64) -> bool {
+        match self.issued.lock().get_mut(&id) {
+            Some(token) => {
+                token.revoked = true;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Checks a token sent to `listener` and returns its id.
+    fn verify(&self, listener: &str, token: &[u8]) -> Result<u64, &'static str> {
+        let token = std::str::from_utf8(token).map_err(|_| "invalid token")?;
+        let mut parts = token[ISSUED_PREFIX.len()..].splitn(3, '.');
+        let (Some(id), Some(expires), Some(mac)) = (parts.next(), parts.next(), parts.next())
+        else {
+            return Err("invalid token");
+        };
+        let (Ok(id), Ok(expires)) = (id.parse(), expires.parse()) else {
+            return Err("invalid token");
+        };
+        if !constant_time_eq(mac.as_bytes(), self.sign(id, expires, listener).as_bytes()) {
+            return Err("invalid token");
+        }
+        let tokens = self.issued.lock();
+        match tokens.get(&id) {
+            Some(t) if t.revoked => Err("token is revoked"),
+            Some(t) if t.expires > SystemTime::now() => Ok(id),
+            _ => Err("token is expired"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::DuplexStream;
+
+    use super::*;
+
+    fn token_file(name: &str, content: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "gpg-bridge-test-{}-{}.token",
+            std::process::id(),
+            name
+        ));
+        std::fs::write(&path, content).unwrap();
+        path
+    }
+
+    async fn client(line: &[u8]) -> DuplexStream {
+        let (mut client, server) = tokio::io::duplex(4096);
+        client.write_all(line).await.unwrap();
+        server
+    }
+
+    #[test]
+    fn test_constant_time_eq() {
+        let cases: &[(&[u8], &[u8], bool)] = &[
+            (b"", b"", true),
+            (b"secret", b"secret", true),
+            (b"secret", b"secreT", false),
+            (b"secret", b"secret2", false),
+            (b"secret2", b"secret", false),
+            (b"", b"s", false),
+        ];
+        for (a, b, eq) in cases {
+            assert_eq!(constant_time_eq(a, b), *eq, "{:?} {:?}", a, b);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_token_file() {
+        let path = token_file("static", "  secret\n");
+        let auth = Authenticator::with_token_file(Some(path.clone()));
+        let long = [&[b'a'; MAX_TOKEN_LEN + 1][..], b"\n"].concat();
+        let cases: &[(&[u8], Option<io::ErrorKind>)] = &[
+            (b"secret\n", None),
+            (b"secret\r\n", None),
+            (b"secret \n", Some(io::ErrorKind::PermissionDenied)),
+            (b"secre\n", Some(io::ErrorKind::PermissionDenied)),
+            (b"\n", Some(io::ErrorKind::PermissionDenied)),
+            (&long, Some(io::ErrorKind::InvalidData)),
+            (b"secret", Some(io::ErrorKind::UnexpectedEof)),
+        ];
+        for (line, err) in cases {
+            let mut conn = client(line).await;
+            let res = auth.authenticate(&mut conn).await;
+            assert_eq!(res.err().map(|e| e.kind()), *err, "{:?}", line);
+        }
+
+        // Protocol data right after the token is left to the bridge.
+        let mut conn = client(b"secret\nOK\n").await;
+        auth.authenticate(&mut conn).await.unwrap();
+        let mut rest = [0; 3];
+        conn.read_exact(&mut rest).await.unwrap();
+        assert_eq!(&rest, b"OK\n");
+
+        let (mut client, mut server) = tokio::io::duplex(4096);
+        send(&mut client, &path).await.unwrap();
+        let mut line = [0; 7];
+        server.read_exact(&mut line).await.unwrap();
+        assert_eq!(&line, b"secret\n");
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_no_token() {
+        let auth = Authenticator::with_token_file(None);
+        let mut conn = client(b"OK\n").await;
+        auth.authenticate(&mut conn).await.unwrap();
+        let mut rest = [0; 3];
+        conn.read_exact(&mut rest).await.unwrap();
+        assert_eq!(&rest, b"OK\n");
+    }
+
+    #[tokio::test]
+    async fn test_empty_token_file() {
+        let path = token_file("empty", " \n");
+        let err = load_token(&path).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    fn issued(store: &Arc<TokenStore>, listener: &str) -> Authenticator {
+        Authenticator {
+            token_file: None,
+            issued: Some((store.clone(), listener.to_owned())),
+        }
+    }
+
+    #[test]
+    fn test_issue_format() {
+        let store = TokenStore::default();
+        let (issued, token) = store.issue("work", Duration::from_secs(60)).unwrap();
+        let expires = unix_secs(issued.expires);
+        let mac = hmac_sha256::HMAC::mac(format!("{}.{}.work", issued.id, expires), &*store.key);
+        let hex = mac.iter().map(|b| format!("{:02x}", b)).collect::<String>();
+        assert_eq!(
+            std::str::from_utf8(&token).unwrap(),
+            format!("gbt.{}.{}.{}", issued.id, expires, hex)
+        );
+        assert_eq!(store.verify("work", &token), Ok(issued.id));
+
+        let (next, _) = store.issue("work", Duration::from_secs(60)).unwrap();
+        assert_eq!(next.id, issued.id + 1);
+    }
+
+    #[test]
+    fn test_verify() {
+        let store = TokenStore::default();
+        let (issued, token) = store.issue("work", Duration::from_secs(60)).unwrap();
+        let token = std::str::from_utf8(&token).unwrap().to_owned();
+        let expires = unix_secs(issued.expires);
+        let other = TokenStore::default();
+        let (_, foreign) = other.issue("work", Duration::from_secs(60)).unwrap();
+        let cases = [
+            ("home", token.clone(), Err("invalid token")),
+            (
+                "work",
+                token.replace(&format!(".{}.", expires), &format!(".{}.", expires + 3600)),
+                Err("invalid token"),
+            ),
+            (
+                "work",
+                token.replacen(&format!("gbt.{}.", issued.id), "gbt.7.", 1),
+                Err("invalid token"),
+            ),
+            (
+                "work",
+                token[..token.len() - 1].to_owned(),
+                Err("invalid token"),
+            ),
+            ("work", "gbt.1.2".to_owned(), Err("invalid token")),
+            ("work", "gbt.x.y.z".to_owned(), Err("invalid token")),
+            (
+                "work",
+                String::from_utf8(foreign.to_vec()).unwrap(),
+                Err("invalid token"),
+            ),
+            ("work", token.clone(), Ok(issued.id)),
+        ];
+        for (listener, token, expected) in cases {
+            assert_eq!(
+                store.verify(listener, token.as_bytes()),
+                expected,
+                "{}",
+                token
+            );
+        }
+
+        assert!(store.revoke(issued.id));
+        assert!(!store.revoke(issued.id + 1));
+        assert_eq!(
+            store.verify("work", token.as_bytes()),
+            Err("token is revoked")
+        );
+        assert!(store.list()[0].revoked);
+    }
+
+    #[test]
+    fn test_expiry() {
+        let store = TokenStore::default();
+        let (_, token) = store.issue("work", Duration::ZERO).unwrap();
+        assert_eq!(store.verify("work", &token), Err("token is expired"));
+        assert!(store.list().is_empty());
+
+        let now = unix_secs(SystemTime::now());
+        for ttl in [
+            Duration::MAX,
+            Duration::from_secs(MAX_EXPIRES),
+            Duration::from_secs(MAX_EXPIRES - now),
+        ] {
+            let err = store.issue("work", ttl).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        }
+        let (issued, _) = store
+            .issue("work", Duration::from_secs(MAX_EXPIRES - now - 3600))
+            .unwrap();
+        assert!(unix_secs(issued.expires) < MAX_EXPIRES);
+    }
+
+    #[tokio::test]
+    async fn test_authenticate_issued() {
+        let store = Arc::new(TokenStore::default());
+        let (_, token) = store.issue("work", Duration::from_secs(60)).unwrap();
+        let line = [&*token, b"\n"].concat();
+
+        issued(&store, "work")
+            .authenticate(&mut client(&line).await)
+            .await
+            .unwrap();
+        for (listener, line) in [("home", line.as_slice()), ("work", b"secret\n")] {
+            let err = issued(&store, listener)
+                .authenticate(&mut client(line).await)
+                .await
+                .unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        }
+    }
+}
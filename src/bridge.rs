@@ -1,2 +1,168 @@
+pub mod auto;
+pub mod backend;
 pub mod extra;
+pub mod mux;
+pub mod rendezvous;
+#[cfg(windows)]
 pub mod ssh;
+pub mod upstream;
+
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::{debug, info};
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+
+use crate::auth::Authenticator;
+use crate::config::BridgeConfig;
+use crate::listener::PeerInfo;
+use crate::logging;
+use crate::mux::UpstreamLease;
+use crate::registry::{ConnectionState, ListenerState, Registry};
+
+/// Limits applied to every bridged connection.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Timeouts {
+    /// Closes a connection that hasn't transferred anything in either direction for this long.
+    pub idle: Option<Duration>,
+    /// Closes a connection that has been open for this long, no matter whether it's active.
+    pub total: Option<Duration>,
+}
+
+/// Stops a running bridge and keeps track of the connections it has accepted.
+#[derive(Clone)]
+pub struct Control {
+    stop: CancellationToken,
+    /// Set once the bridge has started accepting.
+    listening: CancellationToken,
+    closed: CancellationToken,
+    abort: CancellationToken,
+    connections: TaskTracker,
+    registry: Arc<Registry>,
+    listener: Arc<ListenerState>,
+}
+
+impl Control {
+    /// Creates a control for the bridge described by `listener`, its connections are recorded
+    /// in `registry`.
+    pub fn new(registry: Arc<Registry>, listener: Arc<ListenerState>) -> Self {
+        Control {
+            stop: CancellationToken::new(),
+            listening: CancellationToken::new(),
+            closed: CancellationToken::new(),
+            abort: CancellationToken::new(),
+            connections: TaskTracker::new(),
+            registry,
+            listener,
+        }
+    }
+
+    pub fn listener(&self) -> &Arc<ListenerState> {
+        &self.listener
+    }
+
+    /// Asks the bridge to stop accepting new connections.
+    ///
+    /// Accepted connections are still served until they finish.
+    pub fn stop(&self) {
+        self.stop.cancel();
+    }
+
+    pub async fn stopped(&self) {
+        self.stop.cancelled().await
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.stop.is_cancelled()
+    }
+
+    /// Whether the bridge has ever started accepting, failing to listen after that is retried.
+    pub fn has_listened(&self) -> bool {
+        self.listening.is_cancelled()
+    }
+
+    pub(crate) fn mark_listening(&self) {
+        self.listening.cancel();
+    }
+
+    /// Closes all accepted connections immediately.
+    pub fn abort(&self) {
+        self.abort.cancel();
+    }
+
+    /// Number of connections that are still being served.
+    pub fn connections(&self) -> usize {
+        self.connections.len()
+    }
+
+    /// Resolves once the listener of the bridge has been released.
+    pub async fn closed(&self) {
+        self.closed.cancelled().await
+    }
+
+    /// Builds what authenticates clients according to `config`.
+    pub(crate) fn authenticator(&self, config: &BridgeConfig) -> Authenticator {
+        Authenticator::new(config, self.registry.tokens())
+    }
+
+    /// Keeps the mux session to `upstream` open while the lease is held.
+    pub(crate) fn upstream_session(&self, upstream: &str) -> UpstreamLease {
+        self.registry.upstreams().lease(upstream)
+    }
+
+    /// Records a newly accepted connection.
+    pub(crate) fn register(&self, peer: PeerInfo) -> Arc<ConnectionState> {
+        self.registry.register(&self.listener, peer)
+    }
+
+    /// Serves a registered connection in background until `task` finishes or it's killed.
+    pub(crate) fn spawn<F>(&self, connection: Arc<ConnectionState>, task: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let abort = self.abort.clone();
+        let registry = self.registry.clone();
+        self.connections
+            .spawn(logging::scope(connection.id, async move {
+                if connection.peer.is_empty() {
+                    info!("connection accepted by {}", connection.listener.name);
+                } else {
+                    info!(
+                        "connection accepted by {} from {}",
+                        connection.listener.name, connection.peer
+                    );
+                }
+                tokio::select! {
+                    _ = task => {}
+                    _ = abort.cancelled() => {}
+                    _ = connection.killed() => info!("connection killed"),
+                }
+                registry.unregister(connection.id);
+                debug!("connection closed");
+            }));
+    }
+
+    /// Marks the listener as released and waits for all accepted connections to finish.
+    pub(crate) async fn drain(&self) {
+        self.closed.cancel();
+        self.connections.close();
+        self.connections.wait().await;
+    }
+
+    /// A control of the same bridge for serving channels of multiplexed connections.
+    ///
+    /// Draining it doesn't mark the listener as released, which is up to the bridge that owns
+    /// the listener.
+    pub(crate) fn for_channels(&self) -> Control {
+        Control {
+            closed: CancellationToken::new(),
+            ..self.clone()
+        }
+    }
+
+    pub(crate) fn mark_closed(&self) {
+        self.closed.cancel();
+    }
+}
@@ -0,0 +1,245 @@
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::{debug, error, info, warn};
+use tokio::io::AsyncReadExt as _;
+use tokio::sync::watch;
+
+use crate::bridge::Control;
+use crate::config::BridgeConfig;
+use crate::listener::Listener;
+use crate::registry::BackendStatus;
+use crate::stream::replay::Replay;
+use crate::stream::SplitStream;
+use crate::util::report_data_err;
+use crate::{bridge, SocketType};
+
+/// How long a client may stay silent before it's taken for an Assuan client, which waits for
+/// the greeting of the agent.
+const SNIFF_TIMEOUT: Duration = Duration::from_millis(300);
+/// Time to complete the header of an ssh agent request once it has started.
+const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Request types an ssh agent client may start with, see draft-miller-ssh-agent.
+const SSH_REQUESTS: &[u8] = &[1, 11, 13, 17, 18, 19, 20, 21, 22, 23, 25, 26, 27];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Protocol {
+    Ssh,
+    Assuan,
+}
+
+/// Tells the protocol of a client by the bytes it sends first, which are returned as well.
+///
+/// An ssh agent request starts with a 4 byte big-endian length and a known type, while an Assuan
+/// client usually sends nothing before the `OK` greeting.
+async fn sniff(conn: &mut impl SplitStream) -> io::Result<(Protocol, Vec<u8>)> {
+    let (mut read, _) = conn.split_rw();
+    let mut head = [0; 5];
+    let n = match tokio::time::timeout(SNIFF_TIMEOUT, read.read(&mut head)).await {
+        Ok(n) => n?,
+        Err(_) => return Ok((Protocol::Assuan, vec![])),
+    };
+    if n == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "client closed before sending anything",
+        ));
+    }
+    // Requests are far shorter than 16 MiB, so the length starts with a zero byte, which never
+    // starts an Assuan line.
+    if head[0] != 0 {
+        if head[0].is_ascii_alphabetic() {
+            return Ok((Protocol::Assuan, head[..n].to_vec()));
+        }
+        return Err(report_data_err(
+            "client speaks neither ssh agent nor Assuan",
+        ));
+    }
+    match tokio::time::timeout(HEADER_TIMEOUT, read.read_exact(&mut head[n..])).await {
+        Ok(res) => res?,
+        Err(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "client didn't finish the request header in time",
+            ))
+        }
+    };
+    if !SSH_REQUESTS.contains(&head[4]) {
+        return Err(report_data_err(format!(
+            "unknown ssh agent request type {}",
+            head[4]
+        )));
+    }
+    Ok((Protocol::Ssh, head.to_vec()))
+}
+
+/// Serves both ssh agent and Assuan clients on one listener, each connection is forwarded to
+/// Pageant or the extra socket by what it sends first.
+///
+/// Assuan clients are served once they have been silent for [`SNIFF_TIMEOUT`].
+pub async fn bridge_auto<L>(
+    mut listener: L,
+    config: watch::Receiver<Arc<BridgeConfig>>,
+    control: Control,
+) -> io::Result<()>
+where
+    L: Listener,
+    L::Connection: SplitStream + Send + 'static,
+{
+    info!("bridge to ssh or assuan by detection");
+    loop {
+        let (mut conn, peer) = tokio::select! {
+            res = listener.accept() => res?,
+            _ = control.stopped() => break,
+        };
+
+        // Configuration changes only apply to new connections.
+        let config = config.borrow().clone();
+        let timeouts = config.timeouts();
+        let backend = config.assuan_file();
+        let authenticator = control.authenticator(&config);
+        let connection = control.register(peer);
+        let listener = control.listener().clone();
+        control.spawn(connection.clone(), async move {
+            if let Err(e) = authenticator.authenticate(&mut conn).await {
+                warn!("authentication failed: {}", e);
+                listener.record_failure(&e);
+                return;
+            }
+            let res = async {
+                let (protocol, prefix) = sniff(&mut conn).await?;
+                debug!("detected {:?} client", protocol);
+                let mut conn = Replay::new(prefix, conn);
+                match protocol {
+                    #[cfg(windows)]
+                    Protocol::Ssh => {
+                        bridge::ssh::delegate_ssh(conn, timeouts, &connection.activity).await
+                    }
+                    #[cfg(not(windows))]
+                    Protocol::Ssh => Err(crate::util::other_error(
+                        "ssh bridge requires Pageant, which is only available on Windows"
+                            .to_owned(),
+                    )),
+                    Protocol::Assuan => {
+                        let args = async {
+                            let path = match backend {
+                                Some(path) => path,
+                                None => SocketType::Extra.try_get_path().await?,
+                            };
+                            let (port, nonce) = bridge::extra::load_port_nounce(&path).await?;
+                            Ok::<_, io::Error>((path, port, nonce))
+                        };
+                        let (path, port, nonce) = match args.await {
+                            Ok(args) => args,
+                            Err(e) => {
+                                bridge::extra::reply_error(&mut conn, &e).await;
+                                return Err(e);
+                            }
+                        };
+                        listener.set_backend(BackendStatus {
+                            path: Some(path),
+                            port: Some(port),
+                            nonce_loaded: true,
+                        });
+                        bridge::extra::delegate(conn, port, nonce, timeouts, &connection.activity)
+                            .await
+                    }
+                }
+            };
+            if let Err(e) = res.await {
+                error!("failed to delegate stream: {:?}", e);
+                listener.record_failure(&e);
+            }
+        });
+    }
+    drop(listener);
+    control.drain().await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use tokio::io::{AsyncWriteExt as _, DuplexStream};
+
+    use super::*;
+
+    type Sniffed<'a> = Result<(Protocol, &'a [u8]), io::ErrorKind>;
+
+    struct Sniff {
+        res: io::Result<(Protocol, Vec<u8>)>,
+        server: DuplexStream,
+        /// The client if it's kept open.
+        _client: Option<DuplexStream>,
+    }
+
+    /// Sniffs a client that writes `chunks` a moment apart, then closes if `close` is set.
+    async fn sniff_chunks(chunks: &[&[u8]], close: bool) -> Sniff {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        let chunks: Vec<Vec<u8>> = chunks.iter().map(|c| c.to_vec()).collect();
+        let writer = tokio::spawn(async move {
+            for chunk in chunks {
+                client.write_all(&chunk).await.unwrap();
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+            (!close).then_some(client)
+        });
+        let res = sniff(&mut server).await;
+        Sniff {
+            res,
+            server,
+            _client: writer.await.unwrap(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sniff() {
+        let cases: &[(&[&[u8]], Sniffed)] = &[
+            (&[b"\0\0\0\x01\x0b"], Ok((Protocol::Ssh, b"\0\0\0\x01\x0b"))),
+            (
+                &[b"\0\0\0\x05\x0d\0\0\0\0"],
+                Ok((Protocol::Ssh, b"\0\0\0\x05\x0d")),
+            ),
+            (
+                &[b"\0", b"\0\0", b"\x01", b"\x1b"],
+                Ok((Protocol::Ssh, b"\0\0\0\x01\x1b")),
+            ),
+            (&[b"GETINFO version\n"], Ok((Protocol::Assuan, b"GETIN"))),
+            (&[b"NO", b"P\n"], Ok((Protocol::Assuan, b"NO"))),
+            (&[b"\0\0\0\x01\x63"], Err(io::ErrorKind::InvalidData)),
+            (&[b"\0\0\0\x01\x0c"], Err(io::ErrorKind::InvalidData)),
+            (&[b"\x16\x03\x01\x02\0"], Err(io::ErrorKind::InvalidData)),
+            (&[b"# comment\n"], Err(io::ErrorKind::InvalidData)),
+            (&[b"\0\0", b"\0"], Err(io::ErrorKind::UnexpectedEof)),
+            (&[], Err(io::ErrorKind::UnexpectedEof)),
+        ];
+        for (chunks, expected) in cases {
+            let res = sniff_chunks(chunks, true).await.res;
+            let res = res.as_ref().map(|(p, prefix)| (*p, prefix.as_slice()));
+            assert_eq!(res.map_err(|e| e.kind()), *expected, "{:?}", chunks);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sniff_silent() {
+        let started = Instant::now();
+        let sniffed = sniff_chunks(&[], false).await;
+        assert_eq!(sniffed.res.unwrap(), (Protocol::Assuan, vec![]));
+        assert!(started.elapsed() >= SNIFF_TIMEOUT);
+    }
+
+    #[tokio::test]
+    async fn test_replay_prefix() {
+        let sniffed = sniff_chunks(&[b"GETINFO version\n"], true).await;
+        let (protocol, prefix) = sniffed.res.unwrap();
+        assert_eq!(protocol, Protocol::Assuan);
+        let mut conn = Replay::new(prefix, sniffed.server);
+        let (mut read, _) = conn.split_rw();
+        let mut line = String::new();
+        read.read_to_string(&mut line).await.unwrap();
+        assert_eq!(line, "GETINFO version\n");
+    }
+}
@@ -0,0 +1,122 @@
+use std::io;
+use std::sync::Arc;
+
+use log::{error, info, warn};
+use tokio::sync::watch;
+
+use crate::addr::BackendAddr;
+use crate::bridge::upstream::delegate;
+use crate::bridge::{Control, Timeouts};
+use crate::config::BridgeConfig;
+use crate::listener::Listener;
+use crate::registry::BackendStatus;
+use crate::stream::{Activity, SplitStream};
+use crate::util::other_error;
+use crate::SocketType;
+
+/// Named pipe of the OpenSSH agent on Windows, used when `SSH_AUTH_SOCK` is not set.
+#[cfg(windows)]
+const OPENSSH_AGENT_PIPE: &str = r"\\.\pipe\openssh-ssh-agent";
+
+/// Forwards every connection to a backend that speaks the protocol of the bridge, relaying the
+/// stream as is.
+pub async fn bridge_to_backend<L>(
+    ty: SocketType,
+    mut listener: L,
+    config: watch::Receiver<Arc<BridgeConfig>>,
+    control: Control,
+) -> io::Result<()>
+where
+    L: Listener,
+    L::Connection: SplitStream + Send + 'static,
+{
+    info!(
+        "bridge {} to {}",
+        ty,
+        config.borrow().backend.as_deref().unwrap_or_default()
+    );
+    let mut recorded = None;
+    if let Ok(Some(backend)) = config.borrow().backend_addr() {
+        record_backend(&control, &mut recorded, backend);
+    }
+    loop {
+        let (mut conn, peer) = tokio::select! {
+            res = listener.accept() => res?,
+            _ = control.stopped() => break,
+        };
+
+        // Configuration changes only apply to new connections.
+        let config = config.borrow().clone();
+        let timeouts = config.timeouts();
+        let backend = match config.backend_addr() {
+            Ok(Some(backend)) => backend,
+            _ => {
+                error!("bridge {} has no backend to relay to", config.name());
+                continue;
+            }
+        };
+        record_backend(&control, &mut recorded, backend.clone());
+        let authenticator = control.authenticator(&config);
+        let connection = control.register(peer);
+        let listener = control.listener().clone();
+        control.spawn(connection.clone(), async move {
+            if let Err(e) = authenticator.authenticate(&mut conn).await {
+                warn!("authentication failed: {}", e);
+                listener.record_failure(&e);
+                return;
+            }
+            if let Err(e) = relay(ty, conn, &backend, timeouts, &connection.activity).await {
+                error!("failed to forward to {}: {:?}", backend, e);
+                listener.record_failure(&e);
+            }
+        });
+    }
+    drop(listener);
+    control.drain().await;
+    Ok(())
+}
+
+/// Shows `backend` in `control status`, unless it's `recorded` already, reloads may change it.
+fn record_backend(control: &Control, recorded: &mut Option<BackendAddr>, backend: BackendAddr) {
+    if recorded.as_ref() != Some(&backend) {
+        control.listener().set_backend(BackendStatus {
+            path: Some(backend.to_string()),
+            ..Default::default()
+        });
+        *recorded = Some(backend);
+    }
+}
+
+/// Connects to `backend` and relays `conn` to it until either side closes.
+///
+/// `tcp://` backends are other gpg-bridges, which are connected to as upstreams instead.
+pub(crate) async fn relay(
+    ty: SocketType,
+    conn: impl SplitStream,
+    backend: &BackendAddr,
+    timeouts: Timeouts,
+    activity: &Activity,
+) -> io::Result<()> {
+    match backend {
+        #[cfg(unix)]
+        BackendAddr::Unix(path) => {
+            let stream = tokio::net::UnixStream::connect(path).await?;
+            delegate(ty, conn, stream, timeouts, activity).await
+        }
+        #[cfg(unix)]
+        BackendAddr::SshAuthSock => {
+            let path = std::env::var_os("SSH_AUTH_SOCK")
+                .ok_or_else(|| other_error("SSH_AUTH_SOCK is not set".to_owned()))?;
+            let stream = tokio::net::UnixStream::connect(path).await?;
+            delegate(ty, conn, stream, timeouts, activity).await
+        }
+        #[cfg(windows)]
+        BackendAddr::SshAuthSock => {
+            let path =
+                std::env::var_os("SSH_AUTH_SOCK").unwrap_or_else(|| OPENSSH_AGENT_PIPE.into());
+            let stream = tokio::net::windows::named_pipe::ClientOptions::new().open(path)?;
+            delegate(ty, conn, stream, timeouts, activity).await
+        }
+        backend => Err(other_error(format!("{} can't be relayed to", backend))),
+    }
+}
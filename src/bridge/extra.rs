@@ -1,85 +1,168 @@
+use std::io;
 use std::path::Path;
 use std::sync::Arc;
-use std::{io, ptr};
 
-use log::{debug, error, info, trace};
+use log::{debug, error, info, warn};
 use tokio::fs::File;
 use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
 use tokio::net::TcpStream;
-use tokio::sync::Mutex;
+use tokio::sync::{watch, Mutex};
 
+use crate::bridge::{Control, Timeouts};
+use crate::config::BridgeConfig;
 use crate::listener::Listener;
-use crate::stream::{copy, SplitStream};
+use crate::metrics::AssuanSignTimer;
+use crate::protocol::{self, AssuanTracer};
+use crate::registry::BackendStatus;
+use crate::secret::SecretBuf;
+use crate::stream::{relay, Activity, SplitStream};
 use crate::util::report_data_err;
-use crate::{ping_gpg_agent, SocketType};
+use crate::{ping_gpg_agent, Error, SocketType};
 
 struct AgentMeta {
+    /// The backend given by configuration.
+    configured: Option<String>,
     path: Option<String>,
-    args: Option<(u16, [u8; 16])>,
+    args: Option<(u16, SecretBuf)>,
 }
 
-pub async fn bridge_to_stream<L>(mut listener: L, to_path: Option<String>) -> io::Result<()>
+impl AgentMeta {
+    fn status(&self) -> BackendStatus {
+        BackendStatus {
+            path: self.path.clone(),
+            port: self.args.as_ref().map(|(port, _)| *port),
+            nonce_loaded: self.args.is_some(),
+        }
+    }
+}
+
+/// Longest gnupg socket file accepted, they are around 50 bytes at most.
+const MAX_SOCKET_FILE_LEN: usize = 256;
+
+pub async fn bridge_to_stream<L>(
+    ty: SocketType,
+    mut listener: L,
+    config: watch::Receiver<Arc<BridgeConfig>>,
+    control: Control,
+) -> io::Result<()>
 where
     L: Listener,
     L::Connection: SplitStream + Send + 'static,
 {
-    info!("bridge to stream");
+    info!("bridge to {}", ty.name());
+    let backend = config.borrow().assuan_file();
     let meta = Arc::new(Mutex::new(AgentMeta {
-        path: to_path,
+        configured: backend.clone(),
+        path: backend,
         args: None,
     }));
     loop {
-        let conn = listener.accept().await?;
-        info!("connection accepted");
+        let (mut conn, peer) = tokio::select! {
+            res = listener.accept() => res?,
+            _ = control.stopped() => break,
+        };
 
+        // Configuration changes only apply to new connections.
+        let config = config.borrow().clone();
+        let timeouts = config.timeouts();
         let meta = meta.clone();
-        let (port, nounce) = {
-            let mut m = meta.lock().await;
-            if m.args.is_none() {
-                if m.path.is_none() {
-                    m.path = Some(SocketType::Extra.try_get_path().await?);
+        let authenticator = control.authenticator(&config);
+        let connection = control.register(peer);
+        let listener = control.listener().clone();
+        control.spawn(connection.clone(), async move {
+            if let Err(e) = authenticator.authenticate(&mut conn).await {
+                warn!("authentication failed: {}", e);
+                listener.record_failure(&e);
+                return;
+            }
+            // Only authenticated clients get to look up the agent. One that can't reach it is
+            // told why, the next one tries again.
+            let args = async {
+                let mut m = meta.lock().await;
+                let backend = config.assuan_file();
+                if m.configured != backend {
+                    m.configured = backend.clone();
+                    m.path = backend;
+                    m.args = None;
                 }
-                m.args = Some(load_port_nounce(m.path.as_ref().unwrap()).await?);
+                if m.args.is_none() {
+                    if m.path.is_none() {
+                        m.path = Some(ty.try_get_path().await?);
+                    }
+                    m.args = Some(load_port_nounce(m.path.as_ref().unwrap()).await?);
+                    listener.set_backend(m.status());
+                }
+                Ok::<_, io::Error>(m.args.clone().unwrap())
             }
-            m.args.unwrap()
-        };
-
-        tokio::spawn(async move {
-            if let Err(e) = delegate(conn, port, nounce).await {
+            .await;
+            let (port, nounce) = match args {
+                Ok(args) => args,
+                Err(e) => {
+                    error!("failed to load {}: {}", ty.name(), e);
+                    reply_error(&mut conn, &e).await;
+                    listener.record_failure(&e);
+                    return;
+                }
+            };
+            if let Err(e) = delegate(conn, port, nounce, timeouts, &connection.activity).await {
                 error!("failed to delegate stream: {:?}", e);
-                meta.lock().await.args.take();
+                listener.record_failure(&e);
+                let mut m = meta.lock().await;
+                m.args.take();
+                listener.set_backend(m.status());
             }
         });
     }
+    drop(listener);
+    control.drain().await;
+    Ok(())
 }
 
-async fn delegate(mut from: impl SplitStream, to_port: u16, nounce: [u8; 16]) -> io::Result<()> {
+pub(crate) async fn delegate(
+    mut from: impl SplitStream,
+    to_port: u16,
+    nounce: SecretBuf,
+    timeouts: Timeouts,
+    activity: &Activity,
+) -> io::Result<()> {
     let mut delegate = match TcpStream::connect(("127.0.0.1", to_port)).await {
         Ok(s) => s,
         Err(e) => {
             // It's possible that gpg-client was killed and leave stale meta untouched.
             // Reping agent to make it startup.
             let _ = ping_gpg_agent().await;
+            // Other errors, like running out of file descriptors, don't tell about the agent.
+            if e.kind() != io::ErrorKind::ConnectionRefused {
+                return Err(e);
+            }
+            let e = Error::AgentNotRunning(e).into();
+            reply_error(&mut from, &e).await;
             return Err(e);
         }
     };
-    trace!("--> {:?}", String::from_utf8_lossy(&nounce));
+    protocol::trace_nonce(&nounce);
     delegate.write_all(&nounce).await?;
     delegate.flush().await?;
 
-    let (mut source_read, mut source_write) = from.split_rw();
-    let (mut target_read, mut target_write) = delegate.split_rw();
-    let s2t = copy("-->", &mut source_read, &mut target_write);
-    let t2s = copy("<--", &mut target_read, &mut source_write);
-    let (received, replied) = tokio::join!(s2t, t2s);
+    let timer = AssuanSignTimer::default();
+    let tracer = AssuanTracer::default();
+    let inspect = |direction, data: &[u8]| {
+        timer.observe(direction, data);
+        tracer.observe(direction, data);
+    };
+    tokio::select! {
+        res = relay(&mut from, &mut delegate, activity, &inspect) => res?,
+        e = activity.expired(timeouts) => return Err(e),
+    }
     debug!(
         "connection finished, received {}, replied {}",
-        received?, replied?
+        activity.received(),
+        activity.replied()
     );
     Ok(())
 }
 
-fn load_cygwin_port_nounce(buffer: &[u8]) -> io::Result<(u16, [u8; 16])> {
+fn load_cygwin_port_nounce(buffer: &[u8]) -> io::Result<(u16, SecretBuf)> {
     // "%u %c %08x-%08x-%08x-%08x\x00"
     let find = |buffer: &[u8], start_pos: usize, delimeter| {
         if buffer.len() <= start_pos {
@@ -101,45 +184,128 @@ fn load_cygwin_port_nounce(buffer: &[u8]) -> io::Result<(u16, [u8; 16])> {
     let end_pos = find(buffer, 0, b' ')?;
     let port = parse(&buffer[..end_pos], 10)?;
 
-    if (1..=65535).contains(&port)
+    if !(1..=65535).contains(&port)
         || !buffer[end_pos..].starts_with(b" s ")
-        || buffer.len() < end_pos + 3 + 35 + 1
+        || buffer.len() < end_pos + 3 + 35
     {
         return Err(report_data_err("wrong data format"));
     }
 
     let mut start_pos = end_pos + 3;
-    let mut nounce = [0u32; 4];
-    for (pos, n) in nounce.iter_mut().enumerate() {
-        *n = parse(&buffer[start_pos..start_pos + 4], 16)?;
-        if pos < 3 {
-            if buffer[start_pos + 4] != b'-' {
-                return Err(report_data_err("wrong data format"));
-            }
-        } else if buffer[start_pos + 4] != b'x' {
+    let mut nounce = SecretBuf::new(16);
+    for (pos, n) in nounce.chunks_exact_mut(4).enumerate() {
+        // It's on purpose to ignore endianess.
+        n.copy_from_slice(&parse(&buffer[start_pos..start_pos + 8], 16)?.to_ne_bytes());
+        if pos < 3 && buffer[start_pos + 8] != b'-' {
             return Err(report_data_err("wrong data format"));
         }
-        start_pos += 5;
+        start_pos += 9;
     }
-    // It's on purpose to ignore endianess.
-    Ok((port as u16, unsafe { std::mem::transmute(nounce) }))
+    Ok((port as u16, nounce))
 }
 
-async fn load_port_nounce(path: &str) -> io::Result<(u16, [u8; 16])> {
+pub(crate) async fn load_port_nounce(path: &str) -> io::Result<(u16, SecretBuf)> {
     if !Path::new(&path).exists() {
         ping_gpg_agent().await?;
     }
+    let malformed = |reason: String| -> io::Error {
+        Error::SocketFileMalformed {
+            path: path.to_owned(),
+            reason,
+        }
+        .into()
+    };
     let mut f = File::open(&path.replace('\\', "/")).await?;
-    let mut buffer = Vec::with_capacity(50);
-    f.read_to_end(&mut buffer).await?;
+    // Socket files are tiny, reading into a fixed buffer avoids leaving copies of the nonce
+    // behind when growing.
+    let mut buffer = SecretBuf::new(MAX_SOCKET_FILE_LEN);
+    let mut len = 0;
+    loop {
+        if len == buffer.len() {
+            return Err(malformed("too large".to_owned()));
+        }
+        match f.read(&mut buffer[len..]).await? {
+            0 => break,
+            n => len += n,
+        }
+    }
+    let buffer = &buffer[..len];
     if buffer.starts_with(b"!<socket >") {
-        return load_cygwin_port_nounce(&buffer[10..]);
+        return load_cygwin_port_nounce(&buffer[10..]).map_err(|e| malformed(e.to_string()));
+    }
+    if buffer.len() < 16 {
+        return Err(malformed("too short".to_owned()));
     }
     let (left, right) = buffer.split_at(buffer.len() - 16);
-    let to_port: u16 = std::str::from_utf8(left).unwrap().trim().parse().unwrap();
-    let mut nounce = [0; 16];
-    unsafe {
-        ptr::copy_nonoverlapping(right.as_ptr(), nounce.as_mut_ptr(), 16);
+    let to_port: u16 = std::str::from_utf8(left)
+        .ok()
+        .and_then(|p| p.trim().parse().ok())
+        .ok_or_else(|| malformed("no port".to_owned()))?;
+    Ok((to_port, SecretBuf::from_slice(right)))
+}
+
+/// Tells an Assuan client why its connection is about to be closed, if it's a known failure.
+pub(crate) async fn reply_error(conn: &mut impl SplitStream, e: &io::Error) {
+    if let Some(e) = Error::find(e) {
+        let (_, mut write) = conn.split_rw();
+        let _ = write.write_all(e.assuan_reply().as_bytes()).await;
+        let _ = write.flush().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn load(name: &str, content: &[u8]) -> io::Result<(u16, SecretBuf)> {
+        let path =
+            std::env::temp_dir().join(format!("gpg-bridge-socket-{}-{}", std::process::id(), name));
+        std::fs::write(&path, content).unwrap();
+        let res = load_port_nounce(path.to_str().unwrap()).await;
+        std::fs::remove_file(path).unwrap();
+        res
+    }
+
+    #[tokio::test]
+    async fn test_load_port_nounce() {
+        let nonce: Vec<u8> = (1..=16).collect();
+        let (port, loaded) = load("plain", &[b"4321\n", nonce.as_slice()].concat())
+            .await
+            .unwrap();
+        assert_eq!((port, &*loaded), (4321, nonce.as_slice()));
+
+        let words = [0x01020304u32, 0x05060708, 0x090a0b0c, 0x0d0e0f10];
+        let cygwin: Vec<u8> = words.iter().flat_map(|w| w.to_ne_bytes()).collect();
+        for content in [
+            &b"!<socket >12345 s 01020304-05060708-090a0b0c-0d0e0f10\0"[..],
+            b"!<socket >12345 s 01020304-05060708-090a0b0c-0d0e0f10",
+        ] {
+            let (port, loaded) = load("cygwin", content).await.unwrap();
+            assert_eq!((port, &*loaded), (12345, cygwin.as_slice()));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_malformed_socket_file() {
+        let cases: &[&[u8]] = &[
+            b"!<socket >0 s 01020304-05060708-090a0b0c-0d0e0f10\0",
+            b"!<socket >65536 s 01020304-05060708-090a0b0c-0d0e0f10\0",
+            b"!<socket >12345 S 01020304-05060708-090a0b0c-0d0e0f10\0",
+            b"!<socket >12345 s 01020304-05060708-090a0b0c-0d0e0f1",
+            b"!<socket >12345 s 01020304 05060708-090a0b0c-0d0e0f10\0",
+            b"!<socket >12345 s 0102030g-05060708-090a0b0c-0d0e0f10\0",
+            b"!<socket >",
+            b"4321\n0123",
+            b"port\n0123456789abcdef",
+        ];
+        for content in cases {
+            let e = load("malformed", content).await.unwrap_err();
+            assert!(
+                matches!(Error::find(&e), Some(Error::SocketFileMalformed { .. })),
+                "{:?}: {}",
+                String::from_utf8_lossy(content),
+                e
+            );
+        }
     }
-    Ok((to_port, nounce))
 }
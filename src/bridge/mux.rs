@@ -0,0 +1,91 @@
+use std::collections::HashMap;
+use std::io;
+use std::sync::Arc;
+
+use log::{info, warn};
+use tokio::sync::{mpsc, watch};
+use tokio_util::sync::CancellationToken;
+
+use crate::bridge::Control;
+use crate::config::BridgeConfig;
+use crate::listener::channel::ChannelListener;
+use crate::listener::Listener;
+use crate::mux::{self, Transport};
+
+/// Serves connections that multiplex channels of several socket types.
+///
+/// Channels of each type in `channels` are bridged as if they were accepted by a dedicated
+/// listener of that type, others are refused.
+pub async fn bridge_mux<L>(
+    listener: L,
+    config: watch::Receiver<Arc<BridgeConfig>>,
+    control: Control,
+) -> io::Result<()>
+where
+    L: Listener,
+    L::Connection: Transport,
+{
+    let (name, types) = {
+        let config = config.borrow();
+        (config.name().to_owned(), config.channels.clone())
+    };
+    let list: Vec<_> = types.iter().map(|t| t.to_string()).collect();
+    info!("bridge {} over mux", list.join(", "));
+    let mut senders = HashMap::new();
+    let mut bridges = vec![];
+    for ty in types {
+        let (tx, rx) = mpsc::unbounded_channel();
+        senders.insert(ty, tx);
+        bridges.push(crate::bridge_socket(
+            ty,
+            ChannelListener::new(rx),
+            config.clone(),
+            control.for_channels(),
+        ));
+    }
+    let senders = Arc::new(senders);
+    // Sessions are kept until all their channels are finished.
+    let sessions = CancellationToken::new();
+    let accept = async {
+        let mut listener = listener;
+        loop {
+            let (conn, peer) = tokio::select! {
+                res = listener.accept() => res?,
+                _ = control.stopped() => break,
+            };
+            let senders = senders.clone();
+            let sessions = sessions.clone();
+            let name = name.clone();
+            tokio::spawn(async move {
+                let from = peer.clone();
+                let acceptor: mux::Acceptor = Box::new(move |ty, channel| match senders.get(&ty) {
+                    Some(tx) => tx.send((channel, from.clone())).is_ok(),
+                    None => {
+                        warn!("{} refused a {} channel from {}", name, ty, from);
+                        false
+                    }
+                });
+                let session = match mux::accept(conn, acceptor).await {
+                    Ok(session) => session,
+                    Err(e) => {
+                        warn!("dropped mux connection from {}: {}", peer, e);
+                        return;
+                    }
+                };
+                tokio::select! {
+                    _ = session.closed() => {}
+                    _ = sessions.cancelled() => session.close(),
+                }
+            });
+        }
+        drop(listener);
+        Ok::<_, io::Error>(())
+    };
+    let res = tokio::try_join!(accept, futures::future::try_join_all(bridges));
+    // Clients reconnect once their session is closed, to the restarted listener on failure.
+    sessions.cancel();
+    // A failed listener is restarted while accepted connections keep being served.
+    res?;
+    control.drain().await;
+    Ok(())
+}
@@ -0,0 +1,339 @@
+use std::collections::VecDeque;
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures::FutureExt as _;
+use log::{debug, error, info, warn};
+use parking_lot::Mutex;
+use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+use tokio::net::TcpListener;
+use tokio::sync::{watch, Notify, Semaphore};
+
+use crate::auth::Authenticator;
+use crate::bridge::upstream::delegate;
+use crate::bridge::Control;
+use crate::config::BridgeConfig;
+use crate::listener::dial::{ACTIVATE, PARK_LIFETIME};
+use crate::listener::retry::Backoff;
+use crate::listener::Listener;
+use crate::mux::Transport;
+use crate::stream::SplitStream;
+use crate::SocketType;
+
+/// Clients wait this long for a bridge to dial in when none is parked.
+const PARKED_WAIT: Duration = Duration::from_secs(10);
+/// Connections kept parked at once, further ones are dropped until some are taken.
+const MAX_PARKED: usize = 64;
+/// Dialers authenticating at once, further ones are dropped right away.
+const MAX_PENDING: usize = 64;
+/// Dialers that don't finish the TLS handshake in time are dropped.
+#[cfg(feature = "tls")]
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Forwards every connection to a bridge that has dialed in to `rendezvous`, the reverse of
+/// forwarding to an upstream.
+pub async fn bridge_to_rendezvous<L>(
+    ty: SocketType,
+    mut listener: L,
+    config: watch::Receiver<Arc<BridgeConfig>>,
+    control: Control,
+) -> io::Result<()>
+where
+    L: Listener,
+    L::Connection: SplitStream + Send + 'static,
+{
+    let (addr, token_file, tls) = {
+        let config = config.borrow();
+        (
+            config.rendezvous.clone().unwrap_or_default(),
+            config.rendezvous_token_file.clone(),
+            config.rendezvous_tls.clone(),
+        )
+    };
+    #[cfg(feature = "tls")]
+    let acceptor = tls.as_ref().map(crate::tls::acceptor).transpose()?;
+    #[cfg(not(feature = "tls"))]
+    if tls.is_some() {
+        return Err(crate::util::other_error(
+            "gpg-bridge is built without the tls feature".to_owned(),
+        ));
+    }
+    let rendezvous = TcpListener::bind(&addr).await?;
+    info!("bridge {} to dialers on {}", ty, addr);
+    let parked = Arc::new(Parked::default());
+    let pending = Arc::new(Semaphore::new(MAX_PENDING));
+    let authenticator = Authenticator::with_token_file(token_file);
+    let park = async {
+        let mut backoff = Backoff::new();
+        loop {
+            let (conn, peer) = match rendezvous.accept().await {
+                Ok(res) => {
+                    backoff.reset();
+                    res
+                }
+                Err(e) => {
+                    backoff.retry(e).await?;
+                    continue;
+                }
+            };
+            let Ok(permit) = pending.clone().try_acquire_owned() else {
+                warn!("dropped dialer {}, too many are authenticating", peer);
+                continue;
+            };
+            let authenticator = authenticator.clone();
+            let parked = parked.clone();
+            #[cfg(feature = "tls")]
+            let acceptor = acceptor.clone();
+            tokio::spawn(async move {
+                let _permit = permit;
+                let res = async {
+                    #[cfg(feature = "tls")]
+                    let mut conn: Box<dyn Transport> = match acceptor {
+                        Some(acceptor) => {
+                            match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(conn))
+                                .await
+                            {
+                                Ok(res) => Box::new(res?),
+                                Err(_) => {
+                                    return Err(io::Error::new(
+                                        io::ErrorKind::TimedOut,
+                                        "TLS handshake timed out",
+                                    ))
+                                }
+                            }
+                        }
+                        None => Box::new(conn),
+                    };
+                    #[cfg(not(feature = "tls"))]
+                    let mut conn: Box<dyn Transport> = Box::new(conn);
+                    authenticator.authenticate(&mut conn).await?;
+                    Ok::<_, io::Error>(conn)
+                };
+                match res.await {
+                    Ok(conn) => match parked.push(conn) {
+                        true => debug!("{} parked a connection", peer),
+                        false => warn!("dropped dialer {}, too many connections are parked", peer),
+                    },
+                    Err(e) => warn!("dialer {} failed to authenticate: {}", peer, e),
+                }
+            });
+        }
+    };
+    let serve = async {
+        loop {
+            let (mut conn, peer) = tokio::select! {
+                res = listener.accept() => res?,
+                _ = control.stopped() => break,
+            };
+
+            // Configuration changes only apply to new connections.
+            let config = config.borrow().clone();
+            let timeouts = config.timeouts();
+            let client_authenticator = control.authenticator(&config);
+            let parked = parked.clone();
+            let connection = control.register(peer);
+            let listener = control.listener().clone();
+            control.spawn(connection.clone(), async move {
+                // Parked connections are only handed to authenticated clients.
+                if let Err(e) = client_authenticator.authenticate(&mut conn).await {
+                    warn!("authentication failed: {}", e);
+                    listener.record_failure(&e);
+                    return;
+                }
+                let res = async {
+                    let dialer = parked.take().await?;
+                    delegate(ty, conn, dialer, timeouts, &connection.activity).await
+                };
+                if let Err(e) = res.await {
+                    error!("failed to forward to a dialer: {:?}", e);
+                    listener.record_failure(&e);
+                }
+            });
+        }
+        Ok::<_, io::Error>(())
+    };
+    // A failed listener is restarted while accepted connections keep being served.
+    let res = tokio::select! {
+        res = park => res,
+        res = serve => res,
+    };
+    res?;
+    drop(listener);
+    drop(rendezvous);
+    control.drain().await;
+    Ok(())
+}
+
+/// Connections dialers have parked, oldest first, with the time they were parked.
+#[derive(Default)]
+struct Parked {
+    queue: Mutex<VecDeque<(Instant, Box<dyn Transport>)>>,
+    added: Notify,
+}
+
+impl Parked {
+    /// Adds a connection, returns false if too many are parked already.
+    fn push(&self, conn: Box<dyn Transport>) -> bool {
+        let mut queue = self.queue.lock();
+        // Dialers replace parked connections from time to time, which leaves the old ones here.
+        queue.retain_mut(|(parked, conn)| !is_expired(*parked) && !is_closed(conn));
+        if queue.len() >= MAX_PARKED {
+            return false;
+        }
+        queue.push_back((Instant::now(), conn));
+        self.added.notify_one();
+        true
+    }
+
+    /// Activates the oldest parked connection, skipping those that are already closed or about
+    /// to be replaced by their dialer.
+    ///
+    /// Waits for a dialer for up to [`PARKED_WAIT`] when none is parked.
+    async fn take(&self) -> io::Result<Box<dyn Transport>> {
+        let wait = async {
+            loop {
+                let conn = {
+                    let mut queue = self.queue.lock();
+                    let conn = queue.pop_front();
+                    // Another client may wait for the rest, `added` only wakes one at a time.
+                    if !queue.is_empty() {
+                        self.added.notify_one();
+                    }
+                    conn
+                };
+                let Some((parked, mut conn)) = conn else {
+                    self.added.notified().await;
+                    continue;
+                };
+                if is_expired(parked) || is_closed(&mut conn) {
+                    continue;
+                }
+                // Flushed, since a TLS stream may buffer it.
+                if conn.write_all(&[ACTIVATE]).await.is_ok() && conn.flush().await.is_ok() {
+                    return conn;
+                }
+            }
+        };
+        tokio::time::timeout(PARKED_WAIT, wait)
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::NotConnected, "no bridge has dialed in"))
+    }
+}
+
+/// Whether a connection parked at `parked` may be replaced by its dialer any time.
+fn is_expired(parked: Instant) -> bool {
+    parked.elapsed() >= PARK_LIFETIME
+}
+
+/// Whether the dialer has closed a parked connection, which leaves it readable.
+fn is_closed(conn: &mut Box<dyn Transport>) -> bool {
+    conn.read(&mut [0]).now_or_never().is_some()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use tokio::net::TcpStream;
+
+    use super::*;
+    use crate::registry::Registry;
+
+    fn token_file(name: &str, token: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "gpg-bridge-rendezvous-{}-{}.token",
+            std::process::id(),
+            name
+        ));
+        std::fs::write(&path, token).unwrap();
+        path
+    }
+
+    fn free_addr() -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().to_string()
+    }
+
+    async fn connect(addr: &str, token: &[u8]) -> TcpStream {
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(5);
+        let mut conn = loop {
+            match TcpStream::connect(addr).await {
+                Ok(conn) => break conn,
+                Err(_) if tokio::time::Instant::now() < deadline => {
+                    tokio::time::sleep(Duration::from_millis(20)).await
+                }
+                Err(e) => panic!("failed to connect to {}: {}", addr, e),
+            }
+        };
+        conn.write_all(token).await.unwrap();
+        conn
+    }
+
+    #[tokio::test]
+    async fn test_take_parked() {
+        let parked = Parked::default();
+        let (expired, mut expired_dialer) = tokio::io::duplex(64);
+        let (closed, closed_dialer) = tokio::io::duplex(64);
+        let (live, mut live_dialer) = tokio::io::duplex(64);
+        parked
+            .queue
+            .lock()
+            .push_back((Instant::now() - PARK_LIFETIME, Box::new(expired)));
+        assert!(parked.push(Box::new(closed)));
+        assert!(parked.push(Box::new(live)));
+        drop(closed_dialer);
+
+        let mut conn = parked.take().await.unwrap();
+        assert_eq!(live_dialer.read_u8().await.unwrap(), ACTIVATE);
+        conn.write_all(b"x").await.unwrap();
+        assert_eq!(live_dialer.read_u8().await.unwrap(), b'x');
+        // The expired connection is dropped without being activated.
+        assert_eq!(expired_dialer.read(&mut [0]).await.unwrap(), 0);
+        assert!(parked.queue.lock().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_refuse_unauthenticated_client() {
+        let listen = free_addr();
+        let mut config = BridgeConfig::new(SocketType::Ssh, listen.clone());
+        config.rendezvous = Some(free_addr());
+        let tokens = [
+            token_file("dialer", "dialer-secret"),
+            token_file("client", "client-secret"),
+        ];
+        config.rendezvous_token_file = Some(tokens[0].clone());
+        config.token_file = Some(tokens[1].clone());
+        let rendezvous = config.rendezvous.clone().unwrap();
+        let registry = Arc::new(Registry::new());
+        let control = Control::new(registry.clone(), registry.add_listener(&config));
+        let (_config, rx) = watch::channel(Arc::new(config));
+        let bridge = tokio::spawn(crate::serve(rx, control.clone()));
+
+        let mut dialer = connect(&rendezvous, b"dialer-secret\n").await;
+        // Gives the rendezvous time to park the dialer.
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let mut client = connect(&listen, b"wrong\nhello").await;
+        let mut buf = [0; 16];
+        assert!(matches!(client.read(&mut buf).await, Ok(0) | Err(_)));
+        let activated = tokio::time::timeout(Duration::from_millis(300), dialer.read_u8()).await;
+        assert!(activated.is_err(), "parked connection handed to the client");
+
+        let mut client = connect(&listen, b"client-secret\nhello").await;
+        assert_eq!(dialer.read_u8().await.unwrap(), ACTIVATE);
+        let mut hello = [0; 5];
+        dialer.read_exact(&mut hello).await.unwrap();
+        assert_eq!(&hello, b"hello");
+        dialer.write_all(b"world").await.unwrap();
+        client.read_exact(&mut hello).await.unwrap();
+        assert_eq!(&hello, b"world");
+
+        drop((client, dialer));
+        control.stop();
+        bridge.await.unwrap().unwrap();
+        for path in tokens {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+}
@@ -1,13 +1,13 @@
 use std::ffi::c_void;
 use std::io::{self, Error};
 use std::pin::Pin;
-use std::ptr;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::Instant;
 
-use log::{debug, error, trace};
+use log::{debug, error, warn};
 use tokio::io::{AsyncRead, AsyncReadExt as _, AsyncWriteExt as _};
-use tokio::sync::{Semaphore, SemaphorePermit};
+use tokio::sync::{watch, Semaphore, SemaphorePermit};
 use windows::core::HSTRING;
 use windows::Win32::Foundation::{
     CloseHandle, HANDLE, HWND, INVALID_HANDLE_VALUE, LPARAM, LRESULT, WPARAM,
@@ -19,49 +19,102 @@ use windows::Win32::System::Memory::{
 };
 use windows::Win32::UI::WindowsAndMessaging::{FindWindowW, SendMessageW, WM_COPYDATA};
 
+use crate::bridge::{Control, Timeouts};
+use crate::config::BridgeConfig;
 use crate::listener::Listener;
-use crate::ping_gpg_agent;
-use crate::stream::SplitStream;
+use crate::secret::{self, SecretBuf};
+use crate::stream::{Activity, Direction, SplitStream};
 use crate::util::other_error;
+use crate::{metrics, ping_gpg_agent, protocol};
 
 // For now, forwarding ssh agent requests can only be done using IPC messages. gpg
 // ssh agent seems to do security trick on tcp stream and fail to receive anything.
-async fn delegate_ssh(mut from: impl SplitStream) -> io::Result<()> {
+pub(crate) async fn delegate_ssh(
+    mut from: impl SplitStream,
+    timeouts: Timeouts,
+    activity: &Activity,
+) -> io::Result<()> {
     let (mut source_read, mut source_write) = from.split_rw();
-    let mut handler = Handler::new().await?;
-    while let Some(resp) = handler.process_one(&mut source_read).await? {
-        trace!("get {:?}", String::from_utf8_lossy(resp));
-        source_write.write_all(resp).await?;
+    let serve = async {
+        // Waiting for a free Pageant slot counts towards the limits as well, dropping the
+        // handler on timeout releases the slot.
+        let mut handler = Handler::new().await?;
+        let mut received = 0;
+        loop {
+            let resp = match handler.process_one(&mut source_read).await {
+                Ok(Some(resp)) => resp,
+                Ok(None) => break,
+                Err(e) => {
+                    if let Some(typed) = crate::Error::find(&e) {
+                        let _ = source_write.write_all(typed.ssh_reply()).await;
+                    }
+                    return Err(e);
+                }
+            };
+            protocol::trace_ssh(Direction::Reply, &resp[4..]);
+            source_write.write_all(resp).await?;
+            activity.record(Direction::Reply, resp.len());
+            activity.record(Direction::Request, handler.received() - received);
+            received = handler.received();
+        }
+        Ok::<_, io::Error>(())
+    };
+    tokio::select! {
+        res = serve => res?,
+        e = activity.expired(timeouts) => return Err(e),
     }
     debug!(
         "connection finished, received {}, replied {}",
-        handler.received(),
-        handler.replied()
+        activity.received(),
+        activity.replied()
     );
     Ok(())
 }
 
-pub async fn bridge_to_message<L>(mut listener: L) -> io::Result<()>
+pub async fn bridge_to_message<L>(
+    mut listener: L,
+    config: watch::Receiver<Arc<BridgeConfig>>,
+    control: Control,
+) -> io::Result<()>
 where
     L: Listener,
     L::Connection: SplitStream + Send + 'static,
 {
     let reload = Arc::new(AtomicBool::new(false));
     loop {
-        let conn = listener.accept().await?;
+        let (mut conn, peer) = tokio::select! {
+            res = listener.accept() => res?,
+            _ = control.stopped() => break,
+        };
+        // Configuration changes only apply to new connections.
+        let (timeouts, authenticator) = {
+            let config = config.borrow();
+            (config.timeouts(), control.authenticator(&config))
+        };
 
-        if reload.load(Ordering::SeqCst) {
-            ping_gpg_agent().await?;
-            reload.store(false, Ordering::SeqCst);
-        }
         let reload = reload.clone();
-        tokio::spawn(async move {
-            if let Err(e) = delegate_ssh(conn).await {
+        let connection = control.register(peer);
+        control.spawn(connection.clone(), async move {
+            if let Err(e) = authenticator.authenticate(&mut conn).await {
+                warn!("authentication failed: {}", e);
+                connection.listener.record_failure(&e);
+                return;
+            }
+            if reload.swap(false, Ordering::SeqCst) {
+                if let Err(e) = ping_gpg_agent().await {
+                    warn!("failed to start gpg-agent: {}", e);
+                }
+            }
+            if let Err(e) = delegate_ssh(conn, timeouts, &connection.activity).await {
                 error!("failed to delegate message: {:?}", e);
+                connection.listener.record_failure(&e);
                 reload.store(true, Ordering::SeqCst);
             }
         });
     }
+    drop(listener);
+    control.drain().await;
+    Ok(())
 }
 
 /// A magic value used with WM_COPYDATA.
@@ -74,6 +127,8 @@ static PAGEANT_WINDOW_NAME: &str = "Pageant";
 /// for the foreseeable future.  */
 pub const PUTTY_IPC_MAXLEN: usize = 16384;
 
+const SSH_AGENTC_SIGN_REQUEST: u8 = 13;
+
 static CONCURRENCY: Semaphore = Semaphore::const_new(4);
 static TOKEN: parking_lot::Mutex<u8> = parking_lot::const_mutex(0);
 
@@ -95,13 +150,34 @@ fn release_token(mask: u8) {
     *token &= !mask;
 }
 
+/// A Pageant slot, counted in metrics while it's held.
+struct Slot(#[allow(dead_code)] SemaphorePermit<'static>);
+
+impl Slot {
+    async fn acquire() -> Slot {
+        metrics::SSH_SLOT_QUEUE.fetch_add(1, Ordering::Relaxed);
+        let permit = CONCURRENCY.acquire().await;
+        metrics::SSH_SLOT_QUEUE.fetch_sub(1, Ordering::Relaxed);
+        metrics::SSH_SLOTS_IN_USE.fetch_add(1, Ordering::Relaxed);
+        Slot(permit.unwrap())
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        metrics::SSH_SLOTS_IN_USE.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
 pub struct Handler {
     handle: HANDLE,
     view: *mut u8,
     limit: usize,
     mask: u8,
     name: String,
-    _permit: SemaphorePermit<'static>,
+    _slot: Slot,
+    /// The last response, copied out so the shared mapping can be wiped right away.
+    response: SecretBuf,
     received: usize,
     replied: usize,
 }
@@ -110,7 +186,7 @@ unsafe impl Send for Handler {}
 
 impl Handler {
     pub async fn new() -> io::Result<Self> {
-        let permit = CONCURRENCY.acquire().await.unwrap();
+        let slot = Slot::acquire().await;
         let mask = find_available_token();
         let name = format!("{}-{}\0", FILE_MAP_NAME, mask);
         let handle = unsafe {
@@ -149,7 +225,8 @@ impl Handler {
             limit: PUTTY_IPC_MAXLEN,
             mask,
             name,
-            _permit: permit,
+            _slot: slot,
+            response: SecretBuf::new(PUTTY_IPC_MAXLEN),
             received: 0,
             replied: 0,
         })
@@ -159,6 +236,7 @@ impl Handler {
         &mut self,
         reader: &mut Pin<Box<dyn AsyncRead + Send + '_>>,
     ) -> io::Result<Option<&[u8]>> {
+        secret::wipe(&mut self.response);
         let len_bytes = unsafe { std::slice::from_raw_parts_mut(self.view, 4) };
         if let Err(e) = reader.read_exact(len_bytes).await {
             if e.kind() == io::ErrorKind::UnexpectedEof {
@@ -168,15 +246,17 @@ impl Handler {
         }
         let len = u32::from_be(unsafe { (self.view as *mut u32).read_unaligned() }) as usize + 4;
         if len > self.limit {
-            return Err(other_error(format!(
-                "message too large: {} >= {}",
-                len, self.limit
-            )));
+            return Err(crate::Error::OversizedFrame {
+                len,
+                max: self.limit,
+            }
+            .into());
         }
         self.received += len;
         let req = unsafe { std::slice::from_raw_parts_mut(self.view.add(4), len - 4) };
         reader.read_exact(req).await?;
-        trace!("recv request: {:?}", String::from_utf8_lossy(req));
+        protocol::trace_ssh(Direction::Request, req);
+        let sign = req.first() == Some(&SSH_AGENTC_SIGN_REQUEST);
         let win = unsafe {
             FindWindowW(
                 &HSTRING::from(PAGEANT_WINDOW_NAME),
@@ -184,16 +264,14 @@ impl Handler {
             )
         };
         if win == HWND(0) {
-            return Err(other_error(format!(
-                "can't contact gpg agent: {}",
-                Error::last_os_error()
-            )));
+            return Err(crate::Error::PageantNotFound(Error::last_os_error()).into());
         }
         let copy_data = COPYDATASTRUCT {
             dwData: PUTTY_IPC_MAGIC,
             cbData: self.name.len() as u32,
             lpData: self.name.as_mut_ptr() as *mut c_void,
         };
+        let started = Instant::now();
         let res = unsafe {
             SendMessageW(
                 win,
@@ -208,17 +286,25 @@ impl Handler {
                 Error::last_os_error()
             )));
         }
+        if sign {
+            metrics::SSH_SIGN_LATENCY.observe(started.elapsed());
+        }
+        let request_len = len;
         let len = u32::from_be(unsafe { (self.view as *mut u32).read_unaligned() }) as usize + 4;
+        let mapping = unsafe { std::slice::from_raw_parts_mut(self.view, self.limit) };
         if len > self.limit {
+            secret::wipe(mapping);
             return Err(other_error(format!(
                 "response too large: {} > {}",
                 len + 4,
                 self.limit
             )));
         };
+        self.response[..len].copy_from_slice(&mapping[..len]);
+        secret::wipe(&mut mapping[..len.max(request_len)]);
 
         self.replied += len;
-        unsafe { Ok(Some(std::slice::from_raw_parts(self.view, len))) }
+        Ok(Some(&self.response[..len]))
     }
 
     pub fn received(&self) -> usize {
@@ -233,8 +319,8 @@ impl Handler {
 impl Drop for Handler {
     fn drop(&mut self) {
         unsafe {
-            ptr::write_bytes(self.view, 0, self.limit);
             if !self.view.is_null() {
+                secret::wipe(std::slice::from_raw_parts_mut(self.view, self.limit));
                 UnmapViewOfFile(MEMORYMAPPEDVIEW_HANDLE(self.view as isize));
             }
             CloseHandle(self.handle);
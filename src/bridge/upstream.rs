@@ -0,0 +1,217 @@
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use log::{debug, error, info, warn};
+use tokio::net::TcpStream;
+use tokio::sync::watch;
+
+use crate::bridge::{Control, Timeouts};
+use crate::config::BridgeConfig;
+#[cfg(feature = "tls")]
+use crate::config::TlsConnectConfig;
+use crate::listener::Listener;
+use crate::mux::{self, Transport};
+use crate::protocol::AssuanTracer;
+use crate::stream::{relay, Activity, Direction, SplitStream};
+use crate::{auth, SocketType};
+
+/// How to reach another gpg-bridge, `upstream` or a `tcp://` backend.
+pub(crate) struct Upstream {
+    pub addr: String,
+    /// File of the token to send once connected.
+    pub token_file: Option<PathBuf>,
+    #[cfg(feature = "tls")]
+    pub tls: Option<tokio_rustls::TlsConnector>,
+}
+
+impl Upstream {
+    /// Reads how to reach `addr` from `upstream_token_file` and `upstream_tls` of `config`.
+    pub fn new(addr: String, config: &BridgeConfig) -> io::Result<Upstream> {
+        Ok(Upstream {
+            addr,
+            token_file: config.upstream_token_file.clone(),
+            #[cfg(feature = "tls")]
+            tls: config
+                .upstream_tls
+                .as_ref()
+                .map(crate::tls::connector)
+                .transpose()?,
+        })
+    }
+
+    /// Opens a stream to the upstream without sending the token, which a mux session sends on
+    /// each channel instead.
+    async fn transport(&self) -> io::Result<Box<dyn Transport>> {
+        let stream = TcpStream::connect(&self.addr).await?;
+        #[cfg(feature = "tls")]
+        if let Some(connector) = &self.tls {
+            return Ok(Box::new(crate::tls::connect(connector, stream).await?));
+        }
+        Ok(Box::new(stream))
+    }
+
+    /// Opens a stream to the upstream and sends the token.
+    pub async fn connect(&self) -> io::Result<Box<dyn Transport>> {
+        let mut stream = self.transport().await?;
+        if let Some(path) = &self.token_file {
+            auth::send(&mut stream, path).await?;
+        }
+        Ok(stream)
+    }
+}
+
+/// Forwards every connection to another gpg-bridge at `upstream` or a `tcp://` backend, which
+/// talks to the agent.
+///
+/// The upstream is expected to serve the same socket type, so the stream is relayed as is. With
+/// `upstream_mux`, it's relayed over a channel of that type instead.
+pub async fn bridge_to_upstream<L>(
+    ty: SocketType,
+    mut listener: L,
+    config: watch::Receiver<Arc<BridgeConfig>>,
+    control: Control,
+) -> io::Result<()>
+where
+    L: Listener,
+    L::Connection: SplitStream + Send + 'static,
+{
+    info!("bridge {} to upstream", ty);
+    #[cfg(feature = "tls")]
+    let mut connector: Option<(TlsConnectConfig, tokio_rustls::TlsConnector)> = None;
+    // The mux session to the current upstream, connections keep it open until they finish.
+    let mut session: Option<mux::UpstreamLease> = None;
+    loop {
+        let (mut conn, peer) = tokio::select! {
+            res = listener.accept() => res?,
+            _ = control.stopped() => break,
+        };
+
+        // Configuration changes only apply to new connections.
+        let config = config.borrow().clone();
+        let timeouts = config.timeouts();
+        let Some(upstream) = config.upstream_addr() else {
+            error!("bridge {} has no upstream", config.name());
+            continue;
+        };
+        #[cfg(feature = "tls")]
+        let tls = match &config.upstream_tls {
+            Some(tls) => {
+                let cached = connector.as_ref().filter(|(c, _)| c == tls);
+                match cached {
+                    Some((_, c)) => Some(c.clone()),
+                    None => match crate::tls::connector(tls) {
+                        Ok(c) => {
+                            connector = Some((tls.clone(), c.clone()));
+                            Some(c)
+                        }
+                        Err(e) => {
+                            error!("failed to load TLS settings of upstream: {}", e);
+                            control.listener().record_failure(&e);
+                            continue;
+                        }
+                    },
+                }
+            }
+            None => None,
+        };
+
+        let authenticator = control.authenticator(&config);
+        let upstream = Upstream {
+            addr: upstream,
+            token_file: config.upstream_token_file.clone(),
+            #[cfg(feature = "tls")]
+            tls,
+        };
+        if !config.upstream_mux {
+            session = None;
+        } else if session
+            .as_ref()
+            .is_none_or(|s| s.upstream() != upstream.addr)
+        {
+            session = Some(control.upstream_session(&upstream.addr));
+        }
+        let session = session.clone();
+        let connection = control.register(peer);
+        let listener = control.listener().clone();
+        control.spawn(connection.clone(), async move {
+            if let Err(e) = authenticator.authenticate(&mut conn).await {
+                warn!("authentication failed: {}", e);
+                listener.record_failure(&e);
+                return;
+            }
+            let res = async {
+                if let Some(session) = session {
+                    let mut stream = session.open(ty, || upstream.transport()).await?;
+                    if let Some(path) = &upstream.token_file {
+                        auth::send(&mut stream, path).await?;
+                    }
+                    return delegate(ty, conn, stream, timeouts, &connection.activity).await;
+                }
+                let stream = upstream.connect().await?;
+                delegate(ty, conn, stream, timeouts, &connection.activity).await
+            };
+            if let Err(e) = res.await {
+                error!("failed to forward to upstream {}: {:?}", upstream.addr, e);
+                listener.record_failure(&e);
+            }
+        });
+    }
+    drop(listener);
+    drop(session);
+    control.drain().await;
+    Ok(())
+}
+
+pub(crate) async fn delegate(
+    ty: SocketType,
+    mut from: impl SplitStream,
+    mut to: impl SplitStream,
+    timeouts: Timeouts,
+    activity: &Activity,
+) -> io::Result<()> {
+    let tracer = AssuanTracer::default();
+    let inspect = |direction: Direction, data: &[u8]| {
+        if ty != SocketType::Ssh {
+            tracer.observe(direction, data);
+        }
+    };
+    tokio::select! {
+        res = relay(&mut from, &mut to, activity, &inspect) => res?,
+        e = activity.expired(timeouts) => return Err(e),
+    }
+    debug!(
+        "connection finished, received {}, replied {}",
+        activity.received(),
+        activity.replied()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_connect_sends_token() {
+        let path =
+            std::env::temp_dir().join(format!("gpg-bridge-upstream-{}.token", std::process::id()));
+        std::fs::write(&path, "secret\n").unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut config = BridgeConfig::new(SocketType::Ssh, "stdio:".to_owned());
+        config.backend = Some(format!("tcp://{}", listener.local_addr().unwrap()));
+        config.upstream_token_file = Some(path.clone());
+        let upstream = Upstream::new(config.upstream_addr().unwrap(), &config).unwrap();
+
+        let (mut stream, (mut accepted, _)) =
+            tokio::try_join!(upstream.connect(), listener.accept()).unwrap();
+        stream.write_all(b"hello").await.unwrap();
+        let mut line = [0; 12];
+        accepted.read_exact(&mut line).await.unwrap();
+        assert_eq!(&line, b"secret\nhello");
+        std::fs::remove_file(path).unwrap();
+    }
+}
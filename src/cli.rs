@@ -1,19 +1,33 @@
-use clap::Parser;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use clap::{Parser, Subcommand};
+use gpg_bridge::config::LogFormat;
+use gpg_bridge::SocketType;
 
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
+#[command(
+    author,
+    version,
+    about,
+    long_about = None,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
 pub struct Args {
+    #[command(subcommand)]
+    pub command: Option<Command>,
     #[arg(
         long,
         value_name = "ADDRESS",
-        required_unless_present = "extra",
+        required_unless_present_any = ["extra", "config"],
         help = "Sets the listenning address to bridge the ssh socket"
     )]
     pub ssh: Option<String>,
     #[arg(
         long,
         value_name = "ADDRESS",
-        required_unless_present = "ssh",
+        required_unless_present_any = ["ssh", "config"],
         help = "Sets the listenning address to bridge the extra socket"
     )]
     pub extra: Option<String>,
@@ -23,6 +37,186 @@ pub struct Args {
         help = "Sets the path to gnupg extra socket optionaly"
     )]
     pub extra_socket: Option<String>,
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        help = "Closes connections that transfer nothing for the given seconds"
+    )]
+    pub idle_timeout: Option<u64>,
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        help = "Closes connections that stay open longer than the given seconds"
+    )]
+    pub max_duration: Option<u64>,
+    #[arg(
+        short,
+        long,
+        value_name = "PATH",
+        help = "Loads additional bridges from a TOML configuration file"
+    )]
+    pub config: Option<PathBuf>,
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        help = "Sets how long to wait for active connections when shutting down [default: 10]"
+    )]
+    pub shutdown_timeout: Option<u64>,
+    #[arg(
+        long,
+        global = true,
+        value_name = "ADDRESS",
+        help = "Sets the named pipe or Unix socket path of the control endpoint, or none"
+    )]
+    pub control: Option<String>,
+    #[arg(
+        long,
+        value_name = "ADDRESS",
+        help = "Serves Prometheus metrics over HTTP on the TCP address"
+    )]
+    pub metrics: Option<String>,
+    #[arg(
+        long,
+        value_name = "FORMAT",
+        help = "Sets the log format, either text or json [default: text]"
+    )]
+    pub log_format: Option<LogFormat>,
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "Writes logs to the file as well, rotating it when it grows large"
+    )]
+    pub log_file: Option<PathBuf>,
+    #[arg(long, help = "Sends logs to syslog or journald (Unix only)")]
+    pub syslog: bool,
+    #[arg(
+        long,
+        help = "Traces raw payloads including passphrases and keys instead of redacted summaries"
+    )]
+    pub trace_secrets: bool,
+    #[arg(
+        long,
+        help = "Locks buffers holding secrets in memory to keep them out of swap"
+    )]
+    pub lock_memory: bool,
     #[arg(short, long, help = "Runs the program as a background daemon")]
     pub detach: bool,
 }
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    #[command(flatten)]
+    Control(ControlCommand),
+    /// Bridges a single connection over stdin and stdout, then exits
+    Stdio {
+        #[arg(
+            long = "type",
+            value_name = "TYPE",
+            help = "Sets the gpg-agent socket to bridge, one of ssh, extra or agent"
+        )]
+        ty: SocketType,
+        #[arg(
+            long,
+            value_name = "BACKEND",
+            help = "Sets the gnupg socket file or a backend URI, queried from gpgconf if omitted"
+        )]
+        backend: Option<String>,
+        #[arg(
+            long,
+            value_name = "SECONDS",
+            help = "Closes the connection if it transfers nothing for the given seconds"
+        )]
+        idle_timeout: Option<u64>,
+        #[arg(
+            long,
+            value_name = "PATH",
+            help = "Sends the token in the file to a tcp:// backend"
+        )]
+        upstream_token_file: Option<PathBuf>,
+        #[arg(
+            long,
+            value_name = "PATH",
+            requires_all = ["upstream_key", "upstream_server_cert"],
+            help = "Connects to a tcp:// backend with TLS and the client certificate in the PEM file"
+        )]
+        upstream_cert: Option<PathBuf>,
+        #[arg(
+            long,
+            value_name = "PATH",
+            requires = "upstream_cert",
+            help = "Sets the PEM file of the private key of the client certificate"
+        )]
+        upstream_key: Option<PathBuf>,
+        #[arg(
+            long,
+            value_name = "PATH",
+            requires = "upstream_cert",
+            help = "Sets the PEM file of the only server certificate accepted"
+        )]
+        upstream_server_cert: Option<PathBuf>,
+    },
+}
+
+/// Commands sent to a running bridge through its control endpoint.
+#[derive(Subcommand, Debug)]
+pub enum ControlCommand {
+    /// Shows the listeners and their backend state
+    Status,
+    /// Lists active connections
+    Connections,
+    /// Closes an active connection
+    Kill {
+        #[arg(value_name = "ID")]
+        id: u64,
+    },
+    /// Stops the bridge gracefully
+    Stop,
+    /// Manages tokens issued for listeners with `issued_tokens`
+    Token {
+        #[command(subcommand)]
+        command: TokenCommand,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum TokenCommand {
+    /// Issues a token that the listener accepts until it expires or the bridge restarts, and
+    /// prints it
+    Issue {
+        #[arg(long, value_name = "NAME")]
+        listener: String,
+        #[arg(
+            long,
+            value_name = "DURATION",
+            default_value = "1h",
+            value_parser = humantime::parse_duration,
+            help = "Sets how long the token is valid, e.g. 30m or 8h"
+        )]
+        ttl: Duration,
+    },
+    /// Lists tokens that haven't expired
+    List,
+    /// Revokes a token before it expires
+    Revoke {
+        #[arg(value_name = "ID")]
+        id: u64,
+    },
+}
+
+impl ControlCommand {
+    pub fn request(&self) -> String {
+        match self {
+            ControlCommand::Status => "status".to_owned(),
+            ControlCommand::Connections => "connections".to_owned(),
+            ControlCommand::Kill { id } => format!("kill {}", id),
+            ControlCommand::Stop => "stop".to_owned(),
+            ControlCommand::Token { command } => match command {
+                TokenCommand::Issue { listener, ttl } => {
+                    format!("token issue {} {}", ttl.as_secs(), listener)
+                }
+                TokenCommand::List => "token list".to_owned(),
+                TokenCommand::Revoke { id } => format!("token revoke {}", id),
+            },
+        }
+    }
+}
@@ -0,0 +1,755 @@
+use std::collections::HashSet;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+#[cfg(not(unix))]
+use std::time::SystemTime;
+
+use serde::Deserialize;
+
+use crate::acl::{AddrRule, Cidr};
+use crate::addr::{BackendAddr, ListenAddr};
+use crate::bridge::Timeouts;
+use crate::util::report_data_err;
+use crate::SocketType;
+
+/// Bridges declared in a configuration file.
+///
+/// ```toml
+/// shutdown_timeout = 30
+/// control = '\\.\pipe\gpg-bridge-control'
+/// metrics = "127.0.0.1:9464"
+///
+/// [log]
+/// format = "json"
+/// file = "C:/Users/me/AppData/Local/gpg-bridge/gpg-bridge.log"
+///
+/// [[bridge]]
+/// name = "wsl-ssh"
+/// type = "ssh"
+/// listen = "127.0.0.1:4322"
+/// idle_timeout = 600
+///
+/// [[bridge]]
+/// type = "extra"
+/// listen = "10.8.0.1:4321"
+/// backend = "C:/Users/me/AppData/Roaming/gnupg/S.gpg-agent.extra"
+/// ```
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Seconds to wait for active connections when shutting down.
+    pub shutdown_timeout: Option<u64>,
+    /// Named pipe or Unix socket path of the control endpoint, `none` turns it off.
+    pub control: Option<String>,
+    /// TCP address to serve Prometheus metrics on, disabled if omitted.
+    pub metrics: Option<String>,
+    /// Locks buffers holding secrets in memory so they are never swapped to disk.
+    #[serde(default)]
+    pub lock_memory: bool,
+    /// Where and how to write logs, it's only read at start.
+    #[serde(default)]
+    pub log: LogConfig,
+    #[serde(default, rename = "bridge")]
+    pub bridges: Vec<BridgeConfig>,
+}
+
+impl Config {
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Config> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path)?;
+        let config: Config = toml::from_str(&content)
+            .map_err(|e| report_data_err(format!("invalid config {}: {}", path.display(), e)))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> io::Result<()> {
+        let mut names = HashSet::new();
+        for bridge in &self.bridges {
+            if !names.insert(bridge.name()) {
+                return Err(report_data_err(format!(
+                    "duplicated bridge name {}",
+                    bridge.name()
+                )));
+            }
+            bridge.validate()?;
+        }
+        Ok(())
+    }
+}
+
+/// Output format of logs.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    /// One JSON object per line.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("unknown log format {}, expect text or json", s)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct LogConfig {
+    #[serde(default)]
+    pub format: LogFormat,
+    /// Writes logs to the file in addition to stderr.
+    pub file: Option<PathBuf>,
+    /// Size in MiB at which the log file is rotated, defaults to 10.
+    pub max_size: Option<u64>,
+    /// Number of rotated log files to keep, defaults to 5.
+    pub max_files: Option<usize>,
+    /// Sends logs to syslog, which is also collected by journald. Unix only.
+    #[serde(default)]
+    pub syslog: bool,
+    /// Dumps raw payloads at trace level, including passphrases and keys.
+    #[serde(default)]
+    pub trace_secrets: bool,
+}
+
+/// A single listener and the agent socket it forwards to.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct BridgeConfig {
+    /// Identifies the bridge, defaults to the listening address.
+    pub name: Option<String>,
+    #[serde(rename = "type")]
+    pub ty: SocketType,
+    /// Where to take connections from, see [`ListenAddr`].
+    pub listen: String,
+    /// Where to forward to, see [`BackendAddr`]. The gnupg socket of the type is queried from
+    /// gpgconf if not set, and ssh goes to Pageant.
+    pub backend: Option<String>,
+    /// Seconds a connection can stay without any traffic.
+    pub idle_timeout: Option<u64>,
+    /// Seconds a connection can stay open.
+    pub max_duration: Option<u64>,
+    /// Accepts TLS connections with client certificates instead of plain TCP.
+    pub tls: Option<TlsListenConfig>,
+    /// TCP address of another gpg-bridge to forward to instead of the local agent.
+    pub upstream: Option<String>,
+    /// Connects to `upstream` with TLS.
+    pub upstream_tls: Option<TlsConnectConfig>,
+    /// File of the token clients must send before anything else.
+    pub token_file: Option<PathBuf>,
+    /// File of the token to send to `upstream`.
+    pub upstream_token_file: Option<PathBuf>,
+    /// Accepts tokens issued by `gpg-bridge token issue` for this bridge.
+    #[serde(default)]
+    pub issued_tokens: bool,
+    /// Networks that clients of a TCP listener must come from, any if empty.
+    #[serde(default)]
+    pub allow: Vec<AddrRule>,
+    /// Networks that clients of a TCP listener must not come from.
+    #[serde(default)]
+    pub deny: Vec<AddrRule>,
+    /// Relays that send a PROXY header with the address of the client they connect for.
+    #[serde(default)]
+    pub trusted_proxies: Vec<Cidr>,
+    /// Socket types a mux bridge serves, channels of other types are refused.
+    #[serde(default)]
+    pub channels: Vec<SocketType>,
+    /// Forwards to `upstream` as a channel of a mux connection shared with other bridges.
+    #[serde(default)]
+    pub upstream_mux: bool,
+    /// TCP address to accept connections dialed in by a bridge with `listen = "dial://..."`, which
+    /// clients are forwarded to.
+    pub rendezvous: Option<String>,
+    /// File of the token dialers must send to `rendezvous`, required with a rendezvous.
+    pub rendezvous_token_file: Option<PathBuf>,
+    /// Accepts dialers at `rendezvous` with TLS and client certificates.
+    pub rendezvous_tls: Option<TlsListenConfig>,
+    /// Connections kept parked at the rendezvous by a dialing bridge, defaults to 4.
+    pub dial_pool: Option<usize>,
+    /// File of the token to send to the rendezvous when dialing.
+    pub dial_token_file: Option<PathBuf>,
+    /// Dials the rendezvous with TLS.
+    pub dial_tls: Option<TlsConnectConfig>,
+}
+
+/// TLS settings of a listener.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct TlsListenConfig {
+    /// PEM file of the server certificate chain.
+    pub cert: PathBuf,
+    /// PEM file of the server private key.
+    pub key: PathBuf,
+    /// PEM file of CA certificates, clients must present a certificate signed by one of them.
+    pub client_ca: PathBuf,
+}
+
+/// TLS settings to connect to an upstream gpg-bridge.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConnectConfig {
+    /// PEM file of the client certificate chain.
+    pub cert: PathBuf,
+    /// PEM file of the client private key.
+    pub key: PathBuf,
+    /// PEM file of the server certificate, only this exact certificate is accepted.
+    pub server_cert: PathBuf,
+}
+
+impl BridgeConfig {
+    pub fn new(ty: SocketType, listen: String) -> BridgeConfig {
+        BridgeConfig {
+            name: None,
+            ty,
+            listen,
+            backend: None,
+            idle_timeout: None,
+            max_duration: None,
+            tls: None,
+            upstream: None,
+            upstream_tls: None,
+            token_file: None,
+            upstream_token_file: None,
+            issued_tokens: false,
+            allow: vec![],
+            deny: vec![],
+            trusted_proxies: vec![],
+            channels: vec![],
+            upstream_mux: false,
+            rendezvous: None,
+            rendezvous_token_file: None,
+            rendezvous_tls: None,
+            dial_pool: None,
+            dial_token_file: None,
+            dial_tls: None,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        self.name.as_deref().unwrap_or(&self.listen)
+    }
+
+    /// Parses `listen`.
+    pub fn listen_addr(&self) -> io::Result<ListenAddr> {
+        self.listen.parse().map_err(|e| {
+            report_data_err(format!(
+                "bridge {} has an invalid listening address: {}",
+                self.name(),
+                e
+            ))
+        })
+    }
+
+    /// Parses `backend`.
+    pub fn backend_addr(&self) -> io::Result<Option<BackendAddr>> {
+        let Some(backend) = &self.backend else {
+            return Ok(None);
+        };
+        backend.parse().map(Some).map_err(|e| {
+            report_data_err(format!(
+                "bridge {} has an invalid backend: {}",
+                self.name(),
+                e
+            ))
+        })
+    }
+
+    /// Path of the gnupg socket file given by `backend`, if any.
+    pub fn assuan_file(&self) -> Option<String> {
+        match self.backend_addr() {
+            Ok(Some(BackendAddr::AssuanFile(path))) => Some(path),
+            _ => None,
+        }
+    }
+
+    /// Address of the gpg-bridge to forward to, `upstream` or a `tcp://` backend.
+    ///
+    /// Both are connected to the same way, with `upstream_tls` and `upstream_token_file`.
+    pub fn upstream_addr(&self) -> Option<String> {
+        if let Some(upstream) = &self.upstream {
+            return Some(upstream.clone());
+        }
+        match self.backend_addr() {
+            Ok(Some(BackendAddr::Tcp(addr))) => Some(addr),
+            _ => None,
+        }
+    }
+
+    /// Whether connections are relayed as is to `backend`.
+    pub fn relays_backend(&self) -> bool {
+        matches!(self.backend_addr(), Ok(Some(addr)) if addr.is_relay())
+    }
+
+    pub fn timeouts(&self) -> Timeouts {
+        Timeouts {
+            idle: self.idle_timeout.map(Duration::from_secs),
+            total: self.max_duration.map(Duration::from_secs),
+        }
+    }
+
+    pub(crate) fn validate(&self) -> io::Result<()> {
+        if self.listen.is_empty() {
+            return Err(report_data_err(format!(
+                "bridge {} has empty listening address",
+                self.name()
+            )));
+        }
+        if let Some(backend) = self.backend_addr()? {
+            if self.ty != SocketType::Mux && !backend.serves(self.ty) {
+                return Err(report_data_err(format!(
+                    "bridge {} can't forward {} clients to {}",
+                    self.name(),
+                    self.ty,
+                    backend
+                )));
+            }
+            let unsupported = match backend {
+                BackendAddr::Unix(_) if cfg!(not(unix)) => Some("Unix sockets"),
+                BackendAddr::Pageant if cfg!(not(windows)) => Some("Pageant"),
+                _ => None,
+            };
+            if let Some(what) = unsupported {
+                return Err(report_data_err(format!(
+                    "bridge {} forwards to {}, which this platform doesn't support",
+                    self.name(),
+                    what
+                )));
+            }
+        }
+        if self.ty == SocketType::Mux {
+            if self.channels.is_empty() {
+                return Err(report_data_err(format!(
+                    "bridge {} is a mux but serves no channels",
+                    self.name()
+                )));
+            }
+            if self.backend.is_some() || self.upstream.is_some() {
+                return Err(report_data_err(format!(
+                    "bridge {} is a mux and forwards each channel by its type, it doesn't \
+                     accept a backend or an upstream",
+                    self.name()
+                )));
+            }
+        } else if !self.channels.is_empty() {
+            return Err(report_data_err(format!(
+                "bridge {} has channels but is not a mux",
+                self.name()
+            )));
+        }
+        if let Some(ty) = self
+            .channels
+            .iter()
+            .find(|t| matches!(t, SocketType::Mux | SocketType::Auto))
+        {
+            return Err(report_data_err(format!(
+                "bridge {} can't carry {} in a mux channel",
+                self.name(),
+                ty
+            )));
+        }
+        if self.upstream_mux && self.ty == SocketType::Auto {
+            return Err(report_data_err(format!(
+                "bridge {} detects the socket type, which a mux channel can't carry",
+                self.name()
+            )));
+        }
+        let upstream = self.upstream_addr().is_some();
+        if self.upstream_mux && !upstream {
+            return Err(report_data_err(format!(
+                "bridge {} has upstream_mux but no upstream or tcp:// backend",
+                self.name()
+            )));
+        }
+        if self.upstream.is_some() && self.backend.is_some() {
+            return Err(report_data_err(format!(
+                "bridge {} can't have both a backend and an upstream",
+                self.name()
+            )));
+        }
+        if self.upstream_tls.is_some() && !upstream {
+            return Err(report_data_err(format!(
+                "bridge {} has upstream_tls but no upstream or tcp:// backend",
+                self.name()
+            )));
+        }
+        let listen = self.listen_addr()?;
+        let dial = matches!(listen, ListenAddr::Dial(_));
+        let tcp = listen.is_tcp();
+        let unsupported = match listen {
+            ListenAddr::Unix { .. } if cfg!(not(unix)) => Some("Unix sockets"),
+            ListenAddr::Fd(_) if cfg!(not(unix)) => Some("inherited sockets"),
+            ListenAddr::Pipe(_) if cfg!(not(windows)) => Some("named pipes"),
+            _ => None,
+        };
+        if let Some(what) = unsupported {
+            return Err(report_data_err(format!(
+                "bridge {} listens on {}, which are not supported on this platform",
+                self.name(),
+                what
+            )));
+        }
+        if matches!(listen, ListenAddr::Tls(_)) && self.tls.is_none() {
+            return Err(report_data_err(format!(
+                "bridge {} listens on a tls:// address but has no tls settings",
+                self.name()
+            )));
+        }
+        if (self.dial_pool.is_some() || self.dial_token_file.is_some() || self.dial_tls.is_some())
+            && !dial
+        {
+            return Err(report_data_err(format!(
+                "bridge {} has dial settings but doesn't dial",
+                self.name()
+            )));
+        }
+        if self.dial_pool == Some(0) {
+            return Err(report_data_err(format!(
+                "bridge {} must keep at least one connection parked",
+                self.name()
+            )));
+        }
+        if self.rendezvous.is_some() {
+            if self.upstream.is_some() || self.backend.is_some() || self.ty == SocketType::Mux {
+                return Err(report_data_err(format!(
+                    "bridge {} forwards to dialers at its rendezvous, it can't have a backend \
+                     or an upstream, or be a mux",
+                    self.name()
+                )));
+            }
+            if self.rendezvous_token_file.is_none() {
+                return Err(report_data_err(format!(
+                    "bridge {} has a rendezvous but no rendezvous_token_file, anyone reaching it \
+                     could receive the requests of its clients",
+                    self.name()
+                )));
+            }
+        } else if self.rendezvous_token_file.is_some() || self.rendezvous_tls.is_some() {
+            return Err(report_data_err(format!(
+                "bridge {} has rendezvous settings but no rendezvous",
+                self.name()
+            )));
+        }
+        if self.tls.is_some() && !tcp {
+            return Err(report_data_err(format!(
+                "bridge {} can only use TLS on a TCP address",
+                self.name()
+            )));
+        }
+        if (self.token_file.is_some() || self.issued_tokens) && !tcp {
+            return Err(report_data_err(format!(
+                "bridge {} can only require a token on a TCP address",
+                self.name()
+            )));
+        }
+        if !self.trusted_proxies.is_empty() && !tcp {
+            return Err(report_data_err(format!(
+                "bridge {} can only accept PROXY headers on a TCP address",
+                self.name()
+            )));
+        }
+        if (!self.allow.is_empty() || !self.deny.is_empty()) && !tcp {
+            return Err(report_data_err(format!(
+                "bridge {} can only filter clients on a TCP address",
+                self.name()
+            )));
+        }
+        if cfg!(not(windows))
+            && self
+                .allow
+                .iter()
+                .chain(&self.deny)
+                .any(|r| *r == AddrRule::Wsl)
+        {
+            return Err(report_data_err(format!(
+                "bridge {} uses the wsl preset, which is only supported on Windows",
+                self.name()
+            )));
+        }
+        if self.upstream_token_file.is_some() && !upstream {
+            return Err(report_data_err(format!(
+                "bridge {} has upstream_token_file but no upstream or tcp:// backend",
+                self.name()
+            )));
+        }
+        let tls = self.tls.is_some()
+            || self.upstream_tls.is_some()
+            || self.rendezvous_tls.is_some()
+            || self.dial_tls.is_some();
+        if cfg!(not(feature = "tls")) && tls {
+            return Err(report_data_err(format!(
+                "bridge {} uses TLS, but gpg-bridge is built without the tls feature",
+                self.name()
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Signals when the configuration should be reloaded.
+///
+/// It's triggered by SIGHUP on Unix. As there is no such signal on Windows, the configuration
+/// file is checked for modification periodically instead.
+pub struct ReloadTrigger {
+    #[cfg(unix)]
+    hangup: tokio::signal::unix::Signal,
+    #[cfg(not(unix))]
+    path: PathBuf,
+    #[cfg(not(unix))]
+    modified: Option<SystemTime>,
+}
+
+impl ReloadTrigger {
+    #[cfg(unix)]
+    pub fn new(_path: impl AsRef<Path>) -> io::Result<ReloadTrigger> {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        Ok(ReloadTrigger {
+            hangup: signal(SignalKind::hangup())?,
+        })
+    }
+
+    #[cfg(not(unix))]
+    pub fn new(path: impl AsRef<Path>) -> io::Result<ReloadTrigger> {
+        let path = path.as_ref().to_owned();
+        let modified = std::fs::metadata(&path)?.modified().ok();
+        Ok(ReloadTrigger { path, modified })
+    }
+
+    #[cfg(unix)]
+    pub async fn wait(&mut self) {
+        self.hangup.recv().await;
+    }
+
+    #[cfg(not(unix))]
+    pub async fn wait(&mut self) {
+        const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+        loop {
+            tokio::time::sleep(POLL_INTERVAL).await;
+            // Editors may replace the file, ignore the moment it doesn't exist.
+            let modified = match tokio::fs::metadata(&self.path).await {
+                Ok(meta) => meta.modified().ok(),
+                Err(_) => continue,
+            };
+            if modified != self.modified {
+                self.modified = modified;
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(content: &str) -> io::Result<Config> {
+        let config: Config = toml::from_str(content).map_err(|e| report_data_err(e.to_string()))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Parses a configuration of a single bridge made of `fields`.
+    fn bridge(fields: &str) -> io::Result<Config> {
+        parse(&format!("[[bridge]]\n{}", fields))
+    }
+
+    #[test]
+    fn test_parse() {
+        let config = parse(
+            r#"
+            shutdown_timeout = 30
+            control = "none"
+
+            [log]
+            format = "json"
+
+            [[bridge]]
+            name = "wsl-ssh"
+            type = "ssh"
+            listen = "127.0.0.1:4322"
+            idle_timeout = 600
+
+            [[bridge]]
+            type = "extra"
+            listen = "10.8.0.1:4321"
+            backend = "/home/me/.gnupg/S.gpg-agent.extra"
+            max_duration = 60
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.shutdown_timeout, Some(30));
+        assert_eq!(config.control.as_deref(), Some("none"));
+        assert_eq!(config.log.format, LogFormat::Json);
+        assert_eq!(config.bridges.len(), 2);
+        let ssh = &config.bridges[0];
+        assert_eq!((ssh.name(), ssh.ty), ("wsl-ssh", SocketType::Ssh));
+        assert_eq!(ssh.timeouts().idle, Some(Duration::from_secs(600)));
+        let extra = &config.bridges[1];
+        assert_eq!(extra.name(), "10.8.0.1:4321");
+        assert_eq!(
+            extra.assuan_file().as_deref(),
+            Some("/home/me/.gnupg/S.gpg-agent.extra")
+        );
+        assert_eq!(extra.timeouts().total, Some(Duration::from_secs(60)));
+
+        assert_eq!(parse("").unwrap(), Config::default());
+    }
+
+    #[test]
+    fn test_names() {
+        let two = |first: &str, second: &str| {
+            parse(&format!(
+                "[[bridge]]\ntype = \"ssh\"\n{}\n[[bridge]]\ntype = \"extra\"\n{}",
+                first, second
+            ))
+        };
+        let e = two("listen = \"127.0.0.1:1\"", "listen = \"127.0.0.1:1\"").unwrap_err();
+        assert!(e.to_string().contains("duplicated bridge name"), "{}", e);
+        let e = two(
+            "name = \"a\"\nlisten = \"127.0.0.1:1\"",
+            "name = \"a\"\nlisten = \"127.0.0.1:2\"",
+        )
+        .unwrap_err();
+        assert!(e.to_string().contains("duplicated bridge name a"), "{}", e);
+        // A name may be the address of another bridge.
+        two(
+            "listen = \"127.0.0.1:1\"",
+            "name = \"a\"\nlisten = \"127.0.0.1:1\"",
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_missing_fields() {
+        for fields in [
+            "listen = \"127.0.0.1:1\"",
+            "type = \"ssh\"",
+            "type = \"ssh\"\nlisten = \"127.0.0.1:1\"\nport = 1",
+            "type = \"gpg\"\nlisten = \"127.0.0.1:1\"",
+            "type = \"ssh\"\nlisten = \"\"",
+        ] {
+            assert!(bridge(fields).is_err(), "{}", fields);
+        }
+        assert!(parse("unknown = 1").is_err());
+        assert!(parse("[log]\nformat = \"xml\"").is_err());
+    }
+
+    #[test]
+    fn test_invalid() {
+        let cases = [
+            ("type = \"ssh\"\nlisten = \"localhost\"", "invalid listening address"),
+            (
+                "type = \"ssh\"\nlisten = \"127.0.0.1:1\"\nbackend = \"http://h:1\"",
+                "invalid backend",
+            ),
+            (
+                "type = \"ssh\"\nlisten = \"127.0.0.1:1\"\nbackend = \"/gnupg/S.gpg-agent\"",
+                "can't forward ssh clients",
+            ),
+            ("type = \"mux\"\nlisten = \"127.0.0.1:1\"", "serves no channels"),
+            (
+                "type = \"mux\"\nlisten = \"127.0.0.1:1\"\nchannels = [\"ssh\"]\nupstream = \"h:1\"",
+                "doesn't accept a backend or an upstream",
+            ),
+            (
+                "type = \"mux\"\nlisten = \"127.0.0.1:1\"\nchannels = [\"auto\"]",
+                "can't carry auto",
+            ),
+            (
+                "type = \"ssh\"\nlisten = \"127.0.0.1:1\"\nchannels = [\"ssh\"]",
+                "not a mux",
+            ),
+            (
+                "type = \"ssh\"\nlisten = \"127.0.0.1:1\"\nupstream_mux = true",
+                "upstream_mux but no upstream",
+            ),
+            (
+                "type = \"auto\"\nlisten = \"127.0.0.1:1\"\nupstream = \"h:1\"\nupstream_mux = true",
+                "detects the socket type",
+            ),
+            (
+                "type = \"extra\"\nlisten = \"127.0.0.1:1\"\nupstream = \"h:1\"\nbackend = \"/S\"",
+                "both a backend and an upstream",
+            ),
+            (
+                "type = \"ssh\"\nlisten = \"127.0.0.1:1\"\nupstream_token_file = \"/t\"",
+                "upstream_token_file but no upstream",
+            ),
+            (
+                "type = \"ssh\"\nlisten = \"127.0.0.1:1\"\ndial_pool = 2",
+                "dial settings but doesn't dial",
+            ),
+            (
+                "type = \"ssh\"\nlisten = \"127.0.0.1:1\"\ndial_token_file = \"/t\"",
+                "dial settings but doesn't dial",
+            ),
+            (
+                "type = \"ssh\"\nlisten = \"dial://h:1\"\ndial_pool = 0",
+                "at least one connection parked",
+            ),
+            (
+                "type = \"ssh\"\nlisten = \"127.0.0.1:1\"\nrendezvous = \"0.0.0.0:2\"\n\
+                 rendezvous_token_file = \"/t\"\nupstream = \"h:1\"",
+                "forwards to dialers at its rendezvous",
+            ),
+            (
+                "type = \"ssh\"\nlisten = \"127.0.0.1:1\"\nrendezvous = \"0.0.0.0:2\"",
+                "no rendezvous_token_file",
+            ),
+            (
+                "type = \"ssh\"\nlisten = \"127.0.0.1:1\"\nrendezvous_token_file = \"/t\"",
+                "rendezvous settings but no rendezvous",
+            ),
+            (
+                "type = \"ssh\"\nlisten = \"dial://h:1\"\ntoken_file = \"/t\"",
+                "require a token on a TCP address",
+            ),
+            (
+                "type = \"ssh\"\nlisten = \"stdio:\"\nissued_tokens = true",
+                "require a token on a TCP address",
+            ),
+            (
+                "type = \"ssh\"\nlisten = \"dial://h:1\"\nallow = [\"10.0.0.0/8\"]",
+                "filter clients on a TCP address",
+            ),
+            (
+                "type = \"ssh\"\nlisten = \"stdio:\"\ntrusted_proxies = [\"10.0.0.1/32\"]",
+                "PROXY headers on a TCP address",
+            ),
+        ];
+        for (fields, message) in cases {
+            let e = bridge(fields).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData, "{}", fields);
+            assert!(e.to_string().contains(message), "{}: {}", fields, e);
+        }
+    }
+
+    #[test]
+    fn test_valid() {
+        for fields in [
+            "type = \"ssh\"\nlisten = \"dial://h:1\"\ndial_pool = 2\ndial_token_file = \"/t\"",
+            "type = \"ssh\"\nlisten = \"127.0.0.1:1\"\nrendezvous = \"0.0.0.0:2\"\n\
+             rendezvous_token_file = \"/t\"",
+            "type = \"extra\"\nlisten = \"127.0.0.1:1\"\nupstream = \"h:1\"\n\
+             upstream_token_file = \"/t\"\nupstream_mux = true",
+            "type = \"extra\"\nlisten = \"127.0.0.1:1\"\nbackend = \"tcp://h:1\"\n\
+             upstream_token_file = \"/t\"\nupstream_mux = true",
+            "type = \"mux\"\nlisten = \"127.0.0.1:1\"\nchannels = [\"ssh\", \"extra\"]\n\
+             token_file = \"/t\"\nallow = [\"10.0.0.0/8\"]\ntrusted_proxies = [\"10.0.0.1/32\"]",
+        ] {
+            if let Err(e) = bridge(fields) {
+                panic!("{}: {}", fields, e);
+            }
+        }
+    }
+}
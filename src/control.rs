@@ -0,0 +1,323 @@
+use std::fmt::Write as _;
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::{debug, error, info, warn};
+use tokio::io::{AsyncBufReadExt as _, AsyncReadExt as _, AsyncWriteExt as _, BufReader};
+use tokio_util::sync::CancellationToken;
+
+use crate::addr::BackendAddr;
+use crate::listener::retry::RetryListener;
+use crate::listener::{Listener, PeerInfo};
+use crate::registry::Registry;
+use crate::stream::SplitStream;
+use crate::util::other_error;
+use crate::SocketType;
+
+/// Longest command accepted from control clients.
+const MAX_COMMAND_LEN: u64 = 1024;
+
+/// Value of `control` that turns the endpoint off.
+pub const DISABLED: &str = "none";
+
+/// Address of the control endpoint, `configured` or the default one.
+///
+/// There is no default on Unix without `XDG_RUNTIME_DIR`, a shared directory like `/tmp` would
+/// let other users take the path first.
+pub fn addr(configured: Option<&str>) -> Option<String> {
+    match configured {
+        Some(DISABLED) => None,
+        Some(addr) => Some(addr.to_owned()),
+        #[cfg(windows)]
+        None => Some("\\\\.\\pipe\\gpg-bridge-control".to_owned()),
+        #[cfg(unix)]
+        None => std::env::var("XDG_RUNTIME_DIR")
+            .ok()
+            .map(|dir| format!("{}/gpg-bridge-control.sock", dir)),
+    }
+}
+
+/// Serves control commands on `addr`, which is a named pipe on Windows and a Unix socket path
+/// on Unix.
+///
+/// `stop` is cancelled when a client asks the bridge to stop.
+pub async fn serve(addr: &str, registry: Arc<Registry>, stop: CancellationToken) -> io::Result<()> {
+    #[cfg(windows)]
+    {
+        use tokio::net::windows::named_pipe::ServerOptions;
+
+        use crate::listener::named_pipe::NamedPipeServerListener;
+
+        let server = ServerOptions::new()
+            .first_pipe_instance(true)
+            .create(addr)?;
+        let listener = NamedPipeServerListener::new(server, addr.to_owned());
+        serve_listener(listener, registry, stop).await
+    }
+    #[cfg(unix)]
+    {
+        use crate::listener::unix::UnixSocketListener;
+
+        let listener = UnixSocketListener::bind_private(addr, Some(0o600), None)?;
+        serve_listener(listener, registry, stop).await
+    }
+}
+
+async fn serve_listener<L>(
+    listener: L,
+    registry: Arc<Registry>,
+    stop: CancellationToken,
+) -> io::Result<()>
+where
+    L: Listener + Send,
+    L::Connection: SplitStream + Send + 'static,
+{
+    info!("control endpoint start");
+    let mut listener = RetryListener::new(listener);
+    loop {
+        let (conn, peer) = listener.accept().await?;
+        if !same_user(&peer) {
+            warn!("refused control client {} of another user", peer);
+            continue;
+        }
+        let registry = registry.clone();
+        let stop = stop.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle(conn, &registry, &stop).await {
+                error!("failed to handle control request: {:?}", e);
+            }
+        });
+    }
+}
+
+/// Whether `peer` runs as the user of the bridge.
+///
+/// Only checked on Unix, by default other users can't write to a named pipe.
+fn same_user(peer: &PeerInfo) -> bool {
+    #[cfg(unix)]
+    {
+        peer.uid == Some(unsafe { libc::geteuid() })
+    }
+    #[cfg(windows)]
+    {
+        let _ = peer;
+        true
+    }
+}
+
+async fn handle(
+    mut conn: impl SplitStream,
+    registry: &Registry,
+    stop: &CancellationToken,
+) -> io::Result<()> {
+    let (read, mut write) = conn.split_rw();
+    let mut line = String::new();
+    BufReader::new(read.take(MAX_COMMAND_LEN))
+        .read_line(&mut line)
+        .await?;
+    debug!("control command {:?}", line.trim());
+    let resp = match execute(line.trim(), registry, stop) {
+        Ok(body) => format!("OK\n{}", body),
+        Err(msg) => format!("ERR {}\n", msg),
+    };
+    write.write_all(resp.as_bytes()).await?;
+    write.shutdown().await
+}
+
+fn execute(command: &str, registry: &Registry, stop: &CancellationToken) -> Result<String, String> {
+    if let Some(args) = command.strip_prefix("token ") {
+        return token(command, args, registry);
+    }
+    let mut args = command.split_whitespace();
+    match (args.next(), args.next(), args.next()) {
+        (Some("status"), None, _) => Ok(status(registry)),
+        (Some("connections"), None, _) => Ok(connections(registry)),
+        (Some("kill"), Some(id), None) => {
+            let id = id
+                .parse()
+                .map_err(|_| format!("invalid connection id {}", id))?;
+            if registry.kill(id) {
+                Ok(format!("connection {} killed\n", id))
+            } else {
+                Err(format!("connection {} not found", id))
+            }
+        }
+        (Some("stop"), None, _) => {
+            info!("stop requested by control client");
+            stop.cancel();
+            Ok("stopping\n".to_owned())
+        }
+        _ => Err(format!("unknown command {:?}", command)),
+    }
+}
+
+fn status(registry: &Registry) -> String {
+    let connections = registry.connections();
+    let mut out = format!(
+        "{:<16} {:<6} {:<24} {:>11}  BACKEND\n",
+        "NAME", "TYPE", "LISTEN", "CONNECTIONS"
+    );
+    for listener in registry.listeners() {
+        let active = connections
+            .iter()
+            .filter(|c| Arc::ptr_eq(&c.listener, &listener))
+            .count();
+        let relayed = listener
+            .backend()
+            .path
+            .filter(|p| p.parse::<BackendAddr>().is_ok_and(|b| b.is_relay()));
+        let backend = match (listener.ty, relayed) {
+            (_, Some(relayed)) => relayed,
+            (SocketType::Ssh, None) => "pageant".to_owned(),
+            (SocketType::Mux, None) => "per channel type".to_owned(),
+            _ => {
+                let status = listener.backend();
+                format!(
+                    "{} port {} nonce {}",
+                    status.path.as_deref().unwrap_or("(gpgconf)"),
+                    status.port.map_or("-".to_owned(), |p| p.to_string()),
+                    if status.nonce_loaded {
+                        "loaded"
+                    } else {
+                        "not loaded"
+                    },
+                )
+            }
+        };
+        let _ = writeln!(
+            out,
+            "{:<16} {:<6} {:<24} {:>11}  {}",
+            listener.name, listener.ty, listener.listen, active, backend
+        );
+    }
+    out
+}
+
+/// Handles `token issue <SECONDS> <LISTENER>`, `token list` and `token revoke <ID>`.
+fn token(command: &str, args: &str, registry: &Registry) -> Result<String, String> {
+    let tokens = registry.tokens();
+    let mut args = args.splitn(3, ' ');
+    match (args.next(), args.next(), args.next()) {
+        (Some("issue"), Some(ttl), Some(listener)) => {
+            let ttl = ttl
+                .parse()
+                .ok()
+                .filter(|ttl| *ttl > 0)
+                .ok_or_else(|| format!("invalid ttl {}", ttl))?;
+            if !registry.listeners().iter().any(|l| l.name == listener) {
+                return Err(format!("listener {} not found", listener));
+            }
+            let (issued, token) = tokens
+                .issue(listener, Duration::from_secs(ttl))
+                .map_err(|e| e.to_string())?;
+            info!(
+                "issued token {} for {} until {}",
+                issued.id,
+                listener,
+                humantime::format_rfc3339_seconds(issued.expires)
+            );
+            let mut out = String::from_utf8_lossy(&token).into_owned();
+            out.push('\n');
+            Ok(out)
+        }
+        (Some("list"), None, _) => {
+            let mut out = format!("{:>6} {:<16} {:<20} STATUS\n", "ID", "LISTENER", "EXPIRES");
+            for token in tokens.list() {
+                let _ = writeln!(
+                    out,
+                    "{:>6} {:<16} {:<20} {}",
+                    token.id,
+                    token.listener,
+                    humantime::format_rfc3339_seconds(token.expires),
+                    if token.revoked { "revoked" } else { "active" }
+                );
+            }
+            Ok(out)
+        }
+        (Some("revoke"), Some(id), None) => {
+            let id = id.parse().map_err(|_| format!("invalid token id {}", id))?;
+            if tokens.revoke(id) {
+                info!("revoked token {}", id);
+                Ok(format!("token {} revoked\n", id))
+            } else {
+                Err(format!("token {} not found", id))
+            }
+        }
+        _ => Err(format!("unknown command {:?}", command)),
+    }
+}
+
+fn connections(registry: &Registry) -> String {
+    let mut out = format!(
+        "{:>6} {:<16} {:<24} {:>8} {:>10} {:>10}\n",
+        "ID", "LISTENER", "PEER", "AGE", "RECEIVED", "REPLIED"
+    );
+    for conn in registry.connections() {
+        let _ = writeln!(
+            out,
+            "{:>6} {:<16} {:<24} {:>7}s {:>10} {:>10}",
+            conn.id,
+            conn.listener.name,
+            conn.peer,
+            conn.activity.age().as_secs(),
+            conn.activity.received(),
+            conn.activity.replied()
+        );
+    }
+    out
+}
+
+/// Sends `command` to the control endpoint at `addr` and returns the output.
+pub async fn request(addr: &str, command: &str) -> io::Result<String> {
+    #[cfg(windows)]
+    let mut conn = {
+        use tokio::net::windows::named_pipe::ClientOptions;
+
+        const ERROR_PIPE_BUSY: i32 = 231;
+        let mut retries = 0;
+        loop {
+            match ClientOptions::new().open(addr) {
+                Ok(client) => break client,
+                Err(e) if e.raw_os_error() == Some(ERROR_PIPE_BUSY) && retries < 20 => {
+                    retries += 1;
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    };
+    #[cfg(unix)]
+    let mut conn = {
+        use std::os::unix::fs::MetadataExt as _;
+
+        // Don't send commands to a socket planted by another user.
+        if std::fs::symlink_metadata(addr)?.uid() != unsafe { libc::geteuid() } {
+            return Err(other_error(format!("{} belongs to another user", addr)));
+        }
+        tokio::net::UnixStream::connect(addr).await?
+    };
+
+    conn.write_all(format!("{}\n", command).as_bytes()).await?;
+    let read = async {
+        let mut resp = vec![];
+        loop {
+            match conn.read_buf(&mut resp).await {
+                Ok(0) => return Ok(resp),
+                Ok(_) => {}
+                // A closed pipe is reported as an error on Windows.
+                Err(e) if e.kind() == io::ErrorKind::BrokenPipe => return Ok(resp),
+                Err(e) => return Err(e),
+            }
+        }
+    };
+    let resp = tokio::time::timeout(Duration::from_secs(10), read)
+        .await
+        .map_err(|_| other_error("control endpoint doesn't respond".to_owned()))??;
+    let resp = String::from_utf8_lossy(&resp);
+    match resp.split_once('\n') {
+        Some(("OK", body)) => Ok(body.to_owned()),
+        Some((status, _)) if status.starts_with("ERR ") => Err(other_error(status[4..].to_owned())),
+        _ => Err(other_error(format!("unexpected response {:?}", resp))),
+    }
+}
@@ -0,0 +1,211 @@
+//! Runs a single bridge inside another program, which can watch and stop it.
+//!
+//! ```no_run
+//! # async fn run() -> std::io::Result<()> {
+//! use gpg_bridge::registry::Event;
+//! use gpg_bridge::{Bridge, SocketType};
+//!
+//! let bridge = Bridge::builder()
+//!     .socket_type(SocketType::Extra)
+//!     .listen("tcp://127.0.0.1:4321")
+//!     .start()?;
+//! let mut events = bridge.events();
+//! while let Some(event) = events.next().await {
+//!     println!("{:?}, {} active", event, bridge.stats().active);
+//!     if let Event::Error { .. } = event {
+//!         break;
+//!     }
+//! }
+//! bridge.shutdown().await
+//! # }
+//! ```
+
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+use crate::acl::{AddrRule, Cidr};
+use crate::bridge::{Control, Timeouts};
+use crate::config::BridgeConfig;
+use crate::registry::{BackendStatus, Events, Registry};
+use crate::util::{other_error, report_data_err};
+use crate::SocketType;
+
+/// Who may use a bridge, see the keys of the same names in the configuration file.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Policy {
+    /// Networks that TCP clients must come from, any if empty.
+    pub allow: Vec<AddrRule>,
+    /// Networks that TCP clients must not come from.
+    pub deny: Vec<AddrRule>,
+    /// Relays that send a PROXY header with the address of the client.
+    pub trusted_proxies: Vec<Cidr>,
+    /// File of the token clients must send before anything else.
+    pub token_file: Option<PathBuf>,
+}
+
+/// Counters of a running bridge.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// Connections accepted so far.
+    pub accepted: u64,
+    /// Connections that ended with an error.
+    pub failed: u64,
+    /// Connections being served.
+    pub active: usize,
+    /// Bytes received from clients.
+    pub received: u64,
+    /// Bytes replied to clients.
+    pub replied: u64,
+    /// What is known about the agent forwarded to.
+    pub backend: BackendStatus,
+}
+
+/// A bridge run by the embedding program, see [`Bridge::builder`].
+pub struct Bridge;
+
+impl Bridge {
+    pub fn builder() -> BridgeBuilder {
+        BridgeBuilder::default()
+    }
+}
+
+/// Describes a bridge to start, the socket type and listening address are required.
+#[derive(Clone, Debug, Default)]
+pub struct BridgeBuilder {
+    name: Option<String>,
+    ty: Option<SocketType>,
+    listen: String,
+    backend: Option<String>,
+    timeouts: Timeouts,
+    policy: Policy,
+}
+
+impl BridgeBuilder {
+    /// Names the bridge in logs and events, defaults to the listening address.
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    pub fn socket_type(mut self, ty: SocketType) -> Self {
+        self.ty = Some(ty);
+        self
+    }
+
+    /// Where to take connections from, see [`ListenAddr`](crate::addr::ListenAddr).
+    pub fn listen(mut self, addr: impl Into<String>) -> Self {
+        self.listen = addr.into();
+        self
+    }
+
+    /// Where to forward to, see [`BackendAddr`](crate::addr::BackendAddr).
+    pub fn backend(mut self, addr: impl Into<String>) -> Self {
+        self.backend = Some(addr.into());
+        self
+    }
+
+    pub fn timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    pub fn policy(mut self, policy: Policy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    fn config(self) -> io::Result<BridgeConfig> {
+        let ty = self
+            .ty
+            .ok_or_else(|| report_data_err("bridge has no socket type"))?;
+        let mut config = BridgeConfig::new(ty, self.listen);
+        config.name = self.name;
+        config.backend = self.backend;
+        config.idle_timeout = self.timeouts.idle.map(|d| d.as_secs());
+        config.max_duration = self.timeouts.total.map(|d| d.as_secs());
+        config.allow = self.policy.allow;
+        config.deny = self.policy.deny;
+        config.trusted_proxies = self.policy.trusted_proxies;
+        config.token_file = self.policy.token_file;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Starts the bridge on the current tokio runtime.
+    ///
+    /// Failing to listen is only reported by [`BridgeHandle::join`] and as an event.
+    pub fn start(self) -> io::Result<BridgeHandle> {
+        let config = self.config()?;
+        let registry = Arc::new(Registry::new());
+        let listener = registry.add_listener(&config);
+        let (config, rx) = watch::channel(Arc::new(config));
+        let control = Control::new(registry.clone(), listener);
+        let task_control = control.clone();
+        let task = tokio::spawn(async move {
+            let res = crate::serve(rx, task_control.clone()).await;
+            if let Err(e) = &res {
+                task_control.listener().report_error(e);
+            }
+            res
+        });
+        Ok(BridgeHandle {
+            _config: config,
+            registry,
+            control,
+            task,
+        })
+    }
+}
+
+/// Watches and stops a bridge started by [`BridgeBuilder::start`].
+///
+/// Dropping the handle leaves the bridge running in background.
+pub struct BridgeHandle {
+    _config: watch::Sender<Arc<BridgeConfig>>,
+    registry: Arc<Registry>,
+    control: Control,
+    task: JoinHandle<io::Result<()>>,
+}
+
+impl BridgeHandle {
+    /// Stops accepting connections, waits for the accepted ones to finish and returns how the
+    /// bridge ended.
+    pub async fn shutdown(self) -> io::Result<()> {
+        self.control.stop();
+        self.join().await
+    }
+
+    /// Closes all accepted connections immediately, used after [`shutdown`](Self::shutdown)
+    /// has waited long enough.
+    pub fn abort(&self) {
+        self.control.abort();
+    }
+
+    /// Waits until the bridge ends by itself, which it only does on failure.
+    pub async fn join(self) -> io::Result<()> {
+        self.task
+            .await
+            .map_err(|e| other_error(format!("bridge task failed: {}", e)))?
+    }
+
+    pub fn stats(&self) -> Stats {
+        let listener = self.control.listener();
+        Stats {
+            accepted: listener.accepted(),
+            failed: listener.failed(),
+            active: self.registry.connections().len(),
+            received: listener.traffic().received(),
+            replied: listener.traffic().replied(),
+            backend: listener.backend(),
+        }
+    }
+
+    /// Receives connects, disconnects and errors from now on.
+    pub fn events(&self) -> Events {
+        self.registry.subscribe()
+    }
+}
@@ -0,0 +1,182 @@
+//! Failures callers may want to tell apart, carried inside [`io::Error`].
+//!
+//! Functions keep returning [`io::Result`], use [`Error::find`] to get the typed error back.
+
+use std::{error, fmt, io};
+
+/// Source of gpg-error codes raised by gpg-agent.
+const GPG_ERR_SOURCE_GPGAGENT: u32 = 4;
+const GPG_ERR_NOT_FOUND: u32 = 27;
+const GPG_ERR_NO_AGENT: u32 = 77;
+const GPG_ERR_INV_DATA: u32 = 79;
+const GPG_ERR_LINE_TOO_LONG: u32 = 97;
+
+/// `SSH_AGENT_FAILURE` with its length.
+const SSH_AGENT_FAILURE: [u8; 5] = [0, 0, 0, 1, 5];
+
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// gpg-agent can't be reached and failed to start.
+    AgentNotRunning(io::Error),
+    /// There is no Pageant window to send ssh requests to.
+    PageantNotFound(io::Error),
+    /// The gnupg socket file doesn't hold a port and a nonce.
+    SocketFileMalformed { path: String, reason: String },
+    /// gpgconf can't be run to look up the gnupg sockets.
+    GpgconfMissing(io::Error),
+    /// A client sent a frame larger than the protocol allows.
+    OversizedFrame { len: usize, max: usize },
+}
+
+impl Error {
+    /// Finds the typed error carried by `e` or any error it's caused by, if any.
+    pub fn find(e: &io::Error) -> Option<&Error> {
+        let mut next: Option<&(dyn error::Error + 'static)> = e.get_ref().map(|e| e as _);
+        while let Some(e) = next {
+            if let Some(e) = e.downcast_ref() {
+                return Some(e);
+            }
+            // The source of an io::Error is the one of the error it carries, not that error.
+            next = match e.downcast_ref::<io::Error>() {
+                Some(e) => e.get_ref().map(|e| e as _),
+                None => e.source(),
+            };
+        }
+        None
+    }
+
+    fn gpg_error(&self) -> (u32, &'static str) {
+        match self {
+            Error::AgentNotRunning(_) | Error::PageantNotFound(_) => {
+                (GPG_ERR_NO_AGENT, "No agent running")
+            }
+            Error::SocketFileMalformed { .. } => (GPG_ERR_INV_DATA, "Invalid data"),
+            Error::GpgconfMissing(_) => (GPG_ERR_NOT_FOUND, "Not found"),
+            Error::OversizedFrame { .. } => (GPG_ERR_LINE_TOO_LONG, "Line too long"),
+        }
+    }
+
+    /// The `ERR` line telling an Assuan client why its request can't be served.
+    pub fn assuan_reply(&self) -> String {
+        let (code, description) = self.gpg_error();
+        format!(
+            "ERR {} {} <GPG Agent>\n",
+            (GPG_ERR_SOURCE_GPGAGENT << 24) | code,
+            description
+        )
+    }
+
+    /// The message telling an ssh agent client its request failed, the protocol has no details.
+    pub fn ssh_reply(&self) -> &'static [u8] {
+        &SSH_AGENT_FAILURE
+    }
+
+    fn kind(&self) -> io::ErrorKind {
+        match self {
+            Error::AgentNotRunning(_) => io::ErrorKind::ConnectionRefused,
+            Error::PageantNotFound(_) | Error::GpgconfMissing(_) => io::ErrorKind::NotFound,
+            Error::SocketFileMalformed { .. } | Error::OversizedFrame { .. } => {
+                io::ErrorKind::InvalidData
+            }
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::AgentNotRunning(e) => write!(f, "gpg-agent is not running: {}", e),
+            Error::PageantNotFound(e) => write!(f, "Pageant is not running: {}", e),
+            Error::SocketFileMalformed { path, reason } => {
+                write!(f, "socket file {} is malformed: {}", path, reason)
+            }
+            Error::GpgconfMissing(e) => write!(f, "failed to run gpgconf: {}", e),
+            Error::OversizedFrame { len, max } => {
+                write!(f, "client sent {} bytes at once, more than {}", len, max)
+            }
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::AgentNotRunning(e) | Error::PageantNotFound(e) | Error::GpgconfMissing(e) => {
+                Some(e)
+            }
+            Error::SocketFileMalformed { .. } | Error::OversizedFrame { .. } => None,
+        }
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(e: Error) -> io::Error {
+        io::Error::new(e.kind(), e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An error that only tells its cause by `source`.
+    #[derive(Debug)]
+    struct Context(io::Error);
+
+    impl fmt::Display for Context {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str("context")
+        }
+    }
+
+    impl error::Error for Context {
+        fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+            Some(&self.0)
+        }
+    }
+
+    fn oversized() -> io::Error {
+        Error::OversizedFrame { len: 10, max: 5 }.into()
+    }
+
+    #[test]
+    fn test_find() {
+        let cases = [
+            (oversized(), true),
+            (io::Error::other(oversized()), true),
+            (io::Error::other(io::Error::other(oversized())), true),
+            (io::Error::other(Context(oversized())), true),
+            (
+                io::Error::other(Context(io::Error::other(oversized()))),
+                true,
+            ),
+            (io::Error::other("oversized"), false),
+            (io::Error::from(io::ErrorKind::InvalidData), false),
+            (
+                io::Error::other(Context(io::ErrorKind::InvalidData.into())),
+                false,
+            ),
+        ];
+        for (e, found) in cases {
+            let res = Error::find(&e);
+            assert_eq!(
+                res.is_some_and(|e| matches!(e, Error::OversizedFrame { len: 10, max: 5 })),
+                found,
+                "{:?}",
+                e
+            );
+        }
+    }
+
+    #[test]
+    fn test_replies() {
+        let e = Error::AgentNotRunning(io::ErrorKind::NotFound.into());
+        assert_eq!(
+            e.assuan_reply(),
+            format!("ERR {} No agent running <GPG Agent>\n", (4 << 24) | 77)
+        );
+        assert_eq!(e.ssh_reply(), [0, 0, 0, 1, 5]);
+        assert_eq!(io::Error::from(e).kind(), io::ErrorKind::ConnectionRefused);
+    }
+}
@@ -1,24 +1,88 @@
+pub mod acl;
+pub mod addr;
+pub mod auth;
 pub mod bridge;
+pub mod config;
+pub mod control;
+pub mod embed;
+pub mod error;
 pub mod listener;
+pub mod logging;
+pub mod metrics;
+pub mod mux;
+pub mod protocol;
+pub mod proxy;
+pub mod registry;
+pub mod secret;
+pub mod server;
 pub mod stream;
+#[cfg(unix)]
+pub mod systemd;
+#[cfg(feature = "tls")]
+pub mod tls;
 pub mod util;
 
-use std::io;
+pub use crate::embed::Bridge;
+pub use crate::error::Error;
 
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use std::{fmt, io};
+
+use serde::Deserialize;
+#[cfg(windows)]
 use tokio::net::windows::named_pipe::ServerOptions;
 use tokio::net::TcpListener;
 use tokio::process::Command;
+use tokio::sync::watch;
 
+use crate::acl::Acl;
+use crate::addr::{BackendAddr, ListenAddr};
+use crate::bridge::auto::bridge_auto;
+use crate::bridge::backend::bridge_to_backend;
 use crate::bridge::extra::bridge_to_stream;
+use crate::bridge::mux::bridge_mux;
+use crate::bridge::rendezvous::bridge_to_rendezvous;
+#[cfg(windows)]
 use crate::bridge::ssh::bridge_to_message;
+use crate::bridge::upstream::{bridge_to_upstream, Upstream};
+use crate::bridge::{Control, Timeouts};
+use crate::config::{BridgeConfig, TlsListenConfig};
+use crate::listener::dial::{DialListener, Dialer};
+#[cfg(windows)]
 use crate::listener::named_pipe::NamedPipeServerListener;
+use crate::listener::retry::RetryListener;
+use crate::listener::stdio::StdioListener;
+use crate::listener::tcp::AclListener;
+#[cfg(feature = "tls")]
+use crate::listener::tls::TlsListener;
+#[cfg(unix)]
+use crate::listener::unix::UnixSocketListener;
 use crate::listener::Listener;
-use crate::stream::SplitStream;
+use crate::mux::Transport;
+use crate::stream::stdio::Stdio;
+use crate::stream::{Activity, SplitStream};
+use crate::util::other_error;
+
+/// First wait before setting up a failed listener again, doubled up to [`MAX_RESTART_DELAY`]
+/// while it keeps failing.
+const MIN_RESTART_DELAY: Duration = Duration::from_secs(1);
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(60);
+/// A listener that worked for this long starts over from [`MIN_RESTART_DELAY`].
+const RESTART_RESET: Duration = Duration::from_secs(60);
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
 pub enum SocketType {
     Ssh,
     Extra,
+    /// The standard socket, which gives full access to gpg-agent.
+    Agent,
+    /// Channels of the other types multiplexed over a single connection, see [`mux`].
+    Mux,
+    /// Either ssh or extra, detected by the first bytes of each connection.
+    Auto,
 }
 
 impl SocketType {
@@ -26,76 +90,389 @@ impl SocketType {
         match self {
             SocketType::Ssh => "agent-ssh-socket",
             SocketType::Extra => "agent-extra-socket",
+            SocketType::Agent => "agent-socket",
+            SocketType::Mux => "mux",
+            SocketType::Auto => "auto",
         }
     }
 
     pub async fn try_get_path(&self) -> io::Result<String> {
+        if matches!(self, SocketType::Mux | SocketType::Auto) {
+            return Err(other_error(format!("{} has no gnupg socket", self)));
+        }
         let output = Command::new("gpgconf")
             .arg("--list-dir")
             .arg(self.name())
             .output()
-            .await?;
+            .await
+            .map_err(Error::GpgconfMissing)?;
         if !output.status.success() {
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
-                format!(
-                    "failed to load extra socket: {:?}",
-                    String::from_utf8_lossy(&output.stderr)
-                ),
-            ));
+            return Err(other_error(format!(
+                "failed to load {}: {:?}",
+                self.name(),
+                String::from_utf8_lossy(&output.stderr)
+            )));
         }
         Ok(String::from_utf8(output.stdout).unwrap().trim().to_owned())
     }
 }
+impl fmt::Display for SocketType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(match self {
+            SocketType::Ssh => "ssh",
+            SocketType::Extra => "extra",
+            SocketType::Agent => "agent",
+            SocketType::Mux => "mux",
+            SocketType::Auto => "auto",
+        })
+    }
+}
+
+impl FromStr for SocketType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ssh" => Ok(SocketType::Ssh),
+            "extra" => Ok(SocketType::Extra),
+            "agent" => Ok(SocketType::Agent),
+            "mux" => Ok(SocketType::Mux),
+            "auto" => Ok(SocketType::Auto),
+            _ => Err(format!(
+                "unknown socket type {}, expect ssh, extra, agent, mux or auto",
+                s
+            )),
+        }
+    }
+}
+
 pub async fn ping_gpg_agent() -> io::Result<()> {
+    metrics::BACKEND_RECONNECTS.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
     let output = Command::new("gpg-connect-agent")
         .arg("/bye")
         .output()
-        .await?;
+        .await
+        .map_err(Error::AgentNotRunning)?;
     if !output.status.success() {
-        return Err(io::Error::new(
-            io::ErrorKind::Other,
-            format!(
-                "failed to start gpg-agent: {:?}",
-                String::from_utf8_lossy(&output.stderr)
-            ),
-        ));
+        return Err(Error::AgentNotRunning(other_error(format!(
+            "failed to start gpg-agent: {:?}",
+            String::from_utf8_lossy(&output.stderr)
+        )))
+        .into());
     }
     Ok(())
 }
 
 /// A bridge that forwards all requests from certain stream to gpg-agent on Windows.
 ///
-/// `to_path` should point to the path of gnupg UDS. `from_addr` can be either TCP address
-/// or Named Pipe.
-// TODO: use trait to unify access.
-pub async fn bridge(ty: SocketType, from_addr: String, to_path: Option<String>) -> io::Result<()> {
-    // Attempt to setup gpg-agent if it's not up yet.
+/// `to_path` should point to the path of gnupg UDS, or be any [`BackendAddr`]. `from_addr` can be either TCP address,
+/// Named Pipe or an absolute path of Unix domain socket on Unix. Every accepted connection is closed once it exceeds `timeouts`.
+///
+/// It runs until it fails, use [`Bridge::builder`] to stop or watch it.
+pub async fn bridge(
+    ty: SocketType,
+    from_addr: String,
+    to_path: Option<String>,
+    timeouts: Timeouts,
+) -> io::Result<()> {
+    let mut builder = Bridge::builder()
+        .socket_type(ty)
+        .listen(from_addr)
+        .timeouts(timeouts);
+    if let Some(path) = to_path {
+        builder = builder.backend(path);
+    }
+    builder.start()?.join().await
+}
+
+/// Bridges a single connection carried over stdin and stdout, like an ssh `ProxyCommand`.
+///
+/// Only the type, `backend`, `idle_timeout` and `max_duration` of `config` are used, along with
+/// `upstream_token_file` and `upstream_tls` to connect to a `tcp://` backend. Returns once either
+/// the client or the agent closes the connection.
+pub async fn bridge_stdio(config: &BridgeConfig) -> io::Result<()> {
+    config.validate()?;
+    let ty = config.ty;
+    let timeouts = config.timeouts();
+    let backend = config.backend_addr()?;
+    let mut conn = Stdio::new();
+    let activity = Activity::new();
+    // Connected to like the upstream of a bridge, as it's another gpg-bridge.
+    if let Some(addr) = config.upstream_addr() {
+        let upstream = Upstream::new(addr, config)?;
+        let stream = upstream.connect().await?;
+        return bridge::upstream::delegate(ty, conn, stream, timeouts, &activity).await;
+    }
+    if let Some(backend) = backend.as_ref().filter(|b| b.is_relay()) {
+        return bridge::backend::relay(ty, conn, backend, timeouts, &activity).await;
+    }
     let _ = ping_gpg_agent().await;
-    // We can also try to guess ':'. But then we can distinguish between named pipe localhost and
-    // invalid tcp address localhost. Force check '\pipe\' can allow those address fail with clear
-    // error.
-    if from_addr.starts_with("\\\\.\\pipe\\") {
-        let server = ServerOptions::new()
-            .first_pipe_instance(true)
-            .create(&from_addr)?;
-        let listener = NamedPipeServerListener::new(server, from_addr);
-        bridge_listener(ty, listener, to_path).await?;
+    match ty {
+        SocketType::Extra | SocketType::Agent => {
+            let args = async {
+                let path = match backend {
+                    Some(BackendAddr::AssuanFile(path)) => path,
+                    _ => ty.try_get_path().await?,
+                };
+                bridge::extra::load_port_nounce(&path).await
+            };
+            match args.await {
+                Ok((port, nonce)) => {
+                    bridge::extra::delegate(conn, port, nonce, timeouts, &activity).await
+                }
+                Err(e) => {
+                    bridge::extra::reply_error(&mut conn, &e).await;
+                    Err(e)
+                }
+            }
+        }
+        #[cfg(windows)]
+        SocketType::Ssh => bridge::ssh::delegate_ssh(conn, timeouts, &activity).await,
+        #[cfg(not(windows))]
+        SocketType::Ssh => Err(other_error(
+            "ssh bridge requires Pageant, which is only available on Windows".to_owned(),
+        )),
+        SocketType::Mux | SocketType::Auto => Err(other_error(format!(
+            "{} can't be bridged over stdin and stdout",
+            ty
+        ))),
+    }
+}
+
+/// Runs the bridge described by `config` until `control` stops it and all its connections
+/// are finished.
+///
+/// Updates to `config` apply to connections accepted afterwards, except that the type, the
+/// listening address, TLS settings, dial and rendezvous settings and whether to forward to
+/// upstream or relay to the backend are only read at start.
+///
+/// Once the bridge has started accepting, a listener that fails is set up again after a
+/// backoff, while accepted connections keep being served. Failing to listen at first is
+/// returned right away.
+pub async fn serve(config: watch::Receiver<Arc<BridgeConfig>>, control: Control) -> io::Result<()> {
+    // Listener is always released when returning, even on failure.
+    let _closed = ClosedGuard(&control);
+    let mut delay = MIN_RESTART_DELAY;
+    loop {
+        let started = Instant::now();
+        let e = match serve_once(config.clone(), control.clone()).await {
+            Ok(()) => return Ok(()),
+            Err(e) if control.is_stopped() || !control.has_listened() => return Err(e),
+            Err(e) => e,
+        };
+        if started.elapsed() >= RESTART_RESET {
+            delay = MIN_RESTART_DELAY;
+        }
+        log::error!(
+            "bridge {} failed, restarting in {:?}: {}",
+            control.listener().name,
+            delay,
+            e
+        );
+        control.listener().report_error(&e);
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {}
+            _ = control.stopped() => {
+                control.drain().await;
+                return Ok(());
+            }
+        }
+        delay = (delay * 2).min(MAX_RESTART_DELAY);
+    }
+}
+
+// TODO: use trait to unify access.
+async fn serve_once(
+    config: watch::Receiver<Arc<BridgeConfig>>,
+    control: Control,
+) -> io::Result<()> {
+    let listen = config.borrow().listen_addr()?;
+    let (ty, name, tls, upstream) = {
+        let config = config.borrow();
+        (
+            config.ty,
+            config.name().to_owned(),
+            config.tls.clone(),
+            config.upstream.is_some() || config.rendezvous.is_some() || config.relays_backend(),
+        )
+    };
+    // Attempt to setup gpg-agent if it's not up yet. There may be no local agent when
+    // forwarding to upstream or another backend, and starting one may take over the socket to
+    // listen on.
+    if !upstream {
+        let _ = ping_gpg_agent().await;
+    }
+    // A socket passed by systemd under the name of the bridge replaces the listening address.
+    #[cfg(unix)]
+    if let Some(fd) = crate::systemd::listener(&name).transpose()? {
+        log::info!("bridge {} uses the inherited socket", name);
+        return serve_inherited(ty, fd, tls, config, control.clone()).await;
+    }
+    #[cfg(not(unix))]
+    let _ = name;
+    match listen {
+        ListenAddr::Tcp(addr) | ListenAddr::Tls(addr) => {
+            let listener = TcpListener::bind(&addr).await?;
+            serve_tcp(ty, listener, tls, config, control).await
+        }
+        #[cfg(unix)]
+        ListenAddr::Unix { path, mode, owner } => {
+            let listener = match (mode, owner) {
+                (None, None) => UnixSocketListener::bind(&path)?,
+                _ => UnixSocketListener::bind_private(&path, mode, owner)?,
+            };
+            bridge_listener(ty, listener, config, control).await
+        }
+        #[cfg(windows)]
+        ListenAddr::Pipe(path) => {
+            let server = ServerOptions::new()
+                .first_pipe_instance(true)
+                .create(&path)?;
+            let listener = NamedPipeServerListener::new(server, path);
+            bridge_listener(ty, listener, config, control).await
+        }
+        #[cfg(unix)]
+        ListenAddr::Fd(fd_name) => {
+            let fd = crate::systemd::listener(&fd_name)
+                .transpose()?
+                .ok_or_else(|| other_error(format!("no socket named {} is inherited", fd_name)))?;
+            serve_inherited(ty, fd, tls, config, control).await
+        }
+        ListenAddr::Stdio => {
+            let listener = StdioListener::new(control.clone());
+            bridge_listener(ty, listener, config, control).await
+        }
+        ListenAddr::Dial(addr) => {
+            let (dialer, pool) = {
+                let config = config.borrow();
+                let dialer = Dialer {
+                    addr,
+                    token_file: config.dial_token_file.clone(),
+                    #[cfg(feature = "tls")]
+                    tls: config
+                        .dial_tls
+                        .as_ref()
+                        .map(crate::tls::connector)
+                        .transpose()?,
+                };
+                (dialer, config.dial_pool.unwrap_or(4))
+            };
+            let listener = DialListener::new(dialer, pool);
+            bridge_listener(ty, listener, config, control).await
+        }
+        // Rejected when the configuration is validated.
+        addr => Err(other_error(format!(
+            "{} is not supported on this platform",
+            addr
+        ))),
+    }
+}
+
+/// Serves a listening socket passed by systemd, either a Unix or a TCP one.
+#[cfg(unix)]
+async fn serve_inherited(
+    ty: SocketType,
+    fd: std::os::fd::OwnedFd,
+    tls: Option<TlsListenConfig>,
+    config: watch::Receiver<Arc<BridgeConfig>>,
+    control: Control,
+) -> io::Result<()> {
+    if crate::systemd::is_unix(&fd)? {
+        let listener = std::os::unix::net::UnixListener::from(fd);
+        listener.set_nonblocking(true)?;
+        let listener = UnixSocketListener::inherit(tokio::net::UnixListener::from_std(listener)?);
+        bridge_listener(ty, listener, config, control).await
     } else {
-        let listener = TcpListener::bind(&from_addr).await?;
-        bridge_listener(ty, listener, to_path).await?;
+        let listener = std::net::TcpListener::from(fd);
+        listener.set_nonblocking(true)?;
+        let listener = TcpListener::from_std(listener)?;
+        serve_tcp(ty, listener, tls, config, control).await
     }
-    Ok(())
 }
 
-async fn bridge_listener<L>(ty: SocketType, listener: L, to_path: Option<String>) -> io::Result<()>
+async fn serve_tcp(
+    ty: SocketType,
+    listener: TcpListener,
+    tls: Option<TlsListenConfig>,
+    config: watch::Receiver<Arc<BridgeConfig>>,
+    control: Control,
+) -> io::Result<()> {
+    let acl = Acl::new(config.clone());
+    match tls {
+        #[cfg(feature = "tls")]
+        Some(tls) => {
+            let acceptor = crate::tls::acceptor(&tls)?;
+            let listener = TlsListener::new(listener, acceptor, acl);
+            bridge_listener(ty, listener, config, control).await
+        }
+        #[cfg(not(feature = "tls"))]
+        Some(_) => Err(other_error(
+            "gpg-bridge is built without the tls feature".to_owned(),
+        )),
+        None => bridge_listener(ty, AclListener::new(listener, acl), config, control).await,
+    }
+}
+
+struct ClosedGuard<'a>(&'a Control);
+
+impl Drop for ClosedGuard<'_> {
+    fn drop(&mut self) {
+        self.0.mark_closed();
+    }
+}
+
+async fn bridge_listener<L>(
+    ty: SocketType,
+    listener: L,
+    config: watch::Receiver<Arc<BridgeConfig>>,
+    control: Control,
+) -> io::Result<()>
+where
+    L: Listener + Send,
+    L::Connection: SplitStream + Transport,
+{
+    let listener = RetryListener::new(listener);
+    control.mark_listening();
+    if config.borrow().upstream_addr().is_some() {
+        return bridge_to_upstream(ty, listener, config, control).await;
+    }
+    if config.borrow().rendezvous.is_some() {
+        return bridge_to_rendezvous(ty, listener, config, control).await;
+    }
+    if ty == SocketType::Mux {
+        return bridge_mux(listener, config, control).await;
+    }
+    bridge_socket(ty, listener, config, control).await
+}
+
+/// Bridges connections of `listener` to the agent socket of type `ty`.
+async fn bridge_socket<L>(
+    ty: SocketType,
+    listener: L,
+    config: watch::Receiver<Arc<BridgeConfig>>,
+    control: Control,
+) -> io::Result<()>
 where
     L: Listener,
     L::Connection: SplitStream + Send + 'static,
 {
+    if config.borrow().relays_backend() {
+        return bridge_to_backend(ty, listener, config, control).await;
+    }
     match ty {
-        SocketType::Extra => bridge_to_stream(listener, to_path).await?,
-        SocketType::Ssh => bridge_to_message(listener).await?,
+        SocketType::Extra | SocketType::Agent => {
+            bridge_to_stream(ty, listener, config, control).await?
+        }
+        #[cfg(windows)]
+        SocketType::Ssh => bridge_to_message(listener, config, control).await?,
+        #[cfg(not(windows))]
+        SocketType::Ssh => {
+            return Err(other_error(
+                "ssh bridge requires Pageant, which is only available on Windows".to_owned(),
+            ))
+        }
+        SocketType::Auto => bridge_auto(listener, config, control).await?,
+        SocketType::Mux => return Err(other_error("mux can't be nested".to_owned())),
     }
     Ok(())
 }
@@ -1,13 +1,75 @@
+use std::fmt;
 use std::future::Future;
+use std::net::SocketAddr;
 use std::pin::Pin;
 
+pub mod channel;
+pub mod dial;
+#[cfg(windows)]
 pub mod named_pipe;
+pub mod retry;
+pub mod stdio;
 pub mod tcp;
+#[cfg(feature = "tls")]
+pub mod tls;
+#[cfg(unix)]
+pub mod unix;
+
+/// What is known about the client of an accepted connection, depending on the listener.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PeerInfo {
+    /// Address of a TCP client.
+    pub addr: Option<SocketAddr>,
+    /// Address of the relay that told `addr` by the PROXY protocol.
+    pub proxy: Option<SocketAddr>,
+    /// Process of a Unix socket or named pipe client.
+    pub pid: Option<u32>,
+    /// User of a Unix socket client.
+    pub uid: Option<u32>,
+}
+
+impl PeerInfo {
+    pub fn is_empty(&self) -> bool {
+        *self == PeerInfo::default()
+    }
+}
+
+impl fmt::Display for PeerInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut parts = vec![];
+        if let Some(addr) = self.addr {
+            parts.push(addr.to_string());
+        }
+        if let Some(proxy) = self.proxy {
+            parts.push(format!("via {}", proxy));
+        }
+        if let Some(pid) = self.pid {
+            parts.push(format!("pid {}", pid));
+        }
+        if let Some(uid) = self.uid {
+            parts.push(format!("uid {}", uid));
+        }
+        if parts.is_empty() {
+            return f.pad("-");
+        }
+        f.pad(&parts.join(" "))
+    }
+}
+
+impl From<SocketAddr> for PeerInfo {
+    fn from(addr: SocketAddr) -> Self {
+        PeerInfo {
+            addr: Some(addr),
+            ..Default::default()
+        }
+    }
+}
+
+/// Future returned by [`Listener::accept`].
+pub type Accept<'a, C> = Pin<Box<dyn Future<Output = std::io::Result<(C, PeerInfo)>> + Send + 'a>>;
 
 pub trait Listener {
     type Connection;
 
-    fn accept<'a>(
-        &'a mut self,
-    ) -> Pin<Box<dyn Future<Output = std::io::Result<Self::Connection>> + 'a>>;
+    fn accept(&mut self) -> Accept<'_, Self::Connection>;
 }
@@ -0,0 +1,30 @@
+use tokio::io::DuplexStream;
+use tokio::sync::mpsc;
+
+use super::{Accept, Listener, PeerInfo};
+use crate::util::other_error;
+
+/// Accepts channels of multiplexed connections, which are passed in by whoever demultiplexes
+/// them.
+pub struct ChannelListener {
+    channels: mpsc::UnboundedReceiver<(DuplexStream, PeerInfo)>,
+}
+
+impl ChannelListener {
+    pub fn new(channels: mpsc::UnboundedReceiver<(DuplexStream, PeerInfo)>) -> ChannelListener {
+        ChannelListener { channels }
+    }
+}
+
+impl Listener for ChannelListener {
+    type Connection = DuplexStream;
+
+    fn accept(&mut self) -> Accept<'_, Self::Connection> {
+        Box::pin(async move {
+            self.channels
+                .recv()
+                .await
+                .ok_or_else(|| other_error("no more channels to accept".to_owned()))
+        })
+    }
+}
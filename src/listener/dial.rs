@@ -0,0 +1,133 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+use futures::FutureExt as _;
+use log::{debug, info, warn};
+use tokio::io::AsyncReadExt as _;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
+
+use super::{Accept, Listener, PeerInfo};
+use crate::auth;
+use crate::mux::Transport;
+use crate::util::other_error;
+
+/// Sent by the rendezvous to hand a parked connection a client.
+pub const ACTIVATE: u8 = b'\n';
+/// Parked connections are replaced after this long, before NATs forget about them.
+const PARK_REFRESH: Duration = Duration::from_secs(240);
+/// The rendezvous only activates connections parked for less than this, so one is never handed
+/// a client while the dialer replaces it.
+pub const PARK_LIFETIME: Duration = Duration::from_secs(210);
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Dials out to a rendezvous and accepts the connections it hands a client, for hosts that
+/// can't be reached but can reach the other side.
+///
+/// `pool` connections are kept parked at the rendezvous, one is dialed again whenever another
+/// is accepted or lost. They are all closed when the listener is dropped.
+pub struct DialListener {
+    ready: mpsc::Receiver<(Box<dyn Transport>, PeerInfo)>,
+    _dialers: JoinSet<()>,
+}
+
+/// How to park a connection at a rendezvous.
+#[derive(Clone)]
+pub struct Dialer {
+    pub addr: String,
+    /// File of the token to send once connected.
+    pub token_file: Option<PathBuf>,
+    #[cfg(feature = "tls")]
+    pub tls: Option<tokio_rustls::TlsConnector>,
+}
+
+impl Dialer {
+    async fn connect(&self) -> std::io::Result<(Box<dyn Transport>, PeerInfo)> {
+        let conn = TcpStream::connect(&self.addr).await?;
+        let peer = PeerInfo::from(conn.peer_addr()?);
+        #[cfg(feature = "tls")]
+        let mut conn: Box<dyn Transport> = match &self.tls {
+            Some(connector) => Box::new(crate::tls::connect(connector, conn).await?),
+            None => Box::new(conn),
+        };
+        #[cfg(not(feature = "tls"))]
+        let mut conn: Box<dyn Transport> = Box::new(conn);
+        if let Some(path) = &self.token_file {
+            auth::send(&mut conn, path).await?;
+        }
+        Ok((conn, peer))
+    }
+}
+
+impl DialListener {
+    pub fn new(dialer: Dialer, pool: usize) -> DialListener {
+        info!("keeping {} connections parked at {}", pool, dialer.addr);
+        let (tx, ready) = mpsc::channel(1);
+        let mut dialers = JoinSet::new();
+        for _ in 0..pool.max(1) {
+            dialers.spawn(dial(dialer.clone(), tx.clone()));
+        }
+        DialListener {
+            ready,
+            _dialers: dialers,
+        }
+    }
+}
+
+/// Keeps one connection parked at the rendezvous and passes it on once it's activated.
+async fn dial(dialer: Dialer, ready: mpsc::Sender<(Box<dyn Transport>, PeerInfo)>) {
+    let addr = &dialer.addr;
+    let mut backoff = MIN_BACKOFF;
+    loop {
+        let res = dialer.connect();
+        let (mut conn, peer) = match res.await {
+            Ok(parked) => parked,
+            Err(e) => {
+                warn!("failed to dial {}, retrying in {:?}: {}", addr, backoff, e);
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+                continue;
+            }
+        };
+        let res = match tokio::time::timeout(PARK_REFRESH, conn.read_u8()).await {
+            Ok(res) => Some(res),
+            // Nothing may follow once the lifetime is over, but an activation already received
+            // must not be dropped.
+            Err(_) => conn.read_u8().now_or_never(),
+        };
+        match res {
+            Some(Ok(ACTIVATE)) => {
+                backoff = MIN_BACKOFF;
+                if ready.send((conn, peer)).await.is_err() {
+                    return;
+                }
+                continue;
+            }
+            None => {
+                debug!("refreshing parked connection to {}", addr);
+                backoff = MIN_BACKOFF;
+                continue;
+            }
+            Some(Ok(b)) => warn!("rendezvous {} sent unexpected byte {:#04x}", addr, b),
+            // Also when the rendezvous rejects the token.
+            Some(Err(e)) => debug!("parked connection to {} is lost: {}", addr, e),
+        }
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+impl Listener for DialListener {
+    type Connection = Box<dyn Transport>;
+
+    fn accept(&mut self) -> Accept<'_, Self::Connection> {
+        Box::pin(async move {
+            self.ready
+                .recv()
+                .await
+                .ok_or_else(|| other_error("all dialers are gone".to_owned()))
+        })
+    }
+}
@@ -1,9 +1,9 @@
-use std::pin::Pin;
-
-use std::future::Future;
+use std::os::windows::io::AsRawHandle;
 use tokio::net::windows::named_pipe::{NamedPipeServer, ServerOptions};
+use windows::Win32::Foundation::HANDLE;
+use windows::Win32::System::Pipes::GetNamedPipeClientProcessId;
 
-use super::Listener;
+use super::{Accept, Listener, PeerInfo};
 
 pub struct NamedPipeServerListener {
     server: NamedPipeServer,
@@ -19,13 +19,19 @@ impl NamedPipeServerListener {
 impl Listener for NamedPipeServerListener {
     type Connection = NamedPipeServer;
 
-    fn accept<'a>(
-        &'a mut self,
-    ) -> Pin<Box<dyn Future<Output = std::io::Result<Self::Connection>> + 'a>> {
+    fn accept(&mut self) -> Accept<'_, Self::Connection> {
         Box::pin(async move {
             self.server.connect().await?;
+            let mut pid = 0;
+            let found = unsafe {
+                GetNamedPipeClientProcessId(HANDLE(self.server.as_raw_handle() as isize), &mut pid)
+            };
+            let peer = PeerInfo {
+                pid: found.as_bool().then_some(pid),
+                ..Default::default()
+            };
             let server = ServerOptions::new().create(&self.addr)?;
-            Ok(std::mem::replace(&mut self.server, server))
+            Ok((std::mem::replace(&mut self.server, server), peer))
         })
     }
 }
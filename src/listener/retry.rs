@@ -0,0 +1,209 @@
+use std::io;
+use std::time::Duration;
+
+use log::warn;
+
+use super::{Accept, Listener};
+
+/// First wait after running out of resources, doubled up to [`MAX_DELAY`] while it lasts.
+const MIN_DELAY: Duration = Duration::from_millis(50);
+const MAX_DELAY: Duration = Duration::from_secs(2);
+
+/// How accepting should go on after an error.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorClass {
+    /// Only the connection being accepted is lost, the next one can be accepted right away.
+    Connection,
+    /// The process or the system is short of file descriptors, memory or pipe instances.
+    Resources,
+    /// The listener itself is broken.
+    Fatal,
+}
+
+// Network errors of the pending connection, which Linux reports from accept(2).
+#[cfg(unix)]
+const CONNECTION_ERRORS: &[i32] = &[
+    libc::EPROTO,
+    libc::EPERM,
+    libc::ENETDOWN,
+    libc::EHOSTUNREACH,
+];
+#[cfg(unix)]
+const RESOURCE_ERRORS: &[i32] = &[libc::EMFILE, libc::ENFILE, libc::ENOBUFS, libc::ENOMEM];
+
+// ERROR_NO_DATA, a client that closed the pipe before it was accepted.
+#[cfg(windows)]
+const CONNECTION_ERRORS: &[i32] = &[232];
+// ERROR_TOO_MANY_OPEN_FILES, ERROR_NOT_ENOUGH_MEMORY, ERROR_OUTOFMEMORY, ERROR_PIPE_BUSY,
+// ERROR_NO_SYSTEM_RESOURCES, WSAEMFILE and WSAENOBUFS.
+#[cfg(windows)]
+const RESOURCE_ERRORS: &[i32] = &[4, 8, 14, 231, 1450, 10024, 10055];
+
+pub fn classify(e: &io::Error) -> ErrorClass {
+    match e.kind() {
+        io::ErrorKind::ConnectionAborted
+        | io::ErrorKind::ConnectionReset
+        | io::ErrorKind::ConnectionRefused
+        | io::ErrorKind::Interrupted
+        | io::ErrorKind::WouldBlock => return ErrorClass::Connection,
+        io::ErrorKind::OutOfMemory => return ErrorClass::Resources,
+        _ => {}
+    }
+    match e.raw_os_error() {
+        Some(code) if CONNECTION_ERRORS.contains(&code) => ErrorClass::Connection,
+        Some(code) if RESOURCE_ERRORS.contains(&code) => ErrorClass::Resources,
+        _ => ErrorClass::Fatal,
+    }
+}
+
+/// Paces accepting again after errors that don't break the listener.
+pub struct Backoff {
+    delay: Duration,
+}
+
+impl Backoff {
+    pub fn new() -> Self {
+        Backoff { delay: MIN_DELAY }
+    }
+
+    /// Waits until accepting is worth another try after `e`, or returns it if it's fatal.
+    pub async fn retry(&mut self, e: io::Error) -> io::Result<()> {
+        match classify(&e) {
+            ErrorClass::Connection => {
+                warn!("failed to accept a connection: {}", e);
+                Ok(())
+            }
+            ErrorClass::Resources => {
+                let delay = self.next_delay();
+                warn!("failed to accept, retrying in {:?}: {}", delay, e);
+                tokio::time::sleep(delay).await;
+                Ok(())
+            }
+            ErrorClass::Fatal => Err(e),
+        }
+    }
+
+    /// Returns how long to wait now, the next wait is twice as long.
+    fn next_delay(&mut self) -> Duration {
+        let delay = self.delay;
+        self.delay = (self.delay * 2).min(MAX_DELAY);
+        delay
+    }
+
+    pub fn reset(&mut self) {
+        self.delay = MIN_DELAY;
+    }
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Keeps accepting through errors that only lose a connection or last while resources are
+/// short, only fatal ones are returned.
+pub struct RetryListener<L> {
+    inner: L,
+    backoff: Backoff,
+}
+
+impl<L> RetryListener<L> {
+    pub fn new(inner: L) -> Self {
+        RetryListener {
+            inner,
+            backoff: Backoff::new(),
+        }
+    }
+}
+
+impl<L> Listener for RetryListener<L>
+where
+    L: Listener + Send,
+    L::Connection: Send,
+{
+    type Connection = L::Connection;
+
+    fn accept(&mut self) -> Accept<'_, Self::Connection> {
+        Box::pin(async move {
+            loop {
+                match self.inner.accept().await {
+                    Ok(accepted) => {
+                        self.backoff.reset();
+                        return Ok(accepted);
+                    }
+                    Err(e) => self.backoff.retry(e).await?,
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classify() {
+        let cases = [
+            (
+                io::ErrorKind::ConnectionAborted.into(),
+                ErrorClass::Connection,
+            ),
+            (
+                io::ErrorKind::ConnectionReset.into(),
+                ErrorClass::Connection,
+            ),
+            (io::ErrorKind::Interrupted.into(), ErrorClass::Connection),
+            (io::ErrorKind::WouldBlock.into(), ErrorClass::Connection),
+            (io::ErrorKind::OutOfMemory.into(), ErrorClass::Resources),
+            (io::ErrorKind::InvalidInput.into(), ErrorClass::Fatal),
+            (io::ErrorKind::AddrInUse.into(), ErrorClass::Fatal),
+            (io::Error::other("closed"), ErrorClass::Fatal),
+        ];
+        for (e, class) in cases {
+            assert_eq!(classify(&e), class, "{:?}", e);
+        }
+        for code in CONNECTION_ERRORS {
+            let e = io::Error::from_raw_os_error(*code);
+            assert_eq!(classify(&e), ErrorClass::Connection, "{:?}", e);
+        }
+        for code in RESOURCE_ERRORS {
+            let e = io::Error::from_raw_os_error(*code);
+            assert_eq!(classify(&e), ErrorClass::Resources, "{:?}", e);
+        }
+    }
+
+    #[test]
+    fn test_backoff() {
+        let mut backoff = Backoff::new();
+        let delays: Vec<_> = (0..8).map(|_| backoff.next_delay()).collect();
+        assert_eq!(
+            delays,
+            [50, 100, 200, 400, 800, 1600, 2000, 2000].map(Duration::from_millis)
+        );
+        backoff.reset();
+        assert_eq!(backoff.next_delay(), MIN_DELAY);
+    }
+
+    #[tokio::test]
+    async fn test_retry() {
+        let mut backoff = Backoff::new();
+        backoff
+            .retry(io::ErrorKind::ConnectionReset.into())
+            .await
+            .unwrap();
+        // Lost connections don't slow down accepting.
+        assert_eq!(backoff.delay, MIN_DELAY);
+        backoff
+            .retry(io::ErrorKind::OutOfMemory.into())
+            .await
+            .unwrap();
+        assert_eq!(backoff.delay, MIN_DELAY * 2);
+        let e = backoff
+            .retry(io::ErrorKind::InvalidInput.into())
+            .await
+            .unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+    }
+}
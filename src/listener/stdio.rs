@@ -0,0 +1,92 @@
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::oneshot;
+
+use super::{Accept, Listener, PeerInfo};
+use crate::bridge::Control;
+use crate::stream::stdio::Stdio;
+use crate::stream::{PinAsyncRead, PinAsyncWrite, SplitStream};
+
+/// Stdin and stdout, which tells the listener when it's finished.
+pub struct StdioConnection {
+    stdio: Stdio,
+    _done: oneshot::Sender<()>,
+}
+
+impl SplitStream for StdioConnection {
+    #[inline]
+    fn split_rw(&mut self) -> (PinAsyncRead<'_>, PinAsyncWrite<'_>) {
+        self.stdio.split_rw()
+    }
+
+    fn supports_half_close(&self) -> bool {
+        self.stdio.supports_half_close()
+    }
+}
+
+impl AsyncRead for StdioConnection {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stdio).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for StdioConnection {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stdio).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stdio).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stdio).poll_shutdown(cx)
+    }
+}
+
+/// Accepts a single connection over stdin and stdout, and stops the bridge once it's finished.
+pub struct StdioListener {
+    conn: Option<StdioConnection>,
+    done: oneshot::Receiver<()>,
+    control: Control,
+}
+
+impl StdioListener {
+    pub fn new(control: Control) -> StdioListener {
+        let (tx, done) = oneshot::channel();
+        StdioListener {
+            conn: Some(StdioConnection {
+                stdio: Stdio::new(),
+                _done: tx,
+            }),
+            done,
+            control,
+        }
+    }
+}
+
+impl Listener for StdioListener {
+    type Connection = StdioConnection;
+
+    fn accept(&mut self) -> Accept<'_, Self::Connection> {
+        Box::pin(async move {
+            if let Some(conn) = self.conn.take() {
+                return Ok((conn, PeerInfo::default()));
+            }
+            let _ = (&mut self.done).await;
+            self.control.stop();
+            futures::future::pending().await
+        })
+    }
+}
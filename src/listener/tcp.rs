@@ -1,19 +1,84 @@
-use std::future::Future;
-use std::pin::Pin;
+use std::io;
+use std::sync::Arc;
 
 use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
 
-use super::Listener;
+use super::retry::Backoff;
+use super::{Accept, Listener, PeerInfo};
+use crate::acl::Acl;
+use crate::util::other_error;
 
 impl Listener for TcpListener {
     type Connection = TcpStream;
 
-    fn accept<'a>(
-        &'a mut self,
-    ) -> Pin<Box<dyn Future<Output = std::io::Result<Self::Connection>> + 'a>> {
+    fn accept(&mut self) -> Accept<'_, Self::Connection> {
         Box::pin(async move {
-            let (conn, _) = TcpListener::accept(self).await?;
-            Ok(conn)
+            let (conn, addr) = TcpListener::accept(self).await?;
+            Ok((conn, PeerInfo::from(addr)))
+        })
+    }
+}
+
+/// Drops connections from peers that are not permitted by `acl` right after accepting them,
+/// after reading the PROXY header of trusted relays.
+///
+/// Peers are admitted in the background, so a slow relay can't block others.
+pub struct AclListener {
+    connections: mpsc::Receiver<io::Result<(TcpStream, PeerInfo)>>,
+    task: JoinHandle<()>,
+}
+
+impl AclListener {
+    pub fn new(listener: TcpListener, acl: Acl) -> AclListener {
+        let acl = Arc::new(acl);
+        let (tx, connections) = mpsc::channel(16);
+        let task = tokio::spawn(async move {
+            let mut backoff = Backoff::new();
+            loop {
+                let (mut conn, addr) = match listener.accept().await {
+                    Ok(res) => {
+                        backoff.reset();
+                        res
+                    }
+                    Err(e) => {
+                        if let Err(e) = backoff.retry(e).await {
+                            let _ = tx.send(Err(e)).await;
+                            return;
+                        }
+                        continue;
+                    }
+                };
+                let acl = acl.clone();
+                let tx = tx.clone();
+                tokio::spawn(async move {
+                    if let Some(peer) = acl.admit(&mut conn, addr).await {
+                        let _ = tx.send(Ok((conn, peer))).await;
+                    }
+                });
+            }
+        });
+        AclListener { connections, task }
+    }
+}
+
+impl Drop for AclListener {
+    fn drop(&mut self) {
+        // Releases the address.
+        self.task.abort();
+    }
+}
+
+impl Listener for AclListener {
+    type Connection = TcpStream;
+
+    fn accept(&mut self) -> Accept<'_, Self::Connection> {
+        Box::pin(async move {
+            match self.connections.recv().await {
+                Some(res) => res,
+                None => Err(other_error("TCP listener stopped".to_owned())),
+            }
         })
     }
 }
@@ -0,0 +1,88 @@
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::warn;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+
+use super::retry::Backoff;
+use super::{Accept, Listener, PeerInfo};
+use crate::acl::Acl;
+use crate::util::other_error;
+
+/// Clients that don't finish the handshake in time are dropped.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Accepts TCP connections and yields them once the TLS handshake succeeds.
+///
+/// Handshakes run in the background, so a slow or malicious client can't block others.
+pub struct TlsListener {
+    connections: mpsc::Receiver<io::Result<(TlsStream<TcpStream>, PeerInfo)>>,
+    task: JoinHandle<()>,
+}
+
+impl TlsListener {
+    /// Peers not permitted by `acl` are dropped before the handshake.
+    pub fn new(listener: TcpListener, acceptor: TlsAcceptor, acl: Acl) -> TlsListener {
+        let acl = Arc::new(acl);
+        let (tx, connections) = mpsc::channel(16);
+        let task = tokio::spawn(async move {
+            let mut backoff = Backoff::new();
+            loop {
+                let (mut conn, addr) = match listener.accept().await {
+                    Ok(res) => {
+                        backoff.reset();
+                        res
+                    }
+                    Err(e) => {
+                        if let Err(e) = backoff.retry(e).await {
+                            let _ = tx.send(Err(e)).await;
+                            return;
+                        }
+                        continue;
+                    }
+                };
+                let acl = acl.clone();
+                let acceptor = acceptor.clone();
+                let tx = tx.clone();
+                tokio::spawn(async move {
+                    let Some(peer) = acl.admit(&mut conn, addr).await else {
+                        return;
+                    };
+                    match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(conn)).await {
+                        Ok(Ok(stream)) => {
+                            let _ = tx.send(Ok((stream, peer))).await;
+                        }
+                        Ok(Err(e)) => warn!("TLS handshake with {} failed: {}", peer, e),
+                        Err(_) => warn!("TLS handshake with {} timed out", peer),
+                    }
+                });
+            }
+        });
+        TlsListener { connections, task }
+    }
+}
+
+impl Drop for TlsListener {
+    fn drop(&mut self) {
+        // Releases the address.
+        self.task.abort();
+    }
+}
+
+impl Listener for TlsListener {
+    type Connection = TlsStream<TcpStream>;
+
+    fn accept(&mut self) -> Accept<'_, Self::Connection> {
+        Box::pin(async move {
+            match self.connections.recv().await {
+                Some(res) => res,
+                None => Err(other_error("TLS listener stopped".to_owned())),
+            }
+        })
+    }
+}
@@ -0,0 +1,150 @@
+use std::fs::DirBuilder;
+use std::io;
+use std::os::unix::fs::{DirBuilderExt as _, FileTypeExt as _, PermissionsExt as _};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::warn;
+use tokio::net::{UnixListener, UnixStream};
+
+use super::{Accept, Listener, PeerInfo};
+
+/// Listens on a Unix domain socket and removes the socket file when dropped.
+pub struct UnixSocketListener {
+    listener: UnixListener,
+    /// Not set for inherited sockets, whose file belongs to the parent process.
+    path: Option<PathBuf>,
+}
+
+impl UnixSocketListener {
+    pub fn bind(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        remove_stale(path)?;
+        let listener = UnixListener::bind(path)?;
+        Ok(Self {
+            listener,
+            path: Some(path.to_owned()),
+        })
+    }
+
+    /// Binds like [`bind`](Self::bind), but the socket file only appears at `path` once it has
+    /// `mode` and belongs to `owner`, if they are set.
+    ///
+    /// The socket is bound in a new private directory next to `path` and then moved into place,
+    /// so nobody can connect while it still has the permissions given by the umask.
+    pub fn bind_private(
+        path: impl AsRef<Path>,
+        mode: Option<u32>,
+        owner: Option<u32>,
+    ) -> io::Result<Self> {
+        let path = path.as_ref();
+        let parent = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        let Some(file_name) = path.file_name() else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is not a file path", path.display()),
+            ));
+        };
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .subsec_nanos();
+        let dir = parent.join(format!(
+            ".{}.{}.{}",
+            file_name.to_string_lossy(),
+            std::process::id(),
+            nanos
+        ));
+        // Fails if the directory exists, so it can't be one prepared by someone else.
+        DirBuilder::new().mode(0o700).create(&dir)?;
+        let staged = dir.join("socket");
+        let res = Self::bind(&staged).and_then(|mut listener| {
+            if let Some(mode) = mode {
+                std::fs::set_permissions(&staged, std::fs::Permissions::from_mode(mode))?;
+            }
+            if let Some(owner) = owner {
+                std::os::unix::fs::chown(&staged, Some(owner), None)?;
+            }
+            remove_stale(path)?;
+            std::fs::rename(&staged, path)?;
+            listener.path = Some(path.to_owned());
+            Ok(listener)
+        });
+        if let Err(e) = std::fs::remove_dir(&dir) {
+            warn!("failed to remove {}: {}", dir.display(), e);
+        }
+        res
+    }
+
+    /// Uses a socket passed by the parent process, which is left in place when dropped.
+    pub fn inherit(listener: UnixListener) -> Self {
+        Self {
+            listener,
+            path: None,
+        }
+    }
+}
+
+/// Makes way for a new socket at `path`, failing if it's taken.
+fn remove_stale(path: &Path) -> io::Result<()> {
+    let meta = match std::fs::symlink_metadata(path) {
+        Ok(meta) => meta,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    // Anything but a socket is most likely a mistyped path, leave it to the user.
+    if !meta.file_type().is_socket() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} exists and is not a socket", path.display()),
+        ));
+    }
+    match std::os::unix::net::UnixStream::connect(path) {
+        // A socket file left by a crashed process refuses connections, it's safe to replace.
+        Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+            warn!("removing stale socket {}", path.display());
+            std::fs::remove_file(path)
+        }
+        _ => Err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            format!("{} is in use", path.display()),
+        )),
+    }
+}
+
+impl Listener for UnixSocketListener {
+    type Connection = UnixStream;
+
+    fn accept(&mut self) -> Accept<'_, Self::Connection> {
+        Box::pin(async move {
+            let (conn, _) = self.listener.accept().await?;
+            // Unnamed peers have no address worth reporting, but their credentials are known.
+            let peer = match conn.peer_cred() {
+                Ok(cred) => PeerInfo {
+                    pid: cred.pid().map(|pid| pid as u32),
+                    uid: Some(cred.uid()),
+                    ..Default::default()
+                },
+                Err(e) => {
+                    warn!("failed to get peer credentials: {}", e);
+                    PeerInfo::default()
+                }
+            };
+            Ok((conn, peer))
+        })
+    }
+}
+
+impl Drop for UnixSocketListener {
+    fn drop(&mut self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        if let Err(e) = std::fs::remove_file(path) {
+            warn!("failed to remove socket {}: {}", path.display(), e);
+        }
+    }
+}
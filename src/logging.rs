@@ -0,0 +1,271 @@
+use std::fs::{self, File, OpenOptions};
+use std::future::Future;
+use std::io::{self, Write as _};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use log::{Log, Metadata, Record};
+use parking_lot::Mutex;
+use serde::Serialize;
+
+use crate::config::{LogConfig, LogFormat};
+use crate::util::other_error;
+
+const DEFAULT_MAX_SIZE: u64 = 10;
+const DEFAULT_MAX_FILES: usize = 5;
+
+tokio::task_local! {
+    static CONNECTION: u64;
+}
+
+/// Runs `task` with all its log lines tagged by the connection `id`.
+pub(crate) fn scope<F: Future>(id: u64, task: F) -> impl Future<Output = F::Output> {
+    CONNECTION.scope(id, task)
+}
+
+fn connection_id() -> Option<u64> {
+    CONNECTION.try_with(|id| *id).ok()
+}
+
+/// Installs the global logger described by `config`.
+///
+/// Logs always go to stderr, and additionally to a file and syslog if configured. The level is
+/// controlled by `RUST_LOG` as before.
+pub fn init(config: &LogConfig) -> io::Result<()> {
+    let filters = std::env::var("RUST_LOG").unwrap_or_default();
+    let filter = env_logger::filter::Builder::new().parse(&filters).build();
+    let stderr = match config.format {
+        LogFormat::Text => Some(
+            pretty_env_logger::formatted_builder()
+                .parse_filters(&filters)
+                .build(),
+        ),
+        LogFormat::Json => None,
+    };
+    let file = match &config.file {
+        Some(path) => Some(Mutex::new(RotatingFile::open(
+            path.clone(),
+            config.max_size.unwrap_or(DEFAULT_MAX_SIZE) * 1024 * 1024,
+            config.max_files.unwrap_or(DEFAULT_MAX_FILES),
+        )?)),
+        None => None,
+    };
+    #[cfg(unix)]
+    let syslog = if config.syslog {
+        Some(Syslog::connect()?)
+    } else {
+        None
+    };
+    #[cfg(not(unix))]
+    if config.syslog {
+        return Err(other_error("syslog is only supported on Unix".to_owned()));
+    }
+    crate::protocol::set_raw(config.trace_secrets);
+    log::set_max_level(filter.filter());
+    log::set_boxed_logger(Box::new(Logger {
+        filter,
+        format: config.format,
+        stderr,
+        file,
+        #[cfg(unix)]
+        syslog,
+    }))
+    .map_err(|e| other_error(e.to_string()))
+}
+
+struct Logger {
+    filter: env_logger::filter::Filter,
+    format: LogFormat,
+    /// Colored output for terminals, only used by text format.
+    stderr: Option<env_logger::Logger>,
+    file: Option<Mutex<RotatingFile>>,
+    #[cfg(unix)]
+    syslog: Option<Syslog>,
+}
+
+#[derive(Serialize)]
+struct JsonLine<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    time: Option<String>,
+    level: &'a str,
+    target: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    conn: Option<u64>,
+    message: String,
+}
+
+impl Logger {
+    fn line(&self, record: &Record, conn: Option<u64>, time: bool) -> String {
+        let time = time.then(|| humantime::format_rfc3339_millis(SystemTime::now()).to_string());
+        match self.format {
+            LogFormat::Text => {
+                let mut line = String::new();
+                if let Some(time) = time {
+                    line.push_str(&time);
+                    line.push(' ');
+                }
+                line.push_str(&format!("{:<5} {} > ", record.level(), record.target()));
+                if let Some(id) = conn {
+                    line.push_str(&format!("[conn {}] ", id));
+                }
+                line.push_str(&record.args().to_string());
+                line
+            }
+            LogFormat::Json => {
+                let line = JsonLine {
+                    time,
+                    level: record.level().as_str(),
+                    target: record.target(),
+                    conn,
+                    message: record.args().to_string(),
+                };
+                // Serializing strings and numbers can't fail.
+                serde_json::to_string(&line).unwrap()
+            }
+        }
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.filter.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.filter.matches(record) {
+            return;
+        }
+        let conn = connection_id();
+        if let Some(stderr) = &self.stderr {
+            match conn {
+                Some(id) => stderr.log(
+                    &Record::builder()
+                        .args(format_args!("[conn {}] {}", id, record.args()))
+                        .metadata(record.metadata().clone())
+                        .module_path(record.module_path())
+                        .file(record.file())
+                        .line(record.line())
+                        .build(),
+                ),
+                None => stderr.log(record),
+            }
+        }
+        if self.stderr.is_none() || self.file.is_some() {
+            let mut line = self.line(record, conn, true);
+            line.push('\n');
+            if self.stderr.is_none() {
+                let _ = io::stderr().lock().write_all(line.as_bytes());
+            }
+            if let Some(file) = &self.file {
+                let _ = file.lock().write_line(&line);
+            }
+        }
+        #[cfg(unix)]
+        if let Some(syslog) = &self.syslog {
+            syslog.send(record.level(), &self.line(record, conn, false));
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// A log file that is moved aside once it grows larger than `max_size`.
+///
+/// Rotated files are named `<path>.1` to `<path>.<max_files>`, the larger the older.
+struct RotatingFile {
+    path: PathBuf,
+    max_size: u64,
+    max_files: usize,
+    file: File,
+    size: u64,
+}
+
+impl RotatingFile {
+    fn open(path: PathBuf, max_size: u64, max_files: usize) -> io::Result<RotatingFile> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile {
+            path,
+            max_size,
+            max_files,
+            file,
+            size,
+        })
+    }
+
+    fn rotated(path: &Path, index: usize) -> PathBuf {
+        let mut name = path.as_os_str().to_owned();
+        name.push(format!(".{}", index));
+        PathBuf::from(name)
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        if self.size > 0 && self.size + line.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        self.file.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        if self.max_files > 0 {
+            let _ = fs::remove_file(Self::rotated(&self.path, self.max_files));
+            for index in (1..self.max_files).rev() {
+                let _ = fs::rename(
+                    Self::rotated(&self.path, index),
+                    Self::rotated(&self.path, index + 1),
+                );
+            }
+            fs::rename(&self.path, Self::rotated(&self.path, 1))?;
+        }
+        self.file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+/// Sends logs to the local syslog daemon, which is journald on most Linux distributions.
+#[cfg(unix)]
+struct Syslog {
+    socket: std::os::unix::net::UnixDatagram,
+}
+
+#[cfg(unix)]
+impl Syslog {
+    fn connect() -> io::Result<Syslog> {
+        use std::os::unix::net::UnixDatagram;
+
+        let socket = UnixDatagram::unbound()?;
+        // macOS doesn't have /dev/log.
+        socket
+            .connect("/dev/log")
+            .or_else(|_| socket.connect("/var/run/syslog"))
+            .map_err(|e| other_error(format!("failed to connect to syslog: {}", e)))?;
+        Ok(Syslog { socket })
+    }
+
+    fn send(&self, level: log::Level, message: &str) {
+        use log::Level;
+
+        const LOG_DAEMON: u8 = 3 << 3;
+        let severity = match level {
+            Level::Error => 3,
+            Level::Warn => 4,
+            Level::Info => 6,
+            Level::Debug | Level::Trace => 7,
+        };
+        // Syslog adds the time by itself.
+        let message = format!(
+            "<{}>gpg-bridge[{}]: {}",
+            LOG_DAEMON | severity,
+            std::process::id(),
+            message
+        );
+        let _ = self.socket.send(message.as_bytes());
+    }
+}
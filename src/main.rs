@@ -1,17 +1,185 @@
 mod cli;
 
-use std::{os::windows::process::CommandExt, process::Command};
+#[cfg(unix)]
+use std::os::unix::process::CommandExt;
+#[cfg(windows)]
+use std::os::windows::process::CommandExt;
+use std::process::{Command, ExitCode};
+use std::time::Duration;
 
 use clap::Parser as _;
-use gpg_bridge::{util::other_error, SocketType};
+use gpg_bridge::config::{BridgeConfig, Config, LogConfig, ReloadTrigger, TlsConnectConfig};
+use gpg_bridge::server::Server;
+#[cfg(unix)]
+use gpg_bridge::systemd;
+use gpg_bridge::util::other_error;
+use gpg_bridge::{control, logging, metrics, secret, SocketType};
+use tokio_util::sync::CancellationToken;
 
-#[tokio::main]
-async fn main() -> std::io::Result<()> {
-    pretty_env_logger::init();
+const DEFAULT_SHUTDOWN_TIMEOUT: u64 = 10;
+/// Exit code of failures without a more specific one.
+const EXIT_FAILURE: u8 = 1;
+/// Exit code when active connections have to be closed on shutdown.
+const EXIT_FORCED: u8 = 3;
+const EXIT_NO_AGENT: u8 = 4;
+const EXIT_NO_PAGEANT: u8 = 5;
+const EXIT_SOCKET_FILE: u8 = 6;
+const EXIT_NO_GPGCONF: u8 = 7;
+const EXIT_OVERSIZED: u8 = 8;
+
+fn exit_code(e: &std::io::Error) -> u8 {
+    match gpg_bridge::Error::find(e) {
+        Some(gpg_bridge::Error::AgentNotRunning(_)) => EXIT_NO_AGENT,
+        Some(gpg_bridge::Error::PageantNotFound(_)) => EXIT_NO_PAGEANT,
+        Some(gpg_bridge::Error::SocketFileMalformed { .. }) => EXIT_SOCKET_FILE,
+        Some(gpg_bridge::Error::GpgconfMissing(_)) => EXIT_NO_GPGCONF,
+        Some(gpg_bridge::Error::OversizedFrame { .. }) => EXIT_OVERSIZED,
+        _ => EXIT_FAILURE,
+    }
+}
+
+/// Collects bridges from both command line and configuration file.
+fn load_config(args: &cli::Args) -> std::io::Result<Config> {
+    let mut config = Config::default();
+    if let Some(addr) = &args.ssh {
+        config
+            .bridges
+            .push(BridgeConfig::new(SocketType::Ssh, addr.clone()));
+    }
+    if let Some(addr) = &args.extra {
+        let mut bridge = BridgeConfig::new(SocketType::Extra, addr.clone());
+        bridge.backend = args.extra_socket.clone();
+        config.bridges.push(bridge);
+    }
+    for bridge in &mut config.bridges {
+        bridge.idle_timeout = args.idle_timeout;
+        bridge.max_duration = args.max_duration;
+    }
+    if let Some(path) = &args.config {
+        let file = Config::load(path)?;
+        config.shutdown_timeout = file.shutdown_timeout;
+        config.control = file.control;
+        config.metrics = file.metrics;
+        config.log = file.log;
+        config.lock_memory = file.lock_memory;
+        config.bridges.extend(file.bridges);
+    }
+    if args.shutdown_timeout.is_some() {
+        config.shutdown_timeout = args.shutdown_timeout;
+    }
+    if args.control.is_some() {
+        config.control = args.control.clone();
+    }
+    if args.metrics.is_some() {
+        config.metrics = args.metrics.clone();
+    }
+    if let Some(format) = args.log_format {
+        config.log.format = format;
+    }
+    if args.log_file.is_some() {
+        config.log.file = args.log_file.clone();
+    }
+    config.log.syslog |= args.syslog;
+    config.log.trace_secrets |= args.trace_secrets;
+    config.lock_memory |= args.lock_memory;
+    config.validate()?;
+    Ok(config)
+}
+
+/// Resolves when the process is asked to terminate.
+async fn terminated() -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut terminate = signal(SignalKind::terminate())?;
+        tokio::select! {
+            res = tokio::signal::ctrl_c() => res,
+            _ = terminate.recv() => Ok(()),
+        }
+    }
+    #[cfg(windows)]
+    {
+        let mut close = tokio::signal::windows::ctrl_close()?;
+        tokio::select! {
+            res = tokio::signal::ctrl_c() => res,
+            _ = close.recv() => Ok(()),
+        }
+    }
+}
+
+fn main() -> ExitCode {
+    // No other thread is running yet, see the safety note.
+    #[cfg(unix)]
+    unsafe {
+        systemd::take_env()
+    };
     let args = cli::Args::parse();
+    let res = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .and_then(|runtime| runtime.block_on(run(args)));
+    match res {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            ExitCode::from(exit_code(&e))
+        }
+    }
+}
 
-    let ssh_bridge = args.ssh.map(|addr| (addr, None));
-    let extra_bridge = args.extra.map(|addr| (addr, args.extra_socket));
+async fn run(args: cli::Args) -> std::io::Result<ExitCode> {
+    if let Some(cli::Command::Stdio {
+        ty,
+        backend,
+        idle_timeout,
+        upstream_token_file,
+        upstream_cert,
+        upstream_key,
+        upstream_server_cert,
+    }) = &args.command
+    {
+        logging::init(&LogConfig::default())?;
+        let mut config = BridgeConfig::new(*ty, "stdio:".to_owned());
+        config.backend = backend.clone();
+        config.idle_timeout = *idle_timeout;
+        config.upstream_token_file = upstream_token_file.clone();
+        if let (Some(cert), Some(key), Some(server_cert)) =
+            (upstream_cert, upstream_key, upstream_server_cert)
+        {
+            config.upstream_tls = Some(TlsConnectConfig {
+                cert: cert.clone(),
+                key: key.clone(),
+                server_cert: server_cert.clone(),
+            });
+        }
+        let code = match gpg_bridge::bridge_stdio(&config).await {
+            Ok(()) => 0,
+            Err(e) => {
+                log::error!("failed to bridge stdio: {}", e);
+                exit_code(&e).into()
+            }
+        };
+        // A pending read of stdin would block the runtime from shutting down.
+        std::process::exit(code);
+    }
+    if let Some(cli::Command::Control(command)) = &args.command {
+        let Some(addr) = control::addr(args.control.as_deref()) else {
+            eprintln!("error: no control endpoint, set --control to the one of the bridge");
+            return Ok(ExitCode::FAILURE);
+        };
+        return match control::request(&addr, &command.request()).await {
+            Ok(output) => {
+                print!("{}", output);
+                Ok(ExitCode::SUCCESS)
+            }
+            Err(e) => {
+                eprintln!("error: {}", e);
+                Ok(ExitCode::FAILURE)
+            }
+        };
+    }
+    let config = load_config(&args)?;
 
     if args.detach {
         gpg_bridge::ping_gpg_agent().await?;
@@ -24,31 +192,151 @@ async fn main() -> std::io::Result<()> {
             }
         }
 
-        return cmd
-            .creation_flags(0x0000_0200 | 0x0000_0008 | 0x0400_0000)
-            .spawn()
-            .map(|_| ());
+        #[cfg(windows)]
+        cmd.creation_flags(0x0000_0200 | 0x0000_0008 | 0x0400_0000);
+        #[cfg(unix)]
+        cmd.process_group(0)
+            .stdin(std::process::Stdio::null())
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::null());
+        return cmd.spawn().map(|_| ExitCode::SUCCESS);
     }
 
-    let ssh_task = async move {
-        if let Some((addr, socket)) = ssh_bridge {
-            log::info!("ssh bridge start");
-            return gpg_bridge::bridge(SocketType::Ssh, addr, socket).await;
-        }
-        log::info!("ssh bridge disabled");
-        Ok(())
+    logging::init(&config.log)?;
+    #[cfg(unix)]
+    systemd::init();
+    secret::set_lock(config.lock_memory);
+    if config.bridges.is_empty() {
+        return Err(other_error("no bridge is configured".to_owned()));
+    }
+    let mut reload = match &args.config {
+        Some(path) => Some(ReloadTrigger::new(path)?),
+        None => None,
     };
-    let extra_task = async move {
-        if let Some((addr, socket)) = extra_bridge {
-            log::info!("extra bridge start");
-            return gpg_bridge::bridge(SocketType::Extra, addr, socket).await;
+    let mut shutdown_timeout = config.shutdown_timeout;
+    let mut server = Server::new();
+    server.apply(&config);
+    let stop = CancellationToken::new();
+    match control::addr(config.control.as_deref()) {
+        Some(control_addr) => {
+            let registry = server.registry().clone();
+            let control_stop = stop.clone();
+            tokio::spawn(async move {
+                if let Err(e) = control::serve(&control_addr, registry, control_stop).await {
+                    log::warn!("control endpoint {} is unavailable: {}", control_addr, e);
+                }
+            });
+        }
+        None => log::info!("control endpoint disabled"),
+    }
+    if let Some(metrics_addr) = config.metrics.clone() {
+        let registry = server.registry().clone();
+        tokio::spawn(async move {
+            if let Err(e) = metrics::serve(&metrics_addr, registry).await {
+                log::warn!("metrics endpoint {} is unavailable: {}", metrics_addr, e);
+            }
+        });
+    }
+    #[cfg(unix)]
+    {
+        tokio::spawn(systemd::watchdog());
+        systemd::notify("READY=1");
+    }
+    let terminated = terminated();
+    tokio::pin!(terminated);
+    let failure = loop {
+        let reloaded = async {
+            match &mut reload {
+                Some(trigger) => trigger.wait().await,
+                None => futures::future::pending().await,
+            }
+        };
+        tokio::select! {
+            e = server.failed() => break Some(e),
+            res = &mut terminated => {
+                res?;
+                break None;
+            }
+            _ = stop.cancelled() => break None,
+            _ = reloaded => {
+                log::info!("reloading configuration");
+                #[cfg(unix)]
+                systemd::notify_reloading();
+                match load_config(&args) {
+                    Ok(config) => {
+                        shutdown_timeout = config.shutdown_timeout;
+                        secret::set_lock(config.lock_memory);
+                        server.apply(&config);
+                    }
+                    Err(e) => log::error!("failed to reload configuration: {}", e),
+                }
+                #[cfg(unix)]
+                systemd::notify("READY=1");
+            }
         }
-        log::info!("extra bridge disabled");
-        Ok(())
     };
 
-    match tokio::try_join!(ssh_task, extra_task) {
-        Ok(_) => Ok(()),
-        Err(e) => Err(other_error(format!("failed to join tasks {:?}", e))),
+    log::info!("shutting down");
+    #[cfg(unix)]
+    systemd::notify("STOPPING=1");
+    let deadline = Duration::from_secs(shutdown_timeout.unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT));
+    let drained = server.shutdown(deadline).await;
+    if let Some(e) = failure {
+        return Err(e);
+    }
+    if drained {
+        log::info!("all connections finished, exiting");
+        Ok(ExitCode::SUCCESS)
+    } else {
+        log::warn!("exiting with connections closed forcibly");
+        Ok(ExitCode::from(EXIT_FORCED))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use gpg_bridge::Error;
+
+    use super::*;
+
+    #[test]
+    fn test_exit_code() {
+        let missing = || io::Error::from(io::ErrorKind::NotFound);
+        let cases = [
+            (Error::AgentNotRunning(missing()).into(), EXIT_NO_AGENT),
+            (Error::PageantNotFound(missing()).into(), EXIT_NO_PAGEANT),
+            (
+                Error::SocketFileMalformed {
+                    path: "S.gpg-agent.extra".to_owned(),
+                    reason: "too large".to_owned(),
+                }
+                .into(),
+                EXIT_SOCKET_FILE,
+            ),
+            (Error::GpgconfMissing(missing()).into(), EXIT_NO_GPGCONF),
+            (
+                Error::OversizedFrame { len: 10, max: 5 }.into(),
+                EXIT_OVERSIZED,
+            ),
+            // Wrapped by callers that add context.
+            (
+                io::Error::other(io::Error::from(Error::AgentNotRunning(missing()))),
+                EXIT_NO_AGENT,
+            ),
+            (
+                io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    io::Error::other(io::Error::from(Error::GpgconfMissing(missing()))),
+                ),
+                EXIT_NO_GPGCONF,
+            ),
+            (missing(), EXIT_FAILURE),
+            (io::Error::other("gpg-agent is not running"), EXIT_FAILURE),
+        ];
+        for (e, code) in cases {
+            assert_eq!(exit_code(&e), code, "{:?}", e);
+        }
     }
 }
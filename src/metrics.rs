@@ -0,0 +1,395 @@
+use std::collections::VecDeque;
+use std::fmt::Write as _;
+use std::io;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use log::{debug, info};
+use parking_lot::Mutex;
+use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::listener::retry::Backoff;
+use crate::registry::{ListenerState, Registry};
+use crate::stream::Direction;
+
+/// Upper bounds of sign latency buckets in seconds. Signing may wait for a passphrase or a
+/// smartcard touch, so it goes up to minutes.
+const SIGN_BUCKETS: [f64; 12] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 10.0, 30.0, 120.0,
+];
+
+/// Latency distribution in the Prometheus histogram model.
+pub struct Histogram {
+    /// Non-cumulative count of each bucket, the last one is `+Inf`.
+    buckets: [AtomicU64; SIGN_BUCKETS.len() + 1],
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    const fn new() -> Self {
+        #[allow(clippy::declare_interior_mutable_const)]
+        const ZERO: AtomicU64 = AtomicU64::new(0);
+        Histogram {
+            buckets: [ZERO; SIGN_BUCKETS.len() + 1],
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        let pos = SIGN_BUCKETS
+            .iter()
+            .position(|bound| secs <= *bound)
+            .unwrap_or(SIGN_BUCKETS.len());
+        self.buckets[pos].fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let mut count = 0;
+        for (pos, bucket) in self.buckets.iter().enumerate() {
+            count += bucket.load(Ordering::Relaxed);
+            let bound = SIGN_BUCKETS
+                .get(pos)
+                .map_or("+Inf".to_owned(), |b| b.to_string());
+            let _ = writeln!(
+                out,
+                "{}_bucket{{{},le=\"{}\"}} {}",
+                name, labels, bound, count
+            );
+        }
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1e6;
+        let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, sum);
+        let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, count);
+    }
+}
+
+/// Time taken by Pageant to answer ssh sign requests.
+pub static SSH_SIGN_LATENCY: Histogram = Histogram::new();
+/// Time taken by gpg-agent to answer `PKSIGN` on extra and agent sockets.
+pub static ASSUAN_SIGN_LATENCY: Histogram = Histogram::new();
+/// Times gpg-agent is pinged to be started again.
+pub static BACKEND_RECONNECTS: AtomicU64 = AtomicU64::new(0);
+/// Ssh connections waiting for a free Pageant slot.
+pub static SSH_SLOT_QUEUE: AtomicI64 = AtomicI64::new(0);
+/// Ssh connections holding a Pageant slot.
+pub static SSH_SLOTS_IN_USE: AtomicI64 = AtomicI64::new(0);
+
+/// Most commands an Assuan client may send ahead of their replies before timing gives up on
+/// the oldest ones.
+const MAX_PENDING_COMMANDS: usize = 1024;
+/// Longest verb kept of a line, longer ones are none the timer looks for.
+const MAX_VERB_LEN: usize = 8;
+
+/// Measures sign latency of an Assuan connection from the verbs of its lines.
+///
+/// Only the start of each line is kept, so data lines like passphrases are never buffered.
+#[derive(Default)]
+pub struct AssuanSignTimer {
+    state: Mutex<SignTimerState>,
+}
+
+#[derive(Default)]
+struct SignTimerState {
+    /// The line being read in each direction.
+    lines: [LineStart; 2],
+    /// Whether the greeting of the agent is seen, it doesn't answer any command.
+    greeted: bool,
+    /// Commands waiting for their final status, with the start of those that sign.
+    pending: VecDeque<Option<Instant>>,
+}
+
+/// The verb of a line, as far as it's read.
+#[derive(Default)]
+struct LineStart {
+    verb: [u8; MAX_VERB_LEN],
+    len: usize,
+    /// Set once the verb is complete, the rest of the line is skipped.
+    done: bool,
+}
+
+impl LineStart {
+    fn push(&mut self, data: &[u8]) {
+        for c in data {
+            if self.done {
+                return;
+            }
+            match c {
+                b' ' | b'\r' => self.done = true,
+                _ if self.len == MAX_VERB_LEN => self.done = true,
+                _ => {
+                    self.verb[self.len] = *c;
+                    self.len += 1;
+                }
+            }
+        }
+    }
+
+    fn verb(&self) -> &[u8] {
+        &self.verb[..self.len]
+    }
+}
+
+impl AssuanSignTimer {
+    pub fn observe(&self, direction: Direction, mut data: &[u8]) {
+        let mut state = self.state.lock();
+        let state = &mut *state;
+        loop {
+            let line = &mut state.lines[direction as usize];
+            let Some(end) = data.iter().position(|c| *c == b'\n') else {
+                line.push(data);
+                return;
+            };
+            line.push(&data[..end]);
+            let line = std::mem::take(line);
+            state.finish(direction, line.verb());
+            data = &data[end + 1..];
+        }
+    }
+}
+
+impl SignTimerState {
+    fn finish(&mut self, direction: Direction, verb: &[u8]) {
+        match direction {
+            // Empty lines and comments are ignored by the agent, data and the end of an inquiry
+            // answer it and aren't commands.
+            Direction::Request
+                if !(verb.is_empty()
+                    || verb.starts_with(b"#")
+                    || matches!(verb, b"D" | b"END" | b"CAN")) =>
+            {
+                if self.pending.len() == MAX_PENDING_COMMANDS {
+                    self.pending.pop_front();
+                }
+                self.pending
+                    .push_back((verb == b"PKSIGN").then(Instant::now));
+            }
+            // Inquiries and status lines may happen in between, only the final status ends
+            // the oldest command.
+            Direction::Reply if matches!(verb, b"OK" | b"ERR") => {
+                if !std::mem::replace(&mut self.greeted, true) {
+                    return;
+                }
+                if let Some(Some(started)) = self.pending.pop_front() {
+                    ASSUAN_SIGN_LATENCY.observe(started.elapsed());
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Renders all metrics in the Prometheus text exposition format.
+pub fn render(registry: &Registry) -> String {
+    let listeners = registry.listeners();
+    let connections = registry.connections();
+    let mut out = String::new();
+
+    let mut per_listener =
+        |name: &str, kind: &str, help: &str, value: &dyn Fn(&Arc<ListenerState>) -> u64| {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            for listener in &listeners {
+                let _ = writeln!(
+                    out,
+                    "{}{{listener=\"{}\",type=\"{}\"}} {}",
+                    name,
+                    escape(&listener.name),
+                    listener.ty,
+                    value(listener)
+                );
+            }
+        };
+    per_listener(
+        "gpg_bridge_connections_accepted_total",
+        "counter",
+        "Connections accepted by the listener.",
+        &|l| l.accepted(),
+    );
+    per_listener(
+        "gpg_bridge_connections_failed_total",
+        "counter",
+        "Connections that ended with an error.",
+        &|l| l.failed(),
+    );
+    per_listener(
+        "gpg_bridge_connections_active",
+        "gauge",
+        "Connections being served.",
+        &|l| {
+            connections
+                .iter()
+                .filter(|c| Arc::ptr_eq(&c.listener, l))
+                .count() as u64
+        },
+    );
+    per_listener(
+        "gpg_bridge_received_bytes_total",
+        "counter",
+        "Bytes received from clients.",
+        &|l| l.traffic().received(),
+    );
+    per_listener(
+        "gpg_bridge_replied_bytes_total",
+        "counter",
+        "Bytes replied to clients.",
+        &|l| l.traffic().replied(),
+    );
+
+    let name = "gpg_bridge_sign_duration_seconds";
+    let _ = writeln!(out, "# HELP {} Time taken by the agent to sign.", name);
+    let _ = writeln!(out, "# TYPE {} histogram", name);
+    SSH_SIGN_LATENCY.render(&mut out, name, "protocol=\"ssh\"");
+    ASSUAN_SIGN_LATENCY.render(&mut out, name, "protocol=\"assuan\"");
+
+    let mut single = |name: &str, kind: &str, help: &str, value: String| {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} {}", name, kind);
+        let _ = writeln!(out, "{} {}", name, value);
+    };
+    single(
+        "gpg_bridge_backend_reconnects_total",
+        "counter",
+        "Times gpg-agent is pinged to be started.",
+        BACKEND_RECONNECTS.load(Ordering::Relaxed).to_string(),
+    );
+    single(
+        "gpg_bridge_ssh_slot_queue_depth",
+        "gauge",
+        "Ssh connections waiting for a Pageant slot.",
+        SSH_SLOT_QUEUE.load(Ordering::Relaxed).to_string(),
+    );
+    single(
+        "gpg_bridge_ssh_slots_in_use",
+        "gauge",
+        "Ssh connections holding a Pageant slot.",
+        SSH_SLOTS_IN_USE.load(Ordering::Relaxed).to_string(),
+    );
+    out
+}
+
+/// Serves metrics over HTTP at `addr`.
+pub async fn serve(addr: &str, registry: Arc<Registry>) -> io::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    info!("metrics endpoint start at {}", addr);
+    let mut backoff = Backoff::new();
+    loop {
+        let conn = match listener.accept().await {
+            Ok((conn, _)) => {
+                backoff.reset();
+                conn
+            }
+            Err(e) => {
+                backoff.retry(e).await?;
+                continue;
+            }
+        };
+        let registry = registry.clone();
+        tokio::spawn(async move {
+            if let Err(e) = respond(conn, &registry).await {
+                debug!("failed to serve metrics: {:?}", e);
+            }
+        });
+    }
+}
+
+async fn respond(mut conn: TcpStream, registry: &Registry) -> io::Result<()> {
+    const MAX_HEAD_LEN: usize = 8192;
+
+    let mut head = Vec::with_capacity(1024);
+    let read_head = async {
+        while !head.windows(4).any(|w| w == b"\r\n\r\n") && head.len() < MAX_HEAD_LEN {
+            if conn.read_buf(&mut head).await? == 0 {
+                break;
+            }
+        }
+        Ok::<_, io::Error>(())
+    };
+    if tokio::time::timeout(Duration::from_secs(5), read_head)
+        .await
+        .is_err()
+    {
+        return Ok(());
+    }
+
+    let request_line = head.split(|c| *c == b'\r').next().unwrap_or_default();
+    let mut parts = request_line.split(|c| *c == b' ');
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some(b"GET"), Some(b"/metrics")) => ("200 OK", render(registry)),
+        (Some(b"GET"), _) => ("404 Not Found", "not found\n".to_owned()),
+        _ => ("405 Method Not Allowed", "method not allowed\n".to_owned()),
+    };
+    let resp = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    conn.write_all(resp.as_bytes()).await?;
+    conn.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signs() -> u64 {
+        ASSUAN_SIGN_LATENCY
+            .buckets
+            .iter()
+            .map(|b| b.load(Ordering::Relaxed))
+            .sum()
+    }
+
+    #[test]
+    fn test_sign_timer() {
+        let timer = AssuanSignTimer::default();
+        let before = signs();
+        timer.observe(Direction::Reply, b"OK Pleased to meet you\n");
+        timer.observe(Direction::Request, b"SIGKEY 0123\nPKS");
+        timer.observe(Direction::Request, b"IGN\n");
+        {
+            let state = timer.state.lock();
+            assert_eq!(state.pending.len(), 2);
+            assert!(state.pending[0].is_none());
+            assert!(state.pending[1].is_some());
+        }
+
+        // Data lines are skipped past their verb and never kept.
+        timer.observe(Direction::Reply, b"OK\nINQUIRE PASSPHRASE\n");
+        timer.observe(Direction::Request, b"D secret pass");
+        timer.observe(Direction::Request, b"phrase that goes on and on");
+        {
+            let state = timer.state.lock();
+            let line = &state.lines[Direction::Request as usize];
+            assert_eq!(line.verb(), b"D");
+            assert!(line.done);
+            assert_eq!(state.pending.len(), 1);
+        }
+        timer.observe(Direction::Request, b"\nEND\n");
+        timer.observe(Direction::Reply, b"S PROGRESS\nD (7:sig-val)\nO");
+        assert_eq!(signs(), before);
+        timer.observe(Direction::Reply, b"K\n");
+        assert!(signs() > before);
+        assert!(timer.state.lock().pending.is_empty());
+    }
+
+    #[test]
+    fn test_pending_limit() {
+        let timer = AssuanSignTimer::default();
+        for _ in 0..MAX_PENDING_COMMANDS + 10 {
+            timer.observe(Direction::Request, b"NOP\n");
+        }
+        assert_eq!(timer.state.lock().pending.len(), MAX_PENDING_COMMANDS);
+    }
+}
@@ -0,0 +1,646 @@
+//! Framing that carries connections of several socket types over a single stream, so one
+//! forwarded port serves ssh, extra and agent clients alike.
+//!
+//! The client starts with [`MAGIC`], then both sides exchange frames made of a 7 byte header,
+//! the channel id as u32, the kind as u8 and the payload length as u16, all big endian, and the
+//! payload. Only the client opens channels, naming the socket type in the payload of `Open`.
+//! Each side sends `Eof` when it has nothing more to write and `Close` once it's done with a
+//! channel, or to refuse one. A side may have at most [`CHANNEL_WINDOW`] bytes of a channel in
+//! flight, the receiver grants more with `Window` frames, whose payload is the number of bytes
+//! it has consumed as u32.
+
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::io;
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::debug;
+use parking_lot::Mutex;
+use tokio::io::{
+    AsyncRead, AsyncReadExt as _, AsyncWrite, AsyncWriteExt as _, DuplexStream, ReadHalf, WriteHalf,
+};
+use tokio::sync::{mpsc, Semaphore};
+use tokio_util::sync::CancellationToken;
+
+use crate::secret::SecretBuf;
+use crate::util::{other_error, report_data_err};
+use crate::{Error, SocketType};
+
+/// Sent by the client before any frame.
+pub const MAGIC: &[u8; 8] = b"GBMUX/2\n";
+/// Largest payload of a single frame.
+const MAX_PAYLOAD: usize = 16 * 1024;
+/// Frames queued for writing before channels wait for the stream.
+const OUT_BACKLOG: usize = 16;
+/// Bytes buffered between a channel and the connection bridged over it.
+const CHANNEL_BUFFER: usize = 64 * 1024;
+/// Bytes of a channel that may be sent before the receiver has consumed them.
+pub const CHANNEL_WINDOW: usize = 256 * 1024;
+/// Channels open at once on a session, further ones are refused.
+const MAX_CHANNELS: usize = 64;
+/// Clients that don't send [`MAGIC`] in time are dropped.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Gives up on an upstream that doesn't accept a session in time.
+const UPSTREAM_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A stream that can carry multiplexed channels.
+pub trait Transport: AsyncRead + AsyncWrite + Unpin + Send + 'static {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + 'static> Transport for T {}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Kind {
+    Open = 0,
+    Data = 1,
+    Eof = 2,
+    Close = 3,
+    Window = 4,
+}
+
+struct Frame {
+    id: u32,
+    kind: Kind,
+    payload: SecretBuf,
+}
+
+impl Frame {
+    fn new(id: u32, kind: Kind, payload: &[u8]) -> Frame {
+        Frame {
+            id,
+            kind,
+            payload: SecretBuf::from_slice(payload),
+        }
+    }
+}
+
+async fn read_frame(read: &mut (impl AsyncRead + Unpin)) -> io::Result<Frame> {
+    let mut head = [0; 7];
+    read.read_exact(&mut head).await?;
+    let id = u32::from_be_bytes([head[0], head[1], head[2], head[3]]);
+    let kind = match head[4] {
+        0 => Kind::Open,
+        1 => Kind::Data,
+        2 => Kind::Eof,
+        3 => Kind::Close,
+        4 => Kind::Window,
+        k => return Err(report_data_err(format!("unknown mux frame kind {}", k))),
+    };
+    let len = u16::from_be_bytes([head[5], head[6]]) as usize;
+    if len > MAX_PAYLOAD {
+        return Err(Error::OversizedFrame {
+            len,
+            max: MAX_PAYLOAD,
+        }
+        .into());
+    }
+    let mut payload = SecretBuf::new(len);
+    read.read_exact(&mut payload).await?;
+    Ok(Frame { id, kind, payload })
+}
+
+async fn write_frame(write: &mut (impl AsyncWrite + Unpin), frame: &Frame) -> io::Result<()> {
+    let mut head = [0; 7];
+    head[..4].copy_from_slice(&frame.id.to_be_bytes());
+    head[4] = frame.kind as u8;
+    head[5..].copy_from_slice(&(frame.payload.len() as u16).to_be_bytes());
+    write.write_all(&head).await?;
+    write.write_all(&frame.payload).await
+}
+
+/// Decides what to do with a channel opened by the client, returns false to refuse it.
+pub type Acceptor = Box<dyn Fn(SocketType, DuplexStream) -> bool + Send + Sync>;
+
+/// The session's end of an open channel.
+struct Channel {
+    /// Where to deliver data of the channel, `None` tells the peer sent `Eof`.
+    inbox: mpsc::UnboundedSender<Option<SecretBuf>>,
+    /// Bytes delivered to `inbox` that haven't been written to the connection yet.
+    queued: Arc<AtomicUsize>,
+    /// Bytes the channel may still send before the peer grants more, closed with the channel.
+    credit: Arc<Semaphore>,
+}
+
+/// What the pump of a channel works with.
+struct Pipe {
+    inbox: mpsc::UnboundedReceiver<Option<SecretBuf>>,
+    queued: Arc<AtomicUsize>,
+    credit: Arc<Semaphore>,
+}
+
+/// Either end of a multiplexed stream.
+///
+/// Every channel is handed out as one end of a [`DuplexStream`], whose other end is pumped to
+/// and from the stream in background.
+pub struct Session {
+    out: mpsc::Sender<Frame>,
+    channels: Mutex<HashMap<u32, Channel>>,
+    next_id: AtomicU32,
+    closed: CancellationToken,
+}
+
+impl Session {
+    fn start(transport: impl Transport, acceptor: Option<Acceptor>) -> Arc<Session> {
+        let (read, write) = tokio::io::split(transport);
+        let (out, queue) = mpsc::channel(OUT_BACKLOG);
+        let session = Arc::new(Session {
+            out,
+            channels: Mutex::new(HashMap::new()),
+            next_id: AtomicU32::new(0),
+            closed: CancellationToken::new(),
+        });
+        tokio::spawn(write_loop(write, queue, session.closed.clone()));
+        let reader = session.clone();
+        tokio::spawn(async move {
+            if let Err(e) = reader.read_loop(read, acceptor).await {
+                debug!("mux session ended: {}", e);
+            }
+            reader.close();
+        });
+        session
+    }
+
+    async fn read_loop(
+        self: &Arc<Self>,
+        mut read: ReadHalf<impl Transport>,
+        acceptor: Option<Acceptor>,
+    ) -> io::Result<()> {
+        loop {
+            let frame = tokio::select! {
+                res = read_frame(&mut read) => res?,
+                _ = self.closed.cancelled() => return Ok(()),
+            };
+            match frame.kind {
+                Kind::Open => {
+                    let ty = std::str::from_utf8(&frame.payload)
+                        .ok()
+                        .and_then(|t| t.parse().ok());
+                    let accepted = match (&acceptor, ty) {
+                        (Some(acceptor), Some(ty)) => match self.register(frame.id)? {
+                            Some(pipe) => {
+                                let (local, remote) = tokio::io::duplex(CHANNEL_BUFFER);
+                                if acceptor(ty, remote) {
+                                    self.pump(frame.id, local, pipe);
+                                    true
+                                } else {
+                                    self.remove(frame.id);
+                                    false
+                                }
+                            }
+                            None => {
+                                debug!("refused mux channel {}, too many open", frame.id);
+                                false
+                            }
+                        },
+                        _ => false,
+                    };
+                    if !accepted {
+                        let _ = self.out.send(Frame::new(frame.id, Kind::Close, &[])).await;
+                    }
+                }
+                Kind::Data => {
+                    if frame.payload.is_empty() {
+                        continue;
+                    }
+                    let channels = self.channels.lock();
+                    let Some(channel) = channels.get(&frame.id) else {
+                        continue;
+                    };
+                    let len = frame.payload.len();
+                    if channel.queued.load(Ordering::Acquire) + len > CHANNEL_WINDOW {
+                        return Err(report_data_err(format!(
+                            "mux channel {} sent more than its window",
+                            frame.id
+                        )));
+                    }
+                    channel.queued.fetch_add(len, Ordering::AcqRel);
+                    // The pump is gone once the connection stopped reading, which drops the data.
+                    let _ = channel.inbox.send(Some(frame.payload));
+                }
+                Kind::Eof => {
+                    if let Some(channel) = self.channels.lock().get(&frame.id) {
+                        let _ = channel.inbox.send(None);
+                    }
+                }
+                Kind::Window => {
+                    let Ok(bytes) = <[u8; 4]>::try_from(&frame.payload[..]) else {
+                        return Err(report_data_err("malformed mux window"));
+                    };
+                    let granted = u32::from_be_bytes(bytes) as usize;
+                    if let Some(channel) = self.channels.lock().get(&frame.id) {
+                        if channel.credit.available_permits() + granted > CHANNEL_WINDOW {
+                            return Err(report_data_err(format!(
+                                "mux channel {} was granted more than its window",
+                                frame.id
+                            )));
+                        }
+                        channel.credit.add_permits(granted);
+                    }
+                }
+                Kind::Close => self.remove(frame.id),
+            }
+        }
+    }
+
+    /// Adds a channel, `None` if there are too many already.
+    ///
+    /// Fails if a channel with the same id is still open, which the peer must not do.
+    fn register(&self, id: u32) -> io::Result<Option<Pipe>> {
+        let mut channels = self.channels.lock();
+        if channels.contains_key(&id) {
+            return Err(report_data_err(format!(
+                "mux channel {} is already open",
+                id
+            )));
+        }
+        if channels.len() >= MAX_CHANNELS {
+            return Ok(None);
+        }
+        let (inbox, rx) = mpsc::unbounded_channel();
+        let queued = Arc::new(AtomicUsize::new(0));
+        let credit = Arc::new(Semaphore::new(CHANNEL_WINDOW));
+        channels.insert(
+            id,
+            Channel {
+                inbox,
+                queued: queued.clone(),
+                credit: credit.clone(),
+            },
+        );
+        Ok(Some(Pipe {
+            inbox: rx,
+            queued,
+            credit,
+        }))
+    }
+
+    fn remove(&self, id: u32) {
+        if let Some(channel) = self.channels.lock().remove(&id) {
+            channel.credit.close();
+        }
+    }
+
+    /// Copies between the stream and `local` until both directions are finished.
+    fn pump(self: &Arc<Self>, id: u32, local: DuplexStream, pipe: Pipe) {
+        let session = self.clone();
+        tokio::spawn(async move {
+            let Pipe {
+                mut inbox,
+                queued,
+                credit,
+            } = pipe;
+            let (mut read, mut write) = tokio::io::split(local);
+            let upload = async {
+                let mut buf = SecretBuf::new(MAX_PAYLOAD);
+                loop {
+                    let n = read.read(&mut buf).await.unwrap_or(0);
+                    let frame = match n {
+                        0 => Frame::new(id, Kind::Eof, &[]),
+                        n => {
+                            // Closed once the peer or the session is done with the channel.
+                            match credit.acquire_many(n as u32).await {
+                                Ok(permit) => permit.forget(),
+                                Err(_) => return,
+                            }
+                            Frame::new(id, Kind::Data, &buf[..n])
+                        }
+                    };
+                    if session.out.send(frame).await.is_err() || n == 0 {
+                        return;
+                    }
+                }
+            };
+            let download = async {
+                while let Some(Some(data)) = inbox.recv().await {
+                    if write.write_all(&data).await.is_err() {
+                        return;
+                    }
+                    queued.fetch_sub(data.len(), Ordering::AcqRel);
+                    let granted = (data.len() as u32).to_be_bytes();
+                    let _ = session
+                        .out
+                        .send(Frame::new(id, Kind::Window, &granted))
+                        .await;
+                }
+                let _ = write.shutdown().await;
+            };
+            tokio::join!(upload, download);
+            session.remove(id);
+            let _ = session.out.send(Frame::new(id, Kind::Close, &[])).await;
+        });
+    }
+
+    /// Opens a channel to the socket of type `ty` on the other side.
+    pub async fn open(self: &Arc<Self>, ty: SocketType) -> io::Result<DuplexStream> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let Some(pipe) = self.register(id)? else {
+            return Err(other_error("too many open mux channels".to_owned()));
+        };
+        let (local, remote) = tokio::io::duplex(CHANNEL_BUFFER);
+        let open = Frame::new(id, Kind::Open, ty.to_string().as_bytes());
+        if self.closed.is_cancelled() || self.out.send(open).await.is_err() {
+            self.remove(id);
+            return Err(other_error("mux session is closed".to_owned()));
+        }
+        self.pump(id, local, pipe);
+        Ok(remote)
+    }
+
+    /// Stops the session, open channels see EOF.
+    pub fn close(&self) {
+        self.closed.cancel();
+        for (_, channel) in self.channels.lock().drain() {
+            channel.credit.close();
+        }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed.is_cancelled()
+    }
+
+    pub async fn closed(&self) {
+        self.closed.cancelled().await
+    }
+}
+
+async fn write_loop(
+    mut write: WriteHalf<impl Transport>,
+    mut queue: mpsc::Receiver<Frame>,
+    closed: CancellationToken,
+) {
+    let res = async {
+        while let Some(frame) = queue.recv().await {
+            write_frame(&mut write, &frame).await?;
+            // Flushes once the queue is drained, so bursts are written together.
+            while let Ok(frame) = queue.try_recv() {
+                write_frame(&mut write, &frame).await?;
+            }
+            write.flush().await?;
+        }
+        write.shutdown().await
+    };
+    tokio::select! {
+        res = res => {
+            if let Err(e) = res {
+                debug!("failed to write to mux session: {}", e);
+            }
+        }
+        _ = closed.cancelled() => {}
+    }
+    closed.cancel();
+}
+
+/// Starts serving a client that has connected, channels it opens are passed to `acceptor`.
+pub async fn accept(mut transport: impl Transport, acceptor: Acceptor) -> io::Result<Arc<Session>> {
+    let mut magic = [0; MAGIC.len()];
+    match tokio::time::timeout(HANDSHAKE_TIMEOUT, transport.read_exact(&mut magic)).await {
+        Ok(res) => res?,
+        Err(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "client didn't start mux in time",
+            ))
+        }
+    };
+    if &magic != MAGIC {
+        return Err(report_data_err("client doesn't speak mux"));
+    }
+    Ok(Session::start(transport, Some(acceptor)))
+}
+
+/// Starts a session over a connection to a mux bridge.
+pub async fn connect(mut transport: impl Transport) -> io::Result<Arc<Session>> {
+    transport.write_all(MAGIC).await?;
+    Ok(Session::start(transport, None))
+}
+
+/// Sessions to upstream mux bridges by address, shared by every bridge forwarding there.
+///
+/// A session is closed once nothing holds an [`UpstreamLease`] on its address anymore.
+#[derive(Default)]
+pub struct Upstreams {
+    slots: Mutex<BTreeMap<String, Arc<UpstreamSlot>>>,
+}
+
+#[derive(Default)]
+struct UpstreamSlot {
+    session: Mutex<Option<Arc<Session>>>,
+    /// Held while connecting, so channels opened meanwhile share the new session.
+    connecting: tokio::sync::Mutex<()>,
+}
+
+impl UpstreamSlot {
+    fn live(&self) -> Option<Arc<Session>> {
+        self.session.lock().clone().filter(|s| !s.is_closed())
+    }
+}
+
+impl Upstreams {
+    /// Keeps the session to `upstream` open until the lease is dropped.
+    pub fn lease(self: &Arc<Self>, upstream: &str) -> UpstreamLease {
+        let slot = self
+            .slots
+            .lock()
+            .entry(upstream.to_owned())
+            .or_default()
+            .clone();
+        UpstreamLease {
+            upstreams: self.clone(),
+            upstream: upstream.to_owned(),
+            slot,
+        }
+    }
+}
+
+/// Use of the session to an upstream by a bridge or one of its connections.
+pub struct UpstreamLease {
+    upstreams: Arc<Upstreams>,
+    upstream: String,
+    slot: Arc<UpstreamSlot>,
+}
+
+impl UpstreamLease {
+    pub fn upstream(&self) -> &str {
+        &self.upstream
+    }
+
+    /// Opens a channel to the socket of type `ty`, `connect` is called when there is no live
+    /// session to the upstream yet.
+    pub async fn open<F, Fut>(&self, ty: SocketType, connect: F) -> io::Result<DuplexStream>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = io::Result<Box<dyn Transport>>>,
+    {
+        let session = match self.slot.live() {
+            Some(session) => session,
+            None => {
+                let _connecting = self.slot.connecting.lock().await;
+                match self.slot.live() {
+                    Some(session) => session,
+                    None => {
+                        let start = async { self::connect(connect().await?).await };
+                        let session =
+                            match tokio::time::timeout(UPSTREAM_CONNECT_TIMEOUT, start).await {
+                                Ok(res) => res?,
+                                Err(_) => {
+                                    return Err(io::Error::new(
+                                        io::ErrorKind::TimedOut,
+                                        format!("connecting to {} timed out", self.upstream),
+                                    ))
+                                }
+                            };
+                        debug!("mux session to {} started", self.upstream);
+                        *self.slot.session.lock() = Some(session.clone());
+                        session
+                    }
+                }
+            }
+        };
+        session.open(ty).await
+    }
+}
+
+impl Clone for UpstreamLease {
+    fn clone(&self) -> Self {
+        self.upstreams.lease(&self.upstream)
+    }
+}
+
+impl Drop for UpstreamLease {
+    fn drop(&mut self) {
+        let mut slots = self.upstreams.slots.lock();
+        // Leases are only taken with the map locked, so nobody else can take one meanwhile.
+        if Arc::strong_count(&self.slot) > 2 {
+            return;
+        }
+        slots.remove(&self.upstream);
+        if let Some(session) = self.slot.session.lock().take() {
+            debug!("mux session to {} closed", self.upstream);
+            session.close();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Starts serving a client over a pipe, returns the client's end after the handshake.
+    async fn serve(acceptor: Acceptor) -> (DuplexStream, Arc<Session>) {
+        let (mut client, server) = tokio::io::duplex(1 << 20);
+        client.write_all(MAGIC).await.unwrap();
+        let session = accept(server, acceptor).await.unwrap();
+        (client, session)
+    }
+
+    /// Accepts every channel and keeps it open without reading from it.
+    fn keep_all() -> (Acceptor, Arc<Mutex<Vec<DuplexStream>>>) {
+        let kept = Arc::new(Mutex::new(vec![]));
+        let channels = kept.clone();
+        let acceptor: Acceptor = Box::new(move |_, channel| {
+            channels.lock().push(channel);
+            true
+        });
+        (acceptor, kept)
+    }
+
+    async fn send(client: &mut DuplexStream, id: u32, kind: Kind, payload: &[u8]) {
+        write_frame(client, &Frame::new(id, kind, payload))
+            .await
+            .unwrap();
+    }
+
+    async fn ended(session: &Session) -> bool {
+        tokio::time::timeout(Duration::from_secs(5), session.closed())
+            .await
+            .is_ok()
+    }
+
+    #[tokio::test]
+    async fn test_frames() {
+        let mut buf = vec![];
+        write_frame(&mut buf, &Frame::new(7, Kind::Window, &[0, 0, 1, 0]))
+            .await
+            .unwrap();
+        assert_eq!(buf, [0, 0, 0, 7, 4, 0, 4, 0, 0, 1, 0]);
+        let frame = read_frame(&mut &buf[..]).await.unwrap();
+        assert_eq!((frame.id, frame.kind), (7, Kind::Window));
+        assert_eq!(&frame.payload[..], [0, 0, 1, 0]);
+
+        let e = read_frame(&mut &[0, 0, 0, 1, 9, 0, 0][..])
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        let e = read_frame(&mut &[0, 0, 0, 1, 1, 0x40, 1][..])
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(
+            Error::find(&e),
+            Some(Error::OversizedFrame { len: 16385, .. })
+        ));
+        // Truncated payload.
+        assert!(read_frame(&mut &[0, 0, 0, 1, 1, 0, 2, 0][..])
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_refuse() {
+        let (mut client, session) = serve(Box::new(|_, _| false)).await;
+        send(&mut client, 3, Kind::Open, b"ssh").await;
+        send(&mut client, 4, Kind::Open, b"nonsense").await;
+        for id in [3, 4] {
+            let frame = read_frame(&mut client).await.unwrap();
+            assert_eq!((frame.id, frame.kind), (id, Kind::Close));
+        }
+        assert!(session.channels.lock().is_empty());
+        assert!(!session.is_closed());
+    }
+
+    #[tokio::test]
+    async fn test_max_channels() {
+        let (acceptor, kept) = keep_all();
+        let (mut client, session) = serve(acceptor).await;
+        for id in 0..=MAX_CHANNELS as u32 {
+            send(&mut client, id, Kind::Open, b"ssh").await;
+        }
+        let frame = read_frame(&mut client).await.unwrap();
+        assert_eq!((frame.id, frame.kind), (MAX_CHANNELS as u32, Kind::Close));
+        assert_eq!(kept.lock().len(), MAX_CHANNELS);
+        assert!(!session.is_closed());
+    }
+
+    #[tokio::test]
+    async fn test_reused_id() {
+        let (acceptor, _kept) = keep_all();
+        let (mut client, session) = serve(acceptor).await;
+        send(&mut client, 1, Kind::Open, b"ssh").await;
+        send(&mut client, 1, Kind::Open, b"extra").await;
+        assert!(ended(&session).await);
+    }
+
+    #[tokio::test]
+    async fn test_window() {
+        let (acceptor, _kept) = keep_all();
+        let (mut client, session) = serve(acceptor).await;
+        send(&mut client, 1, Kind::Open, b"ssh").await;
+        let data = vec![0; MAX_PAYLOAD];
+        for _ in 0..CHANNEL_WINDOW / MAX_PAYLOAD {
+            send(&mut client, 1, Kind::Data, &data).await;
+        }
+        // Data is granted back once it's passed to the connection.
+        let frame = read_frame(&mut client).await.unwrap();
+        assert_eq!((frame.id, frame.kind), (1, Kind::Window));
+        assert_eq!(&frame.payload[..], (MAX_PAYLOAD as u32).to_be_bytes());
+        assert!(!session.is_closed());
+
+        // The connection doesn't read, so only the channel buffer is passed on.
+        for _ in 0..(CHANNEL_BUFFER + MAX_PAYLOAD) / MAX_PAYLOAD {
+            send(&mut client, 1, Kind::Data, &data).await;
+        }
+        assert!(ended(&session).await);
+    }
+}
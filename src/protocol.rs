@@ -0,0 +1,405 @@
+//! Readable traces of the Assuan and ssh agent protocols.
+//!
+//! Payloads may carry passphrases, private keys and data to be signed, so secret-bearing fields
+//! are redacted unless raw dumps are enabled explicitly.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use log::{log_enabled, trace, Level};
+use parking_lot::Mutex;
+
+use crate::secret::{self, SecretBuf};
+use crate::stream::Direction;
+
+/// Assuan lines are at most 1000 bytes, only the start of longer lines is decoded.
+const MAX_LINE_LEN: usize = 1000;
+/// Longest part of a line shown in traces.
+const MAX_SHOWN_LEN: usize = 200;
+
+static RAW: AtomicBool = AtomicBool::new(false);
+
+/// Traces payloads as they are, including secrets.
+pub fn set_raw(raw: bool) {
+    RAW.store(raw, Ordering::Relaxed);
+}
+
+fn raw() -> bool {
+    RAW.load(Ordering::Relaxed)
+}
+
+fn dump(direction: Direction, data: &[u8]) {
+    trace!("{} {:?}", direction.tag(), String::from_utf8_lossy(data));
+}
+
+fn shorten(data: &[u8]) -> String {
+    if data.len() > MAX_SHOWN_LEN {
+        format!(
+            "{}... ({} bytes)",
+            String::from_utf8_lossy(&data[..MAX_SHOWN_LEN]),
+            data.len()
+        )
+    } else {
+        String::from_utf8_lossy(data).into_owned()
+    }
+}
+
+/// Traces the extra socket nonce sent to gpg-agent.
+pub fn trace_nonce(nonce: &[u8]) {
+    if !log_enabled!(Level::Trace) {
+        return;
+    }
+    if raw() {
+        dump(Direction::Request, nonce);
+    } else {
+        trace!("--> nonce [{} bytes redacted]", nonce.len());
+    }
+}
+
+/// The start of a line split over chunks. It is kept in a single secret buffer, which is wiped
+/// in place and locked in memory like other secrets.
+#[derive(Default)]
+struct PartialLine {
+    buf: Option<SecretBuf>,
+    len: usize,
+}
+
+impl PartialLine {
+    fn as_slice(&self) -> &[u8] {
+        self.buf.as_ref().map_or(&[], |buf| &buf[..self.len])
+    }
+
+    /// Appends as much of `data` as fits, returns whether all of it does.
+    fn push(&mut self, data: &[u8]) -> bool {
+        let buf = self.buf.get_or_insert_with(|| SecretBuf::new(MAX_LINE_LEN));
+        let count = data.len().min(MAX_LINE_LEN - self.len);
+        buf[self.len..self.len + count].copy_from_slice(&data[..count]);
+        self.len += count;
+        count == data.len()
+    }
+
+    fn clear(&mut self) {
+        if let Some(buf) = &mut self.buf {
+            secret::wipe(&mut buf[..self.len]);
+        }
+        self.len = 0;
+    }
+}
+
+#[derive(Default)]
+struct AssuanState {
+    /// Incomplete lines of requests and replies.
+    partial: [PartialLine; 2],
+    /// Whether the rest of a too long line is being skipped, it may be the middle of data.
+    skipping: [bool; 2],
+    /// The last command sent by client.
+    command: Vec<u8>,
+}
+
+/// Decodes Assuan lines from the traffic of a connection.
+#[derive(Default)]
+pub struct AssuanTracer {
+    state: Mutex<AssuanState>,
+}
+
+impl AssuanTracer {
+    pub fn observe(&self, direction: Direction, data: &[u8]) {
+        if !log_enabled!(Level::Trace) {
+            return;
+        }
+        if raw() {
+            dump(direction, data);
+            return;
+        }
+        self.decode(direction, data, |summary| {
+            trace!("{} {}", direction.tag(), summary)
+        });
+    }
+
+    /// Summarizes the lines completed by `data`, passing each summary to `emit`.
+    fn decode(&self, direction: Direction, data: &[u8], mut emit: impl FnMut(String)) {
+        let mut state = self.state.lock();
+        let state = &mut *state;
+        let mut data = data;
+        loop {
+            if state.skipping[direction as usize] {
+                let Some(pos) = data.iter().position(|c| *c == b'\n') else {
+                    return;
+                };
+                state.skipping[direction as usize] = false;
+                data = &data[pos + 1..];
+            }
+            let partial = &mut state.partial[direction as usize];
+            let Some(pos) = data.iter().position(|c| *c == b'\n') else {
+                if !partial.push(data) {
+                    emit(summarize_assuan(
+                        direction,
+                        partial.as_slice(),
+                        &mut state.command,
+                    ));
+                    emit("[rest of the line skipped]".to_owned());
+                    partial.clear();
+                    state.skipping[direction as usize] = true;
+                }
+                return;
+            };
+            let line = &data[..pos];
+            data = &data[pos + 1..];
+            // Lines within a chunk are decoded in place, only split ones are copied.
+            if partial.len == 0 {
+                emit(summarize_assuan(direction, line, &mut state.command));
+                continue;
+            }
+            let whole = partial.push(line);
+            emit(summarize_assuan(
+                direction,
+                partial.as_slice(),
+                &mut state.command,
+            ));
+            if !whole {
+                emit("[rest of the line skipped]".to_owned());
+            }
+            partial.clear();
+        }
+    }
+}
+
+fn split_word(line: &[u8]) -> (&[u8], &[u8]) {
+    match line.iter().position(|c| *c == b' ') {
+        Some(pos) => (&line[..pos], &line[pos + 1..]),
+        None => (line, &[]),
+    }
+}
+
+fn summarize_assuan(direction: Direction, line: &[u8], command: &mut Vec<u8>) -> String {
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+    let (verb, args) = split_word(line);
+    match (direction, verb) {
+        // Data lines carry passphrases, keys, signatures and everything else.
+        (_, b"D") => format!("D [{} bytes redacted]", args.len()),
+        (Direction::Request, b"PRESET_PASSPHRASE") => {
+            let (keygrip, _) = split_word(args);
+            format!(
+                "PRESET_PASSPHRASE {} [redacted]",
+                String::from_utf8_lossy(keygrip)
+            )
+        }
+        // Only a keygrip is expected, anything else is not shown.
+        (Direction::Request, b"SIGKEY" | b"SETKEY") => {
+            command.clear();
+            command.extend_from_slice(verb);
+            match split_word(args) {
+                (_, []) => shorten(line),
+                (keygrip, _) => format!(
+                    "{} {} [redacted]",
+                    String::from_utf8_lossy(verb),
+                    String::from_utf8_lossy(keygrip)
+                ),
+            }
+        }
+        (Direction::Request, b"SETHASH") => {
+            let options = args
+                .split(|c| *c == b' ')
+                .filter(|arg| arg.starts_with(b"--"))
+                .map(String::from_utf8_lossy)
+                .collect::<Vec<_>>();
+            format!("SETHASH {} [hash redacted]", options.join(" "))
+        }
+        (Direction::Reply, b"OK") if command == b"GET_PASSPHRASE" && !args.is_empty() => {
+            "OK [passphrase redacted]".to_owned()
+        }
+        (Direction::Request, _) => {
+            if !matches!(verb, b"END" | b"CAN") {
+                command.clear();
+                command.extend_from_slice(verb);
+            }
+            shorten(line)
+        }
+        (Direction::Reply, _) => shorten(line),
+    }
+}
+
+fn ssh_message_name(ty: u8) -> &'static str {
+    match ty {
+        5 => "SSH_AGENT_FAILURE",
+        6 => "SSH_AGENT_SUCCESS",
+        11 => "SSH_AGENTC_REQUEST_IDENTITIES",
+        12 => "SSH_AGENT_IDENTITIES_ANSWER",
+        13 => "SSH_AGENTC_SIGN_REQUEST",
+        14 => "SSH_AGENT_SIGN_RESPONSE",
+        17 => "SSH_AGENTC_ADD_IDENTITY",
+        18 => "SSH_AGENTC_REMOVE_IDENTITY",
+        19 => "SSH_AGENTC_REMOVE_ALL_IDENTITIES",
+        20 => "SSH_AGENTC_ADD_SMARTCARD_KEY",
+        21 => "SSH_AGENTC_REMOVE_SMARTCARD_KEY",
+        22 => "SSH_AGENTC_LOCK",
+        23 => "SSH_AGENTC_UNLOCK",
+        25 => "SSH_AGENTC_ADD_ID_CONSTRAINED",
+        26 => "SSH_AGENTC_ADD_SMARTCARD_KEY_CONSTRAINED",
+        27 => "SSH_AGENTC_EXTENSION",
+        28 => "SSH_AGENT_EXTENSION_FAILURE",
+        _ => "unknown",
+    }
+}
+
+/// Traces an ssh agent message, `msg` doesn't include the length prefix.
+pub fn trace_ssh(direction: Direction, msg: &[u8]) {
+    if !log_enabled!(Level::Trace) {
+        return;
+    }
+    if raw() {
+        dump(direction, msg);
+        return;
+    }
+    trace!("{} {}", direction.tag(), summarize_ssh(msg));
+}
+
+fn summarize_ssh(msg: &[u8]) -> String {
+    let Some((&ty, body)) = msg.split_first() else {
+        return "empty message".to_owned();
+    };
+    let details = match ty {
+        // Public keys and their comments are fine to show, only the count is useful though.
+        12 if body.len() >= 4 => format!(
+            ", {} keys",
+            u32::from_be_bytes([body[0], body[1], body[2], body[3]])
+        ),
+        // Requests that carry data to sign, private keys or passphrases.
+        13 | 14 | 17 | 20 | 22 | 23 | 25 | 26 => {
+            format!(", [{} bytes redacted]", body.len())
+        }
+        _ if body.is_empty() => String::new(),
+        _ => format!(", {} bytes", body.len()),
+    };
+    format!("{}({}){}", ssh_message_name(ty), ty, details)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(tracer: &AssuanTracer, direction: Direction, chunks: &[&[u8]]) -> Vec<String> {
+        let mut lines = vec![];
+        for chunk in chunks {
+            tracer.decode(direction, chunk, |summary| lines.push(summary));
+        }
+        lines
+    }
+
+    #[test]
+    fn test_redact_assuan() {
+        let cases: &[(&[&[u8]], &[&str])] = &[
+            (
+                &[b"D sec", b"ret pass", b"phrase\r\n"],
+                &["D [17 bytes redacted]"],
+            ),
+            (
+                &[b"PRESET_PASSPHRASE ABCD -1 736", b"563726574\n"],
+                &["PRESET_PASSPHRASE ABCD [redacted]"],
+            ),
+            (&[b"SETKEY AB", b"CD\n"], &["SETKEY ABCD"]),
+            (
+                &[b"SETKEY ABCD sec", b"ret\nSIGKEY ABCD secret\n"],
+                &["SETKEY ABCD [redacted]", "SIGKEY ABCD [redacted]"],
+            ),
+            (
+                &[b"SETHASH --hash=sha256 0123", b"4567\n"],
+                &["SETHASH --hash=sha256 [hash redacted]"],
+            ),
+            (
+                &[b"GETINFO version\nNOP", b"\n"],
+                &["GETINFO version", "NOP"],
+            ),
+        ];
+        for (chunks, expected) in cases {
+            let tracer = AssuanTracer::default();
+            assert_eq!(
+                decode(&tracer, Direction::Request, chunks),
+                *expected,
+                "{:?}",
+                chunks
+            );
+        }
+    }
+
+    #[test]
+    fn test_redact_passphrase_reply() {
+        let tracer = AssuanTracer::default();
+        decode(&tracer, Direction::Request, &[b"GET_PASSPHRASE X X X X\n"]);
+        assert_eq!(
+            decode(&tracer, Direction::Reply, &[b"OK 7365", b"6372\n"]),
+            ["OK [passphrase redacted]"]
+        );
+        decode(&tracer, Direction::Request, &[b"GETINFO pid\n"]);
+        assert_eq!(
+            decode(&tracer, Direction::Reply, &[b"OK 1234\n"]),
+            ["OK 1234"]
+        );
+    }
+
+    #[test]
+    fn test_skip_long_line() {
+        let tracer = AssuanTracer::default();
+        let long = [b"D ".as_slice(), &[b'x'; MAX_LINE_LEN]].concat();
+        assert_eq!(
+            decode(&tracer, Direction::Request, &[&long, b"secret"]),
+            [
+                format!("D [{} bytes redacted]", MAX_LINE_LEN - 2),
+                "[rest of the line skipped]".to_owned()
+            ]
+        );
+        // The middle of a line is never decoded as a line of its own.
+        assert_eq!(
+            decode(&tracer, Direction::Request, &[b"OK secret\nBY", b"E\n"]),
+            ["BYE"]
+        );
+        assert_eq!(tracer.state.lock().partial[0].len, 0);
+    }
+
+    #[test]
+    fn test_wipe_partial() {
+        let tracer = AssuanTracer::default();
+        assert_eq!(
+            decode(
+                &tracer,
+                Direction::Request,
+                &[b"D sec", b"ret\nD pa", b"ss"]
+            ),
+            ["D [6 bytes redacted]"]
+        );
+        let state = tracer.state.lock();
+        let partial = &state.partial[Direction::Request as usize];
+        assert_eq!(partial.as_slice(), b"D pass");
+        assert!(partial.buf.as_ref().unwrap()[partial.len..]
+            .iter()
+            .all(|c| *c == 0));
+    }
+
+    #[test]
+    fn test_summarize_ssh() {
+        let blob = [b"\x00\x00\x00\x07ssh-rsa".as_slice(), &[0x42; 64]].concat();
+        let cases: &[(Vec<u8>, &str)] = &[
+            (vec![], "empty message"),
+            (vec![11], "SSH_AGENTC_REQUEST_IDENTITIES(11)"),
+            (
+                vec![12, 0, 0, 0, 2],
+                "SSH_AGENT_IDENTITIES_ANSWER(12), 2 keys",
+            ),
+            (
+                [&[13], blob.as_slice()].concat(),
+                "SSH_AGENTC_SIGN_REQUEST(13), [75 bytes redacted]",
+            ),
+            (
+                [&[17], blob.as_slice()].concat(),
+                "SSH_AGENTC_ADD_IDENTITY(17), [75 bytes redacted]",
+            ),
+            (
+                [&[25], blob.as_slice()].concat(),
+                "SSH_AGENTC_ADD_ID_CONSTRAINED(25), [75 bytes redacted]",
+            ),
+            (vec![27, 1, 2], "SSH_AGENTC_EXTENSION(27), 2 bytes"),
+        ];
+        for (msg, expected) in cases {
+            assert_eq!(summarize_ssh(msg), *expected);
+        }
+    }
+}
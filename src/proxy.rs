@@ -0,0 +1,202 @@
+//! PROXY protocol headers sent by relays in front of TCP listeners.
+//!
+//! See <https://www.haproxy.org/download/2.8/doc/proxy-protocol.txt>.
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use tokio::io::{AsyncRead, AsyncReadExt as _};
+
+use crate::util::report_data_err;
+
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+/// Longest v1 header, including the trailing CRLF.
+const V1_MAX_LEN: usize = 107;
+
+/// Reads the header in front of the stream and returns the client address it carries.
+///
+/// `None` means the relay has no client address to tell, like for health checks.
+pub async fn read_header(conn: &mut (impl AsyncRead + Unpin)) -> io::Result<Option<SocketAddr>> {
+    // Both versions are longer than the v2 signature.
+    let mut buf = [0; 12];
+    conn.read_exact(&mut buf).await?;
+    if &buf == V2_SIGNATURE {
+        read_v2(conn).await
+    } else if buf.starts_with(b"PROXY ") {
+        let mut line = buf.to_vec();
+        // Reads byte by byte, so nothing after the header is consumed.
+        while !line.ends_with(b"\r\n") {
+            if line.len() == V1_MAX_LEN {
+                return Err(report_data_err("PROXY header is too long"));
+            }
+            line.push(conn.read_u8().await?);
+        }
+        parse_v1(&line[..line.len() - 2])
+    } else {
+        Err(report_data_err("missing PROXY header"))
+    }
+}
+
+fn parse_v1(line: &[u8]) -> io::Result<Option<SocketAddr>> {
+    let invalid = || report_data_err("invalid PROXY header");
+    let line = std::str::from_utf8(line).map_err(|_| invalid())?;
+    let fields: Vec<_> = line.split(' ').collect();
+    match fields[..] {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4" | "TCP6", src, _dst, sport, _dport] => {
+            let ip: IpAddr = src.parse().map_err(|_| invalid())?;
+            let port = sport.parse().map_err(|_| invalid())?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(invalid()),
+    }
+}
+
+async fn read_v2(conn: &mut (impl AsyncRead + Unpin)) -> io::Result<Option<SocketAddr>> {
+    let mut head = [0; 4];
+    conn.read_exact(&mut head).await?;
+    let (ver_cmd, family) = (head[0], head[1]);
+    let len = u16::from_be_bytes([head[2], head[3]]) as usize;
+    let mut body = vec![0; len];
+    conn.read_exact(&mut body).await?;
+    if ver_cmd >> 4 != 2 {
+        return Err(report_data_err("unsupported PROXY protocol version"));
+    }
+    match ver_cmd & 0xf {
+        // LOCAL, sent by the relay itself.
+        0 => return Ok(None),
+        1 => {}
+        _ => return Err(report_data_err("unsupported PROXY command")),
+    }
+    let too_short = || report_data_err("truncated PROXY header");
+    // Either TCP or UDP over IPv4 or IPv6, other families have no IP address.
+    match family {
+        0x11 | 0x12 => {
+            let addr = body.get(..12).ok_or_else(too_short)?;
+            let ip = Ipv4Addr::new(addr[0], addr[1], addr[2], addr[3]);
+            let port = u16::from_be_bytes([addr[8], addr[9]]);
+            Ok(Some(SocketAddr::new(ip.into(), port)))
+        }
+        0x21 | 0x22 => {
+            let addr = body.get(..36).ok_or_else(too_short)?;
+            let ip = Ipv6Addr::from(<[u8; 16]>::try_from(&addr[..16]).unwrap());
+            let port = u16::from_be_bytes([addr[32], addr[33]]);
+            Ok(Some(SocketAddr::new(ip.into(), port)))
+        }
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn read(mut data: &[u8]) -> (io::Result<Option<SocketAddr>>, &[u8]) {
+        let res = read_header(&mut data).await;
+        (res, data)
+    }
+
+    fn v2(ver_cmd: u8, family: u8, body: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[ver_cmd, family]);
+        header.extend_from_slice(&(body.len() as u16).to_be_bytes());
+        header.extend_from_slice(body);
+        header
+    }
+
+    #[tokio::test]
+    async fn test_v1() {
+        let (res, rest) = read(b"PROXY TCP4 192.0.2.1 192.0.2.2 51000 4321\r\nOK").await;
+        assert_eq!(res.unwrap(), Some("192.0.2.1:51000".parse().unwrap()));
+        assert_eq!(rest, b"OK");
+
+        let (res, _) = read(b"PROXY TCP6 2001:db8::1 2001:db8::2 51000 4321\r\n").await;
+        assert_eq!(res.unwrap(), Some("[2001:db8::1]:51000".parse().unwrap()));
+
+        let (res, rest) = read(b"PROXY UNKNOWN\r\nOK").await;
+        assert_eq!(res.unwrap(), None);
+        assert_eq!(rest, b"OK");
+    }
+
+    #[tokio::test]
+    async fn test_v1_invalid() {
+        for header in [
+            &b"PROXY TCP4 192.0.2.1 192.0.2.2 51000\r\n"[..],
+            b"PROXY TCP4 192.0.2.1 192.0.2.2 port 4321\r\n",
+            b"PROXY TCP4 not-an-ip 192.0.2.2 51000 4321\r\n",
+            b"PROXY UDP4 192.0.2.1 192.0.2.2 51000 4321\r\n",
+            b"PROXY TCP4 \xff\xfe 192.0.2.2 51000 4321\r\n",
+            b"GET / HTTP/1.1\r\n\r\n",
+        ] {
+            let (res, _) = read(header).await;
+            assert_eq!(
+                res.unwrap_err().kind(),
+                io::ErrorKind::InvalidData,
+                "{:?}",
+                String::from_utf8_lossy(header)
+            );
+        }
+
+        // Gives up without a CRLF instead of reading forever.
+        let mut header = b"PROXY UNKNOWN ".to_vec();
+        header.resize(1000, b'x');
+        let (res, rest) = read(&header).await;
+        assert_eq!(res.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(rest.len(), header.len() - V1_MAX_LEN);
+
+        let (res, _) = read(b"PROXY TCP4 192.0.2.1").await;
+        assert_eq!(res.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test]
+    async fn test_v2() {
+        let mut header = v2(
+            0x21,
+            0x11,
+            &[192, 0, 2, 1, 192, 0, 2, 2, 0xc7, 0x38, 0x10, 0xe1],
+        );
+        header.extend_from_slice(b"OK");
+        let (res, rest) = read(&header).await;
+        assert_eq!(res.unwrap(), Some("192.0.2.1:51000".parse().unwrap()));
+        assert_eq!(rest, b"OK");
+
+        let mut body = vec![0; 36];
+        body[..16].copy_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        body[32..34].copy_from_slice(&51000u16.to_be_bytes());
+        let (res, _) = read(&v2(0x21, 0x21, &body)).await;
+        assert_eq!(res.unwrap(), Some("[2001:db8::1]:51000".parse().unwrap()));
+
+        // TLVs after the addresses are skipped.
+        let mut body = vec![192, 0, 2, 1, 192, 0, 2, 2, 0xc7, 0x38, 0x10, 0xe1];
+        body.extend_from_slice(&[0x04, 0x00, 0x01, 0x00]);
+        let mut header = v2(0x21, 0x11, &body);
+        header.extend_from_slice(b"OK");
+        let (res, rest) = read(&header).await;
+        assert_eq!(res.unwrap(), Some("192.0.2.1:51000".parse().unwrap()));
+        assert_eq!(rest, b"OK");
+
+        // LOCAL and address families without an IP tell no client.
+        let (res, _) = read(&v2(0x20, 0x00, &[])).await;
+        assert_eq!(res.unwrap(), None);
+        let (res, _) = read(&v2(0x21, 0x31, &[0; 216])).await;
+        assert_eq!(res.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_v2_invalid() {
+        for header in [
+            v2(0x11, 0x11, &[0; 12]),
+            v2(0x22, 0x11, &[0; 12]),
+            v2(0x21, 0x11, &[0; 11]),
+            v2(0x21, 0x21, &[0; 12]),
+        ] {
+            let (res, _) = read(&header).await;
+            assert_eq!(res.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        }
+
+        let mut header = v2(0x21, 0x11, &[0; 12]);
+        header.truncate(20);
+        let (res, _) = read(&header).await;
+        assert_eq!(res.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
@@ -0,0 +1,259 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use log::debug;
+use parking_lot::Mutex;
+use tokio::sync::broadcast;
+use tokio_util::sync::CancellationToken;
+
+use crate::auth::TokenStore;
+use crate::config::BridgeConfig;
+use crate::listener::PeerInfo;
+use crate::mux::Upstreams;
+use crate::stream::{Activity, Traffic};
+use crate::SocketType;
+
+/// What a listener knows about the agent it forwards to.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BackendStatus {
+    /// Path of the gnupg socket file.
+    pub path: Option<String>,
+    /// Port loaded from the socket file.
+    pub port: Option<u16>,
+    /// Whether the nonce is loaded from the socket file.
+    pub nonce_loaded: bool,
+}
+
+/// Events kept for subscribers that fall behind, older ones are dropped.
+const EVENT_BACKLOG: usize = 256;
+
+/// Something that happened to a listener or one of its connections.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Event {
+    Connected {
+        id: u64,
+        listener: String,
+        peer: PeerInfo,
+    },
+    Disconnected {
+        id: u64,
+        listener: String,
+        received: u64,
+        replied: u64,
+    },
+    /// A connection failed, or the bridge stopped listening because of an error.
+    Error { listener: String, message: String },
+}
+
+/// Events of all listeners of a [`Registry`], in the order they happened.
+pub struct Events(broadcast::Receiver<Event>);
+
+impl Events {
+    /// Waits for the next event, `None` once the registry is gone.
+    ///
+    /// Events missed by falling more than [`EVENT_BACKLOG`] behind are skipped.
+    pub async fn next(&mut self) -> Option<Event> {
+        loop {
+            match self.0.recv().await {
+                Ok(event) => return Some(event),
+                Err(broadcast::error::RecvError::Lagged(n)) => debug!("skipped {} events", n),
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    }
+}
+
+pub struct ListenerState {
+    pub name: String,
+    pub ty: SocketType,
+    pub listen: String,
+    backend: Mutex<BackendStatus>,
+    accepted: AtomicU64,
+    failed: AtomicU64,
+    traffic: Arc<Traffic>,
+    events: broadcast::Sender<Event>,
+}
+
+impl ListenerState {
+    /// Number of accepted connections.
+    pub fn accepted(&self) -> u64 {
+        self.accepted.load(Ordering::Relaxed)
+    }
+
+    /// Number of connections that ended with an error.
+    pub fn failed(&self) -> u64 {
+        self.failed.load(Ordering::Relaxed)
+    }
+
+    /// Bytes transferred by all connections of the listener.
+    pub fn traffic(&self) -> &Traffic {
+        &self.traffic
+    }
+
+    pub(crate) fn record_failure(&self, error: &io::Error) {
+        self.failed.fetch_add(1, Ordering::Relaxed);
+        self.report_error(error);
+    }
+
+    /// Tells subscribers about an error without counting a failed connection.
+    pub(crate) fn report_error(&self, error: &io::Error) {
+        let _ = self.events.send(Event::Error {
+            listener: self.name.clone(),
+            message: error.to_string(),
+        });
+    }
+
+    pub fn backend(&self) -> BackendStatus {
+        self.backend.lock().clone()
+    }
+
+    pub(crate) fn set_backend(&self, status: BackendStatus) {
+        *self.backend.lock() = status;
+    }
+}
+
+pub struct ConnectionState {
+    pub id: u64,
+    /// The listener that accepted the connection.
+    pub listener: Arc<ListenerState>,
+    pub peer: PeerInfo,
+    pub activity: Activity,
+    kill: CancellationToken,
+}
+
+impl ConnectionState {
+    pub(crate) async fn killed(&self) {
+        self.kill.cancelled().await
+    }
+}
+
+/// Live state of all bridges and their connections.
+pub struct Registry {
+    next_id: AtomicU64,
+    listeners: Mutex<BTreeMap<String, Arc<ListenerState>>>,
+    connections: Mutex<BTreeMap<u64, Arc<ConnectionState>>>,
+    tokens: Arc<TokenStore>,
+    upstreams: Arc<Upstreams>,
+    events: broadcast::Sender<Event>,
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        Registry {
+            next_id: AtomicU64::new(0),
+            listeners: Mutex::default(),
+            connections: Mutex::default(),
+            tokens: Arc::default(),
+            upstreams: Arc::default(),
+            events: broadcast::channel(EVENT_BACKLOG).0,
+        }
+    }
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Receives events that happen from now on.
+    pub fn subscribe(&self) -> Events {
+        Events(self.events.subscribe())
+    }
+
+    pub fn add_listener(&self, config: &BridgeConfig) -> Arc<ListenerState> {
+        let state = Arc::new(ListenerState {
+            name: config.name().to_owned(),
+            ty: config.ty,
+            listen: config.listen.clone(),
+            backend: Mutex::new(BackendStatus {
+                path: config.backend.clone(),
+                ..Default::default()
+            }),
+            accepted: AtomicU64::new(0),
+            failed: AtomicU64::new(0),
+            traffic: Arc::new(Traffic::default()),
+            events: self.events.clone(),
+        });
+        self.listeners
+            .lock()
+            .insert(state.name.clone(), state.clone());
+        state
+    }
+
+    /// Removes the listener, unless it has been replaced by a new one with the same name.
+    pub fn remove_listener(&self, state: &Arc<ListenerState>) {
+        let mut listeners = self.listeners.lock();
+        if listeners
+            .get(&state.name)
+            .is_some_and(|l| Arc::ptr_eq(l, state))
+        {
+            listeners.remove(&state.name);
+        }
+    }
+
+    pub fn listeners(&self) -> Vec<Arc<ListenerState>> {
+        self.listeners.lock().values().cloned().collect()
+    }
+
+    pub fn connections(&self) -> Vec<Arc<ConnectionState>> {
+        self.connections.lock().values().cloned().collect()
+    }
+
+    /// Tokens issued for listeners, they outlive reloads but not the process.
+    pub fn tokens(&self) -> &Arc<TokenStore> {
+        &self.tokens
+    }
+
+    /// Sessions to upstream mux bridges, shared by all bridges forwarding to the same address.
+    pub(crate) fn upstreams(&self) -> &Arc<Upstreams> {
+        &self.upstreams
+    }
+
+    pub(crate) fn register(
+        &self,
+        listener: &Arc<ListenerState>,
+        peer: PeerInfo,
+    ) -> Arc<ConnectionState> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        listener.accepted.fetch_add(1, Ordering::Relaxed);
+        let state = Arc::new(ConnectionState {
+            id,
+            listener: listener.clone(),
+            peer,
+            activity: Activity::with_totals(listener.traffic.clone()),
+            kill: CancellationToken::new(),
+        });
+        self.connections.lock().insert(id, state.clone());
+        let _ = self.events.send(Event::Connected {
+            id,
+            listener: listener.name.clone(),
+            peer: state.peer.clone(),
+        });
+        state
+    }
+
+    pub(crate) fn unregister(&self, id: u64) {
+        let Some(state) = self.connections.lock().remove(&id) else {
+            return;
+        };
+        let _ = self.events.send(Event::Disconnected {
+            id,
+            listener: state.listener.name.clone(),
+            received: state.activity.received(),
+            replied: state.activity.replied(),
+        });
+    }
+
+    /// Closes the connection with the given id, returns false if there is no such connection.
+    pub fn kill(&self, id: u64) -> bool {
+        match self.connections.lock().get(&id) {
+            Some(conn) => {
+                conn.kill.cancel();
+                true
+            }
+            None => false,
+        }
+    }
+}
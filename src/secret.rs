@@ -0,0 +1,159 @@
+//! Buffers for data that may contain secrets, like nonces, passphrases and keys.
+
+use std::alloc::{self, Layout};
+use std::ops::{Deref, DerefMut};
+use std::ptr::{self, NonNull};
+use std::sync::atomic::{self, AtomicBool, Ordering};
+use std::{fmt, slice};
+
+use log::warn;
+
+static LOCK: AtomicBool = AtomicBool::new(false);
+static LOCK_FAILED: AtomicBool = AtomicBool::new(false);
+
+/// Locks buffers allocated afterwards in memory, so they are never swapped to disk.
+pub fn set_lock(lock: bool) {
+    LOCK.store(lock, Ordering::Relaxed);
+}
+
+/// Overwrites `buf` with zeros in a way that is not optimized out.
+pub fn wipe(buf: &mut [u8]) {
+    for b in buf {
+        unsafe { ptr::write_volatile(b, 0) };
+    }
+    atomic::compiler_fence(Ordering::SeqCst);
+}
+
+#[cfg(unix)]
+fn page_size() -> usize {
+    match unsafe { libc::sysconf(libc::_SC_PAGESIZE) } {
+        size if size > 0 => size as usize,
+        _ => 4096,
+    }
+}
+
+#[cfg(windows)]
+fn page_size() -> usize {
+    4096
+}
+
+#[cfg(unix)]
+fn lock_pages(ptr: *mut u8, len: usize) -> bool {
+    unsafe { libc::mlock(ptr as *const libc::c_void, len) == 0 }
+}
+
+#[cfg(unix)]
+fn unlock_pages(ptr: *mut u8, len: usize) {
+    unsafe {
+        libc::munlock(ptr as *const libc::c_void, len);
+    }
+}
+
+#[cfg(windows)]
+fn lock_pages(ptr: *mut u8, len: usize) -> bool {
+    use windows::Win32::System::Memory::VirtualLock;
+
+    unsafe { VirtualLock(ptr as *const _, len).as_bool() }
+}
+
+#[cfg(windows)]
+fn unlock_pages(ptr: *mut u8, len: usize) {
+    use windows::Win32::System::Memory::VirtualUnlock;
+
+    unsafe {
+        VirtualUnlock(ptr as *const _, len);
+    }
+}
+
+/// A fixed size buffer that is zeroed when dropped.
+///
+/// When locking is enabled by [`set_lock`], the buffer takes whole pages and they are locked
+/// in memory. Failing to lock is not fatal, it's only logged once.
+pub struct SecretBuf {
+    ptr: NonNull<u8>,
+    len: usize,
+    layout: Layout,
+    locked: bool,
+}
+
+unsafe impl Send for SecretBuf {}
+unsafe impl Sync for SecretBuf {}
+
+impl SecretBuf {
+    /// Allocates a zeroed buffer of `len` bytes.
+    pub fn new(len: usize) -> SecretBuf {
+        let lock = LOCK.load(Ordering::Relaxed);
+        // Pages are unlocked as a whole, so they should not be shared with other buffers.
+        let layout = if lock {
+            let page = page_size();
+            Layout::from_size_align(len.max(1).div_ceil(page) * page, page)
+        } else {
+            Layout::from_size_align(len.max(1), 1)
+        }
+        .unwrap();
+        let ptr = match NonNull::new(unsafe { alloc::alloc_zeroed(layout) }) {
+            Some(ptr) => ptr,
+            None => alloc::handle_alloc_error(layout),
+        };
+        let locked = lock && lock_pages(ptr.as_ptr(), layout.size());
+        if lock && !locked && !LOCK_FAILED.swap(true, Ordering::Relaxed) {
+            warn!(
+                "failed to lock secrets in memory: {}",
+                std::io::Error::last_os_error()
+            );
+        }
+        SecretBuf {
+            ptr,
+            len,
+            layout,
+            locked,
+        }
+    }
+
+    pub fn from_slice(data: &[u8]) -> SecretBuf {
+        let mut buf = SecretBuf::new(data.len());
+        buf.copy_from_slice(data);
+        buf
+    }
+}
+
+impl Deref for SecretBuf {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl DerefMut for SecretBuf {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl Clone for SecretBuf {
+    fn clone(&self) -> Self {
+        SecretBuf::from_slice(self)
+    }
+}
+
+impl fmt::Debug for SecretBuf {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SecretBuf([{} bytes redacted])", self.len)
+    }
+}
+
+impl Drop for SecretBuf {
+    fn drop(&mut self) {
+        unsafe {
+            wipe(slice::from_raw_parts_mut(
+                self.ptr.as_ptr(),
+                self.layout.size(),
+            ));
+            if self.locked {
+                unlock_pages(self.ptr.as_ptr(), self.layout.size());
+            }
+            alloc::dealloc(self.ptr.as_ptr(), self.layout);
+        }
+    }
+}
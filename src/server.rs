@@ -0,0 +1,267 @@
+use std::collections::HashMap;
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::{error, info, warn};
+use tokio::sync::{mpsc, watch};
+
+use crate::bridge::Control;
+use crate::config::{BridgeConfig, Config};
+use crate::registry::Registry;
+
+struct Running {
+    id: u64,
+    config: watch::Sender<Arc<BridgeConfig>>,
+    control: Control,
+    /// Whether failing to listen takes down the server, only for bridges it started with.
+    fatal: bool,
+}
+
+/// Runs a set of bridges and keeps them in sync with configuration.
+pub struct Server {
+    bridges: HashMap<String, Running>,
+    /// Bridges that no longer accept connections but are still serving.
+    stopping: HashMap<u64, Control>,
+    registry: Arc<Registry>,
+    next_id: u64,
+    /// Set once the first configuration is applied, later ones come from reloading.
+    applied: bool,
+    exit_tx: mpsc::UnboundedSender<(u64, String, io::Result<()>)>,
+    exit_rx: mpsc::UnboundedReceiver<(u64, String, io::Result<()>)>,
+}
+
+impl Server {
+    pub fn new() -> Self {
+        let (exit_tx, exit_rx) = mpsc::unbounded_channel();
+        Server {
+            bridges: HashMap::new(),
+            stopping: HashMap::new(),
+            registry: Arc::new(Registry::new()),
+            next_id: 0,
+            applied: false,
+            exit_tx,
+            exit_rx,
+        }
+    }
+
+    pub fn registry(&self) -> &Arc<Registry> {
+        &self.registry
+    }
+
+    /// Starts, stops and updates bridges to match `config`.
+    ///
+    /// A bridge is restarted only when its type, listening address, TLS settings, mux channels,
+    /// dial or rendezvous settings, whether it forwards to upstream or whether it relays to its
+    /// backend changes. Stopped bridges keep serving their accepted connections until they
+    /// finish, other changes only apply to new connections.
+    pub fn apply(&mut self, config: &Config) {
+        let mut released = vec![];
+        let stopping = &mut self.stopping;
+        self.bridges.retain(|name, running| {
+            let current = running.config.borrow();
+            let keep = config.bridges.iter().any(|b| {
+                b.name() == name
+                    && b.ty == current.ty
+                    && b.listen == current.listen
+                    && b.tls == current.tls
+                    && b.upstream_addr().is_some() == current.upstream_addr().is_some()
+                    && b.relays_backend() == current.relays_backend()
+                    && b.channels == current.channels
+                    && b.dial_pool == current.dial_pool
+                    && b.dial_token_file == current.dial_token_file
+                    && b.dial_tls == current.dial_tls
+                    && b.rendezvous == current.rendezvous
+                    && b.rendezvous_token_file == current.rendezvous_token_file
+                    && b.rendezvous_tls == current.rendezvous_tls
+            });
+            if !keep {
+                info!("stopping bridge {}", name);
+                running.control.stop();
+                released.push(running.control.clone());
+                stopping.insert(running.id, running.control.clone());
+            }
+            keep
+        });
+
+        for bridge in &config.bridges {
+            match self.bridges.get(bridge.name()) {
+                Some(running) => {
+                    if **running.config.borrow() != *bridge {
+                        info!("updating bridge {}", bridge.name());
+                        running.config.send_replace(Arc::new(bridge.clone()));
+                    }
+                }
+                None => self.start(bridge.clone(), released.clone()),
+            }
+        }
+        self.applied = true;
+    }
+
+    /// Starts a bridge once all bridges in `released` have closed their listeners, as they may
+    /// use the same address.
+    fn start(&mut self, bridge: BridgeConfig, released: Vec<Control>) {
+        let id = self.next_id;
+        self.next_id += 1;
+        let name = bridge.name().to_owned();
+        let listener = self.registry.add_listener(&bridge);
+        let (config, rx) = watch::channel(Arc::new(bridge));
+        let control = Control::new(self.registry.clone(), listener);
+        let registry = self.registry.clone();
+        let exit_tx = self.exit_tx.clone();
+        let task_control = control.clone();
+        let task_name = name.clone();
+        tokio::spawn(async move {
+            for control in released {
+                control.closed().await;
+            }
+            info!("{} bridge start", task_name);
+            let res = crate::serve(rx, task_control.clone()).await;
+            registry.remove_listener(task_control.listener());
+            let _ = exit_tx.send((id, task_name, res));
+        });
+        self.bridges.insert(
+            name,
+            Running {
+                id,
+                config,
+                control,
+                fatal: !self.applied,
+            },
+        );
+    }
+
+    /// Waits until one of the bridges of the first configuration fails, which only happens when
+    /// it can't start listening, as bridges set up their listener again when it fails later on.
+    ///
+    /// The error is returned as is, so [`crate::Error::find`] still finds its cause, and the
+    /// failed bridge is logged. Bridges added by reloading are removed when they fail, and
+    /// serving goes on with the others. Failures of stopped bridges are only logged.
+    pub async fn failed(&mut self) -> io::Error {
+        loop {
+            // `exit_tx` is owned by self, so the channel is never closed.
+            let (id, name, res) = self.exit_rx.recv().await.unwrap();
+            let fatal = match self.bridges.get(&name) {
+                Some(running) if running.id == id => self.bridges.remove(&name).map(|r| r.fatal),
+                _ => {
+                    self.stopping.remove(&id);
+                    None
+                }
+            };
+            match (res, fatal) {
+                (Err(e), Some(true)) => {
+                    error!("bridge {} failed: {}", name, e);
+                    return e;
+                }
+                (Err(e), Some(false)) => error!("bridge {} failed and is removed: {}", name, e),
+                (Err(e), None) => error!("stopped bridge {} failed: {:?}", name, e),
+                (Ok(()), _) => info!("bridge {} stopped", name),
+            }
+        }
+    }
+
+    /// Stops all bridges and waits for their connections to finish.
+    ///
+    /// Connections still open after `deadline` are closed forcibly. Returns whether all
+    /// connections finished in time.
+    pub async fn shutdown(&mut self, deadline: Duration) -> bool {
+        let stopping = &mut self.stopping;
+        for (name, running) in self.bridges.drain() {
+            info!("stopping bridge {}", name);
+            running.control.stop();
+            stopping.insert(running.id, running.control);
+        }
+
+        let drained = tokio::time::timeout(deadline, self.wait_stopped())
+            .await
+            .is_ok();
+        if !drained {
+            let remaining: usize = self.stopping.values().map(Control::connections).sum();
+            warn!(
+                "closing {} connections that didn't finish in {:?}",
+                remaining, deadline
+            );
+            for control in self.stopping.values() {
+                control.abort();
+            }
+            self.wait_stopped().await;
+        }
+        drained
+    }
+
+    async fn wait_stopped(&mut self) {
+        while !self.stopping.is_empty() {
+            let (id, name, res) = self.exit_rx.recv().await.unwrap();
+            self.stopping.remove(&id);
+            match res {
+                Err(e) => error!("stopped bridge {} failed: {:?}", name, e),
+                Ok(()) => info!("bridge {} stopped", name),
+            }
+        }
+    }
+}
+
+impl Default for Server {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use super::*;
+    use crate::SocketType;
+
+    fn config(listens: &[&str]) -> Config {
+        Config {
+            bridges: listens
+                .iter()
+                .map(|listen| BridgeConfig::new(SocketType::Extra, listen.to_string()))
+                .collect(),
+            ..Config::default()
+        }
+    }
+
+    /// An address that is in use as long as the returned listener lives.
+    fn taken() -> (TcpListener, String) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        (listener, addr)
+    }
+
+    #[tokio::test]
+    async fn test_start_unbindable() {
+        let (_listener, addr) = taken();
+        let mut server = Server::new();
+        server.apply(&config(&[&addr]));
+        let e = tokio::time::timeout(Duration::from_secs(5), server.failed())
+            .await
+            .unwrap();
+        // Returned as is, so its typed error and kind are kept.
+        assert_eq!(e.kind(), io::ErrorKind::AddrInUse, "{}", e);
+    }
+
+    #[tokio::test]
+    async fn test_reload_unbindable() {
+        let free = taken().1;
+        let (_listener, addr) = taken();
+        let mut server = Server::new();
+        server.apply(&config(&[&free]));
+        server.apply(&config(&[&free, &addr]));
+        // The added bridge is removed and the first one keeps serving.
+        assert!(
+            tokio::time::timeout(Duration::from_millis(500), server.failed())
+                .await
+                .is_err()
+        );
+        assert!(!server.bridges.contains_key(&addr));
+        assert!(server.bridges.contains_key(&free));
+        tokio::net::TcpStream::connect(&free).await.unwrap();
+        // It's tried again on the next reload.
+        server.apply(&config(&[&free, &addr]));
+        assert!(server.bridges.contains_key(&addr));
+        assert!(server.shutdown(Duration::from_secs(5)).await);
+    }
+}
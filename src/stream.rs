@@ -1,38 +1,343 @@
+pub mod boxed;
+pub mod duplex;
+#[cfg(windows)]
 pub mod named_pipe;
+pub mod replay;
+pub mod stdio;
 pub mod tcp;
+#[cfg(feature = "tls")]
+pub mod tls;
+#[cfg(unix)]
+pub mod unix;
 
+use std::io;
 use std::pin::Pin;
-use std::{io, ptr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 
-use log::trace;
 use tokio::io::{AsyncRead, AsyncReadExt as _, AsyncWrite, AsyncWriteExt as _};
+use tokio::time::Instant;
+
+use crate::bridge::Timeouts;
+use crate::secret::SecretBuf;
 
 pub type PinAsyncRead<'a> = Pin<Box<dyn AsyncRead + Send + 'a>>;
 pub type PinAsyncWrite<'a> = Pin<Box<dyn AsyncWrite + Send + 'a>>;
 
 pub trait SplitStream {
     /// Splits a TcpStream into a read half and a write half, which can be used to read and write the stream concurrently.
-    fn split_rw(&mut self) -> (PinAsyncRead, PinAsyncWrite);
+    fn split_rw(&mut self) -> (PinAsyncRead<'_>, PinAsyncWrite<'_>);
+
+    /// Whether shutting down the write half signals EOF to the peer while keeping the read
+    /// half open.
+    fn supports_half_close(&self) -> bool {
+        true
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    /// From client to the agent.
+    Request,
+    /// From the agent back to client.
+    Reply,
+}
+
+impl Direction {
+    pub(crate) fn tag(self) -> &'static str {
+        match self {
+            Direction::Request => "-->",
+            Direction::Reply => "<--",
+        }
+    }
+}
+
+/// Observes data forwarded by [`relay`].
+pub type Inspect<'a> = &'a (dyn Fn(Direction, &[u8]) + Sync);
+
+/// Bytes transferred in each direction.
+#[derive(Debug, Default)]
+pub struct Traffic {
+    received: AtomicU64,
+    replied: AtomicU64,
+}
+
+impl Traffic {
+    fn add(&self, direction: Direction, bytes: usize) {
+        let counter = match direction {
+            Direction::Request => &self.received,
+            Direction::Reply => &self.replied,
+        };
+        counter.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn received(&self) -> u64 {
+        self.received.load(Ordering::Relaxed)
+    }
+
+    pub fn replied(&self) -> u64 {
+        self.replied.load(Ordering::Relaxed)
+    }
+}
+
+/// Tracks the data transferred by a connection and when it last happened.
+pub struct Activity {
+    started: Instant,
+    /// Milliseconds since `started`.
+    last: AtomicU64,
+    traffic: Traffic,
+    /// Traffic shared by all connections of a listener.
+    totals: Option<Arc<Traffic>>,
+}
+
+impl Activity {
+    pub fn new() -> Self {
+        Activity {
+            started: Instant::now(),
+            last: AtomicU64::new(0),
+            traffic: Traffic::default(),
+            totals: None,
+        }
+    }
+
+    /// Creates an activity that also adds transferred bytes to `totals`.
+    pub fn with_totals(totals: Arc<Traffic>) -> Self {
+        Activity {
+            totals: Some(totals),
+            ..Self::new()
+        }
+    }
+
+    pub fn touch(&self) {
+        let elapsed = self.started.elapsed().as_millis() as u64;
+        self.last.fetch_max(elapsed, Ordering::Relaxed);
+    }
+
+    pub fn record(&self, direction: Direction, bytes: usize) {
+        self.traffic.add(direction, bytes);
+        if let Some(totals) = &self.totals {
+            totals.add(direction, bytes);
+        }
+        self.touch();
+    }
+
+    /// How long the connection has been open.
+    pub fn age(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    pub fn received(&self) -> u64 {
+        self.traffic.received()
+    }
+
+    pub fn replied(&self) -> u64 {
+        self.traffic.replied()
+    }
+
+    fn last_active(&self) -> Instant {
+        self.started + Duration::from_millis(self.last.load(Ordering::Relaxed))
+    }
+
+    /// Resolves once the connection exceeds any of the given limits.
+    ///
+    /// Never resolves if no limit is set.
+    pub async fn expired(&self, timeouts: Timeouts) -> io::Error {
+        let deadline = timeouts.total.map(|total| self.started + total);
+        loop {
+            let idle_deadline = timeouts.idle.map(|idle| self.last_active() + idle);
+            let next = match (deadline, idle_deadline) {
+                (Some(a), Some(b)) => a.min(b),
+                (Some(a), None) | (None, Some(a)) => a,
+                (None, None) => return futures::future::pending().await,
+            };
+            tokio::time::sleep_until(next).await;
+
+            let now = Instant::now();
+            if deadline.is_some_and(|d| d <= now) {
+                return io::Error::new(io::ErrorKind::TimedOut, "connection exceeded max duration");
+            }
+            if let Some(idle) = timeouts.idle {
+                if self.last_active() + idle <= now {
+                    return io::Error::new(io::ErrorKind::TimedOut, "connection idle for too long");
+                }
+            }
+        }
+    }
+}
+
+impl Default for Activity {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 pub async fn copy<'a>(
-    tag: &str,
+    direction: Direction,
     from: &mut Pin<Box<dyn AsyncRead + Send + 'a>>,
     to: &mut Pin<Box<dyn AsyncWrite + Send + 'a>>,
-) -> io::Result<u64> {
-    let mut buf = vec![0; 4096];
-    let mut total = 0;
+    activity: &Activity,
+    inspect: Inspect<'_>,
+) -> io::Result<()> {
+    // Zeroed when dropped, whether it's finished, failed or cancelled.
+    let mut buf = SecretBuf::new(4096);
     loop {
         let cnt = from.read(&mut buf).await?;
         if cnt == 0 {
             to.shutdown().await?;
-            unsafe {
-                ptr::write_bytes(buf.as_mut_ptr(), 0, 4096);
-            }
-            return Ok(total);
+            return Ok(());
         }
-        total += cnt as u64;
-        trace!("{} {:?}", tag, String::from_utf8_lossy(&buf[..cnt]));
+        inspect(direction, &buf[..cnt]);
         to.write_all(&buf[..cnt]).await?;
+        activity.record(direction, cnt);
+    }
+}
+
+/// Forwards data between a client and the agent until both directions are finished.
+///
+/// EOF in one direction is propagated by shutting down the opposite write half, and the other
+/// direction keeps going. If either direction fails, the other one is cancelled. When the client
+/// can't be half-closed, the connection ends as soon as the agent stops replying.
+pub async fn relay(
+    client: &mut impl SplitStream,
+    agent: &mut impl SplitStream,
+    activity: &Activity,
+    inspect: Inspect<'_>,
+) -> io::Result<()> {
+    let half_close = client.supports_half_close();
+    let (mut source_read, mut source_write) = client.split_rw();
+    let (mut target_read, mut target_write) = agent.split_rw();
+    let s2t = copy(
+        Direction::Request,
+        &mut source_read,
+        &mut target_write,
+        activity,
+        inspect,
+    );
+    let t2s = copy(
+        Direction::Reply,
+        &mut target_read,
+        &mut source_write,
+        activity,
+        inspect,
+    );
+    tokio::pin!(s2t, t2s);
+
+    let (mut requested, mut replied) = (false, false);
+    while !requested || !replied {
+        tokio::select! {
+            res = &mut s2t, if !requested => {
+                res?;
+                requested = true;
+            }
+            res = &mut t2s, if !replied => {
+                res?;
+                replied = true;
+                if !half_close {
+                    break;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::DuplexStream;
+    use tokio::task::JoinHandle;
+
+    use super::*;
+
+    /// Relays between the returned client and agent ends until the relay finishes or one of
+    /// `timeouts` expires, like bridges do.
+    fn start(timeouts: Timeouts) -> (DuplexStream, DuplexStream, JoinHandle<io::Result<()>>) {
+        let (client, mut from) = tokio::io::duplex(1024);
+        let (agent, mut to) = tokio::io::duplex(1024);
+        let task = tokio::spawn(async move {
+            let activity = Activity::new();
+            tokio::select! {
+                res = relay(&mut from, &mut to, &activity, &|_: Direction, _: &[u8]| {}) => res,
+                e = activity.expired(timeouts) => Err(e),
+            }
+        });
+        (client, agent, task)
+    }
+
+    #[tokio::test]
+    async fn test_idle_timeout() {
+        let timeouts = Timeouts {
+            idle: Some(Duration::from_millis(300)),
+            total: None,
+        };
+        let (mut client, mut agent, task) = start(timeouts);
+        let started = Instant::now();
+        let mut buf = [0; 4];
+        // Traffic keeps the connection open past the idle timeout.
+        for _ in 0..4 {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            client.write_all(b"ping").await.unwrap();
+            agent.read_exact(&mut buf).await.unwrap();
+        }
+        let e = task.await.unwrap().unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+        assert!(e.to_string().contains("idle"), "{}", e);
+        assert!(started.elapsed() >= Duration::from_millis(700));
+        // Both ends see the connection closed.
+        assert_eq!(client.read(&mut buf).await.unwrap(), 0);
+        assert_eq!(agent.read(&mut buf).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_max_duration() {
+        let timeouts = Timeouts {
+            idle: Some(Duration::from_secs(10)),
+            total: Some(Duration::from_millis(300)),
+        };
+        let (mut client, mut agent, task) = start(timeouts);
+        let started = Instant::now();
+        let mut buf = [0; 4];
+        // Closed even though it's busy.
+        while !task.is_finished() {
+            let _ = client.write_all(b"ping").await;
+            let _ = agent.read_exact(&mut buf).await;
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        let e = task.await.unwrap().unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+        assert!(e.to_string().contains("max duration"), "{}", e);
+        assert!(started.elapsed() >= Duration::from_millis(300));
+        assert_eq!(client.read(&mut buf).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_half_close() {
+        let (mut client, mut agent, task) = start(Timeouts::default());
+        client.write_all(b"request").await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut request = vec![];
+        agent.read_to_end(&mut request).await.unwrap();
+        assert_eq!(request, b"request");
+        // The reply still gets through after the client is done sending.
+        agent.write_all(b"reply").await.unwrap();
+        agent.shutdown().await.unwrap();
+        let mut reply = vec![];
+        client.read_to_end(&mut reply).await.unwrap();
+        assert_eq!(reply, b"reply");
+        task.await.unwrap().unwrap();
+
+        // And the other way around.
+        let (mut client, mut agent, task) = start(Timeouts::default());
+        agent.write_all(b"greeting").await.unwrap();
+        agent.shutdown().await.unwrap();
+        let mut greeting = vec![];
+        client.read_to_end(&mut greeting).await.unwrap();
+        assert_eq!(greeting, b"greeting");
+        client.write_all(b"request").await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut request = vec![];
+        agent.read_to_end(&mut request).await.unwrap();
+        assert_eq!(request, b"request");
+        task.await.unwrap().unwrap();
     }
 }
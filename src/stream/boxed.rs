@@ -0,0 +1,11 @@
+use super::{PinAsyncRead, PinAsyncWrite, SplitStream};
+use crate::mux::Transport;
+
+/// A connection that is either plain TCP or TLS.
+impl SplitStream for Box<dyn Transport> {
+    #[inline]
+    fn split_rw(&mut self) -> (PinAsyncRead<'_>, PinAsyncWrite<'_>) {
+        let (read_half, write_half) = tokio::io::split(self);
+        (Box::pin(read_half), Box::pin(write_half))
+    }
+}
@@ -0,0 +1,12 @@
+use tokio::io::DuplexStream;
+
+use super::{PinAsyncRead, PinAsyncWrite, SplitStream};
+
+/// A channel of a multiplexed connection.
+impl SplitStream for DuplexStream {
+    #[inline]
+    fn split_rw(&mut self) -> (PinAsyncRead<'_>, PinAsyncWrite<'_>) {
+        let (read_half, write_half) = tokio::io::split(self);
+        (Box::pin(read_half), Box::pin(write_half))
+    }
+}
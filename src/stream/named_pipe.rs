@@ -3,10 +3,22 @@ use std::task::{ready, Context, Poll};
 
 use log::trace;
 use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
-use tokio::net::windows::named_pipe::NamedPipeServer;
+use tokio::net::windows::named_pipe::{NamedPipeClient, NamedPipeServer};
 
 use super::{PinAsyncRead, PinAsyncWrite, SplitStream};
 
+const ERROR_BROKEN_PIPE: i32 = 109;
+const ERROR_PIPE_NOT_CONNECTED: i32 = 233;
+
+/// A client closing its end of the pipe is reported as an error instead of EOF.
+fn is_pipe_closed(e: &std::io::Error) -> bool {
+    e.kind() == std::io::ErrorKind::BrokenPipe
+        || matches!(
+            e.raw_os_error(),
+            Some(ERROR_BROKEN_PIPE) | Some(ERROR_PIPE_NOT_CONNECTED)
+        )
+}
+
 struct PipeServerRead<'a> {
     server: &'a NamedPipeServer,
 }
@@ -19,6 +31,9 @@ impl<'a> AsyncRead for PipeServerRead<'a> {
     ) -> Poll<Result<(), std::io::Error>> {
         trace!("polling pipe reader");
         if let Err(e) = ready!(self.server.poll_read_ready(cx)) {
+            if is_pipe_closed(&e) {
+                return Poll::Ready(Ok(()));
+            }
             return Poll::Ready(Err(e));
         }
 
@@ -31,9 +46,13 @@ impl<'a> AsyncRead for PipeServerRead<'a> {
                 }
                 Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
                     if let Err(e) = ready!(self.server.poll_read_ready(cx)) {
+                        if is_pipe_closed(&e) {
+                            return Poll::Ready(Ok(()));
+                        }
                         return Poll::Ready(Err(e));
                     }
                 }
+                Err(e) if is_pipe_closed(&e) => return Poll::Ready(Ok(())),
                 Err(e) => return Poll::Ready(Err(e)),
             }
         }
@@ -58,7 +77,7 @@ impl<'a> AsyncWrite for PipeServerWrite<'a> {
             match self.server.try_write(buf) {
                 Ok(n) => return Poll::Ready(Ok(n)),
                 Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
-                    if let Err(e) = ready!(self.server.poll_read_ready(cx)) {
+                    if let Err(e) = ready!(self.server.poll_write_ready(cx)) {
                         return Poll::Ready(Err(e));
                     }
                 }
@@ -79,10 +98,26 @@ impl<'a> AsyncWrite for PipeServerWrite<'a> {
 }
 
 impl SplitStream for NamedPipeServer {
-    fn split_rw(&mut self) -> (PinAsyncRead, PinAsyncWrite) {
+    fn split_rw(&mut self) -> (PinAsyncRead<'_>, PinAsyncWrite<'_>) {
         (
             Box::pin(PipeServerRead { server: self }),
             Box::pin(PipeServerWrite { server: self }),
         )
     }
+
+    fn supports_half_close(&self) -> bool {
+        false
+    }
+}
+
+/// A connection to a pipe served by another process, like the OpenSSH agent.
+impl SplitStream for NamedPipeClient {
+    fn split_rw(&mut self) -> (PinAsyncRead<'_>, PinAsyncWrite<'_>) {
+        let (read_half, write_half) = tokio::io::split(self);
+        (Box::pin(read_half), Box::pin(write_half))
+    }
+
+    fn supports_half_close(&self) -> bool {
+        false
+    }
 }
@@ -0,0 +1,30 @@
+use std::io::Cursor;
+
+use tokio::io::AsyncReadExt as _;
+
+use super::{PinAsyncRead, PinAsyncWrite, SplitStream};
+
+/// A stream whose first bytes have already been read, they are read again before the rest.
+pub struct Replay<S> {
+    prefix: Vec<u8>,
+    inner: S,
+}
+
+impl<S> Replay<S> {
+    pub fn new(prefix: Vec<u8>, inner: S) -> Self {
+        Replay { prefix, inner }
+    }
+}
+
+impl<S: SplitStream + Send> SplitStream for Replay<S> {
+    /// The prefix is only replayed by the first read half.
+    fn split_rw(&mut self) -> (PinAsyncRead<'_>, PinAsyncWrite<'_>) {
+        let prefix = Cursor::new(std::mem::take(&mut self.prefix));
+        let (read, write) = self.inner.split_rw();
+        (Box::pin(prefix.chain(read)), write)
+    }
+
+    fn supports_half_close(&self) -> bool {
+        self.inner.supports_half_close()
+    }
+}
@@ -4,7 +4,7 @@ use super::{PinAsyncRead, PinAsyncWrite, SplitStream};
 
 impl SplitStream for TcpStream {
     #[inline]
-    fn split_rw(&mut self) -> (PinAsyncRead, PinAsyncWrite) {
+    fn split_rw(&mut self) -> (PinAsyncRead<'_>, PinAsyncWrite<'_>) {
         let (read_half, write_half) = TcpStream::split(self);
         (Box::pin(read_half), Box::pin(write_half))
     }
@@ -0,0 +1,22 @@
+use tokio::net::TcpStream;
+use tokio_rustls::{client, server};
+
+use super::{PinAsyncRead, PinAsyncWrite, SplitStream};
+
+// Shutting down the write half sends close_notify, which TLS 1.3 allows to be a half-close.
+
+impl SplitStream for server::TlsStream<TcpStream> {
+    #[inline]
+    fn split_rw(&mut self) -> (PinAsyncRead<'_>, PinAsyncWrite<'_>) {
+        let (read_half, write_half) = tokio::io::split(self);
+        (Box::pin(read_half), Box::pin(write_half))
+    }
+}
+
+impl SplitStream for client::TlsStream<TcpStream> {
+    #[inline]
+    fn split_rw(&mut self) -> (PinAsyncRead<'_>, PinAsyncWrite<'_>) {
+        let (read_half, write_half) = tokio::io::split(self);
+        (Box::pin(read_half), Box::pin(write_half))
+    }
+}
@@ -0,0 +1,11 @@
+use tokio::net::UnixStream;
+
+use super::{PinAsyncRead, PinAsyncWrite, SplitStream};
+
+impl SplitStream for UnixStream {
+    #[inline]
+    fn split_rw(&mut self) -> (PinAsyncRead<'_>, PinAsyncWrite<'_>) {
+        let (read_half, write_half) = UnixStream::split(self);
+        (Box::pin(read_half), Box::pin(write_half))
+    }
+}
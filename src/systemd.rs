@@ -0,0 +1,141 @@
+//! Socket activation and service notifications of systemd, or any supervisor that follows the
+//! same protocol.
+//!
+//! See sd_listen_fds(3) and sd_notify(3).
+
+use std::collections::BTreeMap;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::net::UnixDatagram;
+use std::time::Duration;
+use std::{env, io};
+
+use log::{debug, info, warn};
+use parking_lot::Mutex;
+
+/// The first inherited file descriptor.
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Inherited sockets by name, they are kept open so a restarted bridge can use them again.
+static LISTENERS: Mutex<BTreeMap<String, OwnedFd>> = Mutex::new(BTreeMap::new());
+/// Number and names of the inherited sockets, found by [`take_env`] and taken over by [`init`].
+static ACTIVATION: Mutex<Option<(RawFd, String)>> = Mutex::new(None);
+
+/// Reads `LISTEN_FDS` and `LISTEN_FDNAMES` for [`init`], and removes them so child processes
+/// don't take the sockets by mistake.
+///
+/// # Safety
+///
+/// Changing the environment is only sound while no other thread reads it, so it must be called
+/// at the start of `main` before the runtime is built.
+pub unsafe fn take_env() {
+    let pid = env::var("LISTEN_PID")
+        .ok()
+        .and_then(|p| p.parse::<u32>().ok());
+    let count = env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|n| n.parse::<RawFd>().ok());
+    let names = env::var("LISTEN_FDNAMES").unwrap_or_default();
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+    if let Some(count) = count.filter(|_| pid == Some(std::process::id())) {
+        *ACTIVATION.lock() = Some((count, names));
+    }
+}
+
+/// Takes over the sockets found by [`take_env`].
+///
+/// It should be called once at start, before any other file is opened.
+pub fn init() {
+    let Some((count, names)) = ACTIVATION.lock().take() else {
+        return;
+    };
+    let mut names = names.split(':');
+    let mut listeners = LISTENERS.lock();
+    for fd in LISTEN_FDS_START..LISTEN_FDS_START + count {
+        unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        // Unnamed sockets are called "unknown" by systemd as well.
+        let name = names.next().filter(|n| !n.is_empty()).unwrap_or("unknown");
+        if listeners.insert(name.to_owned(), fd).is_some() {
+            warn!(
+                "multiple sockets are named {}, only the last one is used",
+                name
+            );
+        }
+    }
+    info!(
+        "inherited sockets {}",
+        listeners.keys().cloned().collect::<Vec<_>>().join(", ")
+    );
+}
+
+/// A listening socket inherited for the bridge named `name`, if any.
+pub fn listener(name: &str) -> Option<io::Result<OwnedFd>> {
+    LISTENERS.lock().get(name).map(|fd| fd.try_clone())
+}
+
+/// Whether the inherited socket is a Unix domain socket rather than a TCP one.
+pub fn is_unix(fd: &OwnedFd) -> io::Result<bool> {
+    let mut addr: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of_val(&addr) as libc::socklen_t;
+    let res = unsafe { libc::getsockname(fd.as_raw_fd(), &mut addr as *mut _ as *mut _, &mut len) };
+    if res != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(addr.ss_family as libc::c_int == libc::AF_UNIX)
+}
+
+/// Sends `state` like `READY=1` to the supervisor, does nothing if it's not listening.
+pub fn notify(state: &str) {
+    let Some(path) = env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    let res = UnixDatagram::unbound().and_then(|socket| match path.as_bytes().strip_prefix(b"@") {
+        // An abstract socket, which is only supported on Linux.
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            socket.send_to_addr(state.as_bytes(), &addr)
+        }
+        _ => socket.send_to(state.as_bytes(), &path),
+    });
+    match res {
+        Ok(_) => debug!("notified {}", state.replace('\n', " ")),
+        Err(e) => warn!("failed to notify the service manager: {}", e),
+    }
+}
+
+/// `RELOADING=1` also carries the time it started, which `Type=notify-reload` requires.
+pub fn notify_reloading() {
+    let mut now: libc::timespec = unsafe { std::mem::zeroed() };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut now) };
+    let usec = now.tv_sec as u64 * 1_000_000 + now.tv_nsec as u64 / 1_000;
+    notify(&format!("RELOADING=1\nMONOTONIC_USEC={}", usec));
+}
+
+/// Interval of watchdog pings, if the supervisor asks for them.
+pub fn watchdog_interval() -> Option<Duration> {
+    let pid = env::var("WATCHDOG_PID").ok();
+    if pid.is_some_and(|p| p.parse() != Ok(std::process::id())) {
+        return None;
+    }
+    let usec: u64 = env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    // Pings twice within the timeout, as recommended.
+    Some(Duration::from_micros(usec / 2)).filter(|d| !d.is_zero())
+}
+
+/// Pings the watchdog forever, or just waits if there is no watchdog.
+pub async fn watchdog() {
+    let Some(interval) = watchdog_interval() else {
+        return futures::future::pending().await;
+    };
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        notify("WATCHDOG=1");
+    }
+}
@@ -0,0 +1,149 @@
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{self, CryptoProvider};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{
+    CertificateError, ClientConfig, DigitallySignedStruct, RootCertStore, ServerConfig,
+    SignatureScheme,
+};
+use tokio::net::TcpStream;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+use crate::config::{TlsConnectConfig, TlsListenConfig};
+use crate::util::report_data_err;
+
+/// Name sent to upstream, the server certificate is pinned so it's not verified.
+const UPSTREAM_NAME: &str = "gpg-bridge";
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(crypto::ring::default_provider())
+}
+
+fn load_certs(path: &Path) -> io::Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| report_data_err(format!("invalid {}: {}", path.display(), e)))?;
+    if certs.is_empty() {
+        return Err(report_data_err(format!(
+            "no certificate found in {}",
+            path.display()
+        )));
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &Path) -> io::Result<PrivateKeyDer<'static>> {
+    PrivateKeyDer::from_pem_file(path)
+        .map_err(|e| report_data_err(format!("invalid {}: {}", path.display(), e)))
+}
+
+fn tls_err(e: rustls::Error) -> io::Error {
+    report_data_err(e)
+}
+
+/// Builds an acceptor that requires client certificates signed by the configured CA.
+pub fn acceptor(config: &TlsListenConfig) -> io::Result<TlsAcceptor> {
+    let provider = provider();
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(&config.client_ca)? {
+        roots.add(cert).map_err(tls_err)?;
+    }
+    let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider.clone())
+        .build()
+        .map_err(report_data_err)?;
+    let server = ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(tls_err)?
+        .with_client_cert_verifier(verifier)
+        .with_single_cert(load_certs(&config.cert)?, load_key(&config.key)?)
+        .map_err(tls_err)?;
+    Ok(TlsAcceptor::from(Arc::new(server)))
+}
+
+/// Builds a connector that only trusts the pinned server certificate.
+pub fn connector(config: &TlsConnectConfig) -> io::Result<TlsConnector> {
+    let provider = provider();
+    let pinned = load_certs(&config.server_cert)?.swap_remove(0);
+    let client = ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(tls_err)?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(PinnedCert { pinned, provider }))
+        .with_client_auth_cert(load_certs(&config.cert)?, load_key(&config.key)?)
+        .map_err(tls_err)?;
+    Ok(TlsConnector::from(Arc::new(client)))
+}
+
+pub async fn connect(
+    connector: &TlsConnector,
+    stream: TcpStream,
+) -> io::Result<TlsStream<TcpStream>> {
+    let name = ServerName::try_from(UPSTREAM_NAME).unwrap();
+    connector.connect(name, stream).await
+}
+
+/// Accepts exactly one certificate regardless of its issuer, names and validity period.
+#[derive(Debug)]
+struct PinnedCert {
+    pinned: CertificateDer<'static>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedCert {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if end_entity.as_ref() == self.pinned.as_ref() {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::InvalidCertificate(
+                CertificateError::ApplicationVerificationFailure,
+            ))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
@@ -5,5 +5,5 @@ pub fn report_data_err(e: impl Into<Box<dyn error::Error + Send + Sync>>) -> io:
 }
 
 pub fn other_error(details: String) -> io::Error {
-    io::Error::new(io::ErrorKind::Other, details)
+    io::Error::other(details)
 }